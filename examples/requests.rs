@@ -3,183 +3,176 @@ extern crate urpc;
 
 use urpc::{
     client, consts,
-    server::{self, Request},
-    OptBufNo, OptBufYes,
+    server::{self, Items},
 };
 
-use hex;
+use serde::{Deserialize, Serialize};
 
-mod cli {
-    client_requests! {
-        client_requests;
-        (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
-        (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
-        (2, recv_bytes, RecvBytes(u32, OptBufNo, u32, OptBufYes))
-    }
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RegError {
+    InvalidRegister(u8),
 }
 
-server_requests! {
-    ServerRequests;
+service_requests! {
+    Requests;
     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
     (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
-    (2, recv_bytes, RecvBytes(u32, OptBufNo, u32, OptBufYes))
+    (2, recv_bytes, RecvBytes(u32, OptBufNo, u32, OptBufYes)),
+    (3, read_reg, ReadReg(u8, OptBufNo, u32, OptBufNo, RegError)),
+    (4, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
 }
 
-fn main() -> () {
+struct Server;
+
+impl Handler for Server {
+    fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+        Ok(body)
+    }
+
+    fn send_bytes(&mut self, _body: u32, buf: &[u8]) -> Result<u32, ()> {
+        println!("send_bytes: {}", hex::encode(buf));
+        Ok(1111)
+    }
+
+    fn recv_bytes(&mut self, _body: u32, out: &mut [u8]) -> Result<(u32, usize), ()> {
+        let n = 8;
+        for (i, b) in out.iter_mut().enumerate().take(n) {
+            *b = (i * 2) as u8;
+        }
+        Ok((2222, n))
+    }
+
+    fn read_reg(&mut self, reg: u8) -> Result<u32, RegError> {
+        if reg < 4 {
+            Ok(0x1000 + reg as u32)
+        } else {
+            Err(RegError::InvalidRegister(reg))
+        }
+    }
+
+    fn samples(&mut self, body: u8, items: &mut Items<u16>) -> Result<(), ()> {
+        for i in 0..body as u16 {
+            items.send(i * 100).ok();
+        }
+        Ok(())
+    }
+}
+
+fn main() {
     const BUF_LEN: usize = 4096;
-    let mut read_buf = vec![0; BUF_LEN];
-    let mut write_buf = vec![0; BUF_LEN];
+    let mut client_buf = vec![0; BUF_LEN];
+    let mut server_buf = vec![0; BUF_LEN];
 
     let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+
+    // Send the five requests without waiting for the replies
+    let mut requests_bytes = Vec::new();
 
-    let mut req0 = None;
-    let mut req1 = None;
-    let mut req2 = None;
-    for i in 0..3 {
-        read_buf.iter_mut().for_each(|x| *x = 0);
-        write_buf.iter_mut().for_each(|x| *x = 0);
-        let mut read_buf_len = 0;
-        match i {
-            0 => {
-                println!("--- Ping ---");
-                let mut req = cli::Ping::new([0, 1, 2, 3]);
-                read_buf_len = req.request(&mut rpc_client, &mut read_buf).unwrap();
-                req0 = Some(req);
+    println!("--- Ping ---");
+    let mut req0 = Ping::new([0, 1, 2, 3]);
+    let n = req0.request(&mut rpc_client, &mut client_buf).unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    println!("--- SendBytes ---");
+    let req_buf = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let mut req1 = SendBytes::new(1100);
+    let n = req1
+        .request(&req_buf, &mut rpc_client, &mut client_buf)
+        .unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    println!("--- RecvBytes ---");
+    let mut req2 = RecvBytes::new(2200);
+    let n = req2.request(&mut rpc_client, &mut client_buf).unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    println!("--- ReadReg ---");
+    let mut req3 = ReadReg::new(7);
+    let n = req3.request(&mut rpc_client, &mut client_buf).unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    println!("--- Samples ---");
+    let mut req4 = Samples::new(3);
+    let n = req4.request(&mut rpc_client, &mut client_buf).unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    // Cancel a request after sending it.  Its late reply will be dropped by the client.
+    println!("--- Ping (cancelled) ---");
+    let mut req5 = Ping::new([4, 5, 6, 7]);
+    let n = req5.request(&mut rpc_client, &mut client_buf).unwrap();
+    println!("request: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+    let n = req5.cancel(&mut rpc_client, &mut client_buf).unwrap();
+    println!("cancel: {}", hex::encode(&client_buf[..n]));
+    requests_bytes.extend_from_slice(&client_buf[..n]);
+
+    println!("in flight: {}", rpc_client.in_flight());
+
+    // Serve the requests, storing the replies of each request
+    let mut handler = Server;
+    let mut replies = Vec::new();
+    let mut pos = 0;
+    let mut read_len = consts::REQ_HEADER_LEN;
+    while pos < requests_bytes.len() {
+        let buf = &requests_bytes[pos..pos + read_len];
+        println!("pos: {}, buf: {}", pos, hex::encode(buf));
+        pos += read_len;
+        match server::dispatch::<Requests, _>(&mut rpc_server, &mut handler, buf, &mut server_buf)
+            .unwrap()
+        {
+            server::ParseResult::NeedBytes(n) => {
+                read_len = n;
             }
-            1 => {
-                println!("--- SendBytes ---");
-                let req_buf = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
-                let mut req = cli::SendBytes::new(1100);
-                read_buf_len = req
-                    .request(&req_buf, &mut rpc_client, &mut read_buf)
-                    .unwrap();
-                req1 = Some(req);
+            server::ParseResult::Cancel(chan_id) => {
+                read_len = consts::REQ_HEADER_LEN;
+                // The reply has already been sent, so there's nothing to stop
+                println!("cancel: channel {}", chan_id);
             }
-            2 => {
-                println!("--- RecvBytes ---");
-                let mut req = cli::RecvBytes::new(2200);
-                read_buf_len = req.request(&mut rpc_client, &mut read_buf).unwrap();
-                req2 = Some(req);
+            server::ParseResult::Request(n) => {
+                read_len = consts::REQ_HEADER_LEN;
+                println!("reply: {}", hex::encode(&server_buf[..n]));
+                replies.push(server_buf[..n].to_vec());
             }
-            _ => {}
         }
+    }
 
-        println!(
-            "read_buf: {}, {}",
-            read_buf_len,
-            hex::encode(&read_buf[..read_buf_len])
-        );
-
-        let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    // Deliver the replies of each request to the client in reverse order
+    for reply in replies.iter().rev() {
         let mut pos = 0;
-        let mut read_len = consts::REQ_HEADER_LEN;
-        let mut write_buf_len = 0;
-        loop {
-            let buf = &read_buf[pos..pos + read_len];
+        let mut read_len = consts::REP_HEADER_LEN;
+        while pos < reply.len() {
+            let buf = &reply[pos..pos + read_len];
             println!("pos: {}, buf: {}", pos, hex::encode(buf));
             pos += read_len;
-            match ServerRequests::from_rpc(&mut rpc_server, &buf).unwrap() {
-                server::ParseResult::NeedBytes(n) => {
-                    read_len = n;
-                }
-                server::ParseResult::Request(req) => {
-                    read_len = consts::REQ_HEADER_LEN;
-                    println!("request: {:?}", req);
-                    match req {
-                        ServerRequests::Ping(ping) => {
-                            let ping_body = ping.body;
-                            write_buf_len = ping.reply(ping_body, &mut write_buf).unwrap();
-                        }
-                        ServerRequests::SendBytes((send_bytes, buf)) => {
-                            println!("send_bytes: {}", hex::encode(buf));
-                            write_buf_len = send_bytes.reply(1111, &mut write_buf).unwrap();
-                        }
-                        ServerRequests::RecvBytes(recv_bytes) => {
-                            let opt_buf_len = {
-                                let mut opt_buf = recv_bytes.get_opt_buf(&mut write_buf);
-                                let n = 8;
-                                for i in 0..n {
-                                    opt_buf[i] = (i * 2) as u8;
-                                }
-                                n
-                            };
-                            write_buf_len = recv_bytes
-                                .reply(2222, opt_buf_len as u16, &mut write_buf)
-                                .unwrap();
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-        println!(
-            "write_buf: {}, {}",
-            write_buf_len,
-            hex::encode(&write_buf[..write_buf_len])
-        );
-
-        match i {
-            0 => {
-                let mut req = req0.unwrap();
-                let mut pos = 0;
-                let mut read_len = consts::REP_HEADER_LEN;
-                loop {
-                    let buf = &write_buf[pos..pos + read_len];
-                    println!("pos: {}, buf: {}", pos, hex::encode(buf));
-                    pos += read_len;
-                    read_len = rpc_client.parse(&buf).unwrap().0;
-                    match req.take_reply(&mut rpc_client) {
-                        Some(r) => {
-                            let r = r.unwrap();
-                            println!("reply: {:?}", r);
-                            break;
-                        }
-                        None => {}
-                    }
-                }
-                req0 = Some(req);
-            }
-            1 => {
-                let mut req = req1.unwrap();
-                let mut pos = 0;
-                let mut read_len = consts::REP_HEADER_LEN;
-                loop {
-                    let buf = &write_buf[pos..pos + read_len];
-                    println!("pos: {}, buf: {}", pos, hex::encode(buf));
-                    pos += read_len;
-                    read_len = rpc_client.parse(&buf).unwrap().0;
-                    match req.take_reply(&mut rpc_client) {
-                        Some(r) => {
-                            let r = r.unwrap();
-                            println!("reply: {:?}", r);
-                            break;
-                        }
-                        None => {}
-                    }
-                }
-                req1 = Some(req);
-            }
-            2 => {
-                let mut req = req2.unwrap();
-                let mut pos = 0;
-                let mut read_len = consts::REP_HEADER_LEN;
-                loop {
-                    let buf = &write_buf[pos..pos + read_len];
-                    println!("pos: {}, buf: {}", pos, hex::encode(buf));
-                    pos += read_len;
-                    read_len = rpc_client.parse(&buf).unwrap().0;
-                    match req.take_reply(&mut rpc_client) {
-                        Some(r) => {
-                            let (r, b) = r.unwrap();
-                            println!("reply: {:?} {:?}", r, b);
-                            break;
-                        }
-                        None => {}
-                    }
-                }
-                req2 = Some(req);
-            }
-            _ => {}
+            read_len = rpc_client.parse(buf).unwrap().0;
         }
     }
+
+    println!(
+        "reply ping: {:?}",
+        req0.take_reply(&mut rpc_client).unwrap()
+    );
+    println!(
+        "reply send_bytes: {:?}",
+        req1.take_reply(&mut rpc_client).unwrap()
+    );
+    println!(
+        "reply recv_bytes: {:?}",
+        req2.take_reply(&mut rpc_client).unwrap()
+    );
+    println!(
+        "reply read_reg: {:?}",
+        req3.take_reply(&mut rpc_client).unwrap()
+    );
+    while let Some(item) = req4.take_item(&mut rpc_client) {
+        println!("item samples: {:?}", item);
+    }
+    println!("samples done: {}", req4.is_done());
+    println!("in flight: {}", rpc_client.in_flight());
 }
@@ -6,6 +6,7 @@ use core::convert;
 use core::marker::PhantomData;
 use core::mem::swap;
 
+use crc::Digest;
 use postcard;
 use serde::{de::DeserializeOwned, Serialize};
 
@@ -16,9 +17,28 @@ pub enum Error {
     ReplyBodyTooLong,
     ReplyOptBufTooLong,
     ReplyOptBufUnexpected,
-    NotIdle,
-    NotExpectingBytes,
-    TODO,
+    /// The reply header options have reserved bits set.
+    ReplyOptsInvalid(u8),
+    /// The frame length doesn't match the reply lengths in its header.
+    FrameLength,
+    /// The reply CRC trailer doesn't match the reply.
+    ChecksumMismatch,
+    NoFreeChanId,
+    UnexpectedChanId(u8),
+    /// The server doesn't implement the requested method.
+    UnknownMethod,
+    /// The server couldn't deserialize the request body.
+    InvalidBody,
+    /// The request didn't fit in the server buffer.
+    RequestTooLong,
+    /// The server couldn't handle the request at the moment.
+    Busy,
+    /// The request failed the server CRC check and should be sent again.
+    Resend,
+    /// The server schema hash, which doesn't match the client one.
+    SchemaMismatch(u32),
+    /// The reply storage can't hold the reply until it's taken.
+    ReplyStorageFull,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -29,305 +49,1233 @@ impl convert::From<postcard::Error> for Error {
     }
 }
 
+impl convert::From<InvalidOpts> for Error {
+    fn from(InvalidOpts(opts): InvalidOpts) -> Self {
+        Self::ReplyOptsInvalid(opts)
+    }
+}
+
+impl convert::From<ErrorCode> for Error {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::UnknownMethod => Self::UnknownMethod,
+            ErrorCode::InvalidBody => Self::InvalidBody,
+            ErrorCode::TooLong => Self::RequestTooLong,
+            ErrorCode::Busy => Self::Busy,
+            ErrorCode::Resend => Self::Resend,
+        }
+    }
+}
+
 pub trait MethodId {
     const METHOD_ID: u8;
 }
 
+/// Method id of the built-in [`Ping`] method.
+pub struct PingId;
+
+impl MethodId for PingId {
+    const METHOD_ID: u8 = METHOD_PING;
+}
+
+/// Method id of the built-in [`GetInfo`] method.
+pub struct GetInfoId;
+
+impl MethodId for GetInfoId {
+    const METHOD_ID: u8 = METHOD_GET_INFO;
+}
+
+/// Method id of the built-in [`MaxBufLen`] method.
+pub struct MaxBufLenId;
+
+impl MethodId for MaxBufLenId {
+    const METHOD_ID: u8 = METHOD_MAX_BUF_LEN;
+}
+
+/// Method id of the built-in [`SchemaHash`] method.
+pub struct SchemaHashId;
+
+impl MethodId for SchemaHashId {
+    const METHOD_ID: u8 = METHOD_SCHEMA_HASH;
+}
+
+// The built-in requests are replied by the server runtime (`server::dispatch` and
+// `server::RpcServerIO`) without reaching the handler.
+
+/// Built-in request with an empty reply, to check that the server is alive.
+pub type Ping = RequestType<PingId, (), OptBufNo, (), OptBufNo>;
+
+/// Built-in request of the server info.
+pub type GetInfo = RequestType<GetInfoId, (), OptBufNo, ServerInfo, OptBufNo>;
+
+/// Built-in request of the maximum buffer length of the server.  Requests whose body or
+/// optional buffer are longer are rejected by the server.
+pub type MaxBufLen = RequestType<MaxBufLenId, (), OptBufNo, u16, OptBufNo>;
+
+/// Built-in request of the schema hash of the server service.
+pub type SchemaHash = RequestType<SchemaHashId, (), OptBufNo, u32, OptBufNo>;
+
+/// Error obtained when taking the reply of a request.
+#[derive(Debug)]
+pub enum MethodError<E> {
+    /// The method replied with its custom error.
+    Method(E),
+    /// The reply couldn't be handled by the client.
+    Client(Error),
+}
+
+/// Result of taking the reply of a request of a method with error type `E`.
+pub type MethodResult<T, E> = core::result::Result<T, MethodError<E>>;
+
+impl<E> convert::From<Error> for MethodError<E> {
+    fn from(error: Error) -> Self {
+        Self::Client(error)
+    }
+}
+
+impl<E> convert::From<postcard::Error> for MethodError<E> {
+    fn from(error: postcard::Error) -> Self {
+        Self::Client(error.into())
+    }
+}
+
 /// Type used to build a Request for a particular RPC Call.
 #[derive(Debug)]
-pub struct RequestType<M: MethodId, Q: Serialize, QB: OptBuf, P: DeserializeOwned, PB: OptBuf> {
+pub struct RequestType<
+    M: MethodId,
+    Q: Serialize,
+    QB: OptBuf,
+    P: DeserializeOwned,
+    PB: OptBuf,
+    E: DeserializeOwned = (),
+> {
     chan_id: u8,
     body: Q,
-    phantom: PhantomData<(M, QB, P, PB)>,
+    phantom: PhantomData<(M, QB, P, PB, E)>,
 }
 
-impl<M: MethodId, Q: Serialize, QB: OptBuf, P: DeserializeOwned, PB: OptBuf>
-    RequestType<M, Q, QB, P, PB>
+impl<
+        M: MethodId,
+        Q: Serialize,
+        QB: OptBuf,
+        P: DeserializeOwned,
+        PB: OptBuf,
+        E: DeserializeOwned,
+    > RequestType<M, Q, QB, P, PB, E>
 {
     pub fn new(req: Q) -> Self {
         Self {
             chan_id: 0,
             body: req,
-            phantom: PhantomData::<(M, QB, P, PB)>,
+            phantom: PhantomData::<(M, QB, P, PB, E)>,
         }
     }
 
     pub fn chan_id(&self) -> u8 {
         self.chan_id
     }
+
+    /// Build a packet that cancels this request and serialize it into buf.  See
+    /// [`RpcClient::cancel`].
+    pub fn cancel<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        rpc_client.cancel(self.chan_id, buf)
+    }
 }
 
-impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf>
-    RequestType<M, Q, OptBufNo, P, PB>
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf, E: DeserializeOwned>
+    RequestType<M, Q, OptBufNo, P, PB, E>
 {
     /// Build a request and serialize it into buf.
-    pub fn request(&mut self, rpc_client: &mut RpcClient, mut buf: &mut [u8]) -> Result<usize> {
-        let mut header = RequestHeader {
-            method_idx: M::METHOD_ID,
-            chan_id: 0,
-            opts: 0,
-            body_len: 0,
-            buf_len: 0,
-        };
-        let n = rpc_client.req(&mut header, &self.body, None, PB::opt_buf(), &mut buf)?;
+    pub fn request<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut header = RequestHeader::new(M::METHOD_ID);
+        let n = rpc_client.req(&mut header, &self.body, None, PB::opt_buf(), buf)?;
         self.chan_id = header.chan_id;
         Ok(n)
     }
 }
 
-impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf>
-    RequestType<M, Q, OptBufYes, P, PB>
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf, E: DeserializeOwned>
+    RequestType<M, Q, OptBufYes, P, PB, E>
 {
     /// Build a request and serialize it into buf.
-    pub fn request(
+    pub fn request<S: ReplyStorage>(
         &mut self,
         req_body_buf: &[u8],
-        rpc_client: &mut RpcClient,
-        mut buf: &mut [u8],
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
     ) -> Result<usize> {
-        let mut header = RequestHeader {
-            method_idx: M::METHOD_ID,
-            chan_id: 0,
-            opts: 0,
-            body_len: 0,
-            buf_len: 0,
-        };
+        let mut header = RequestHeader::new(M::METHOD_ID);
         let n = rpc_client.req(
             &mut header,
             &self.body,
             Some(req_body_buf),
             PB::opt_buf(),
-            &mut buf,
+            buf,
         )?;
         self.chan_id = header.chan_id;
         Ok(n)
     }
 }
 
-impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf>
-    RequestType<M, Q, QB, P, OptBufYes>
+/// Deserialize the body of a reply, which holds the method error or the protocol error code if
+/// one of the error flags is set.
+fn reply_from_bytes<P: DeserializeOwned, E: DeserializeOwned>(
+    rep_header: &ReplyHeader,
+    rep_body_buf: &[u8],
+) -> MethodResult<P, E> {
+    if rep_header.is_err_code() {
+        let code: ErrorCode = postcard::from_bytes(rep_body_buf)?;
+        Err(MethodError::Client(code.into()))
+    } else if rep_header.is_err() {
+        Err(MethodError::Method(postcard::from_bytes(rep_body_buf)?))
+    } else {
+        Ok(postcard::from_bytes(rep_body_buf)?)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf, E: DeserializeOwned>
+    RequestType<M, Q, QB, P, OptBufYes, E>
 {
     /// Try to take the reply for this request from the RPC Client.  If no such reply exists,
     /// returns None.
-    pub fn take_reply<'a>(
+    pub fn take_reply<'a, S: ReplyStorage>(
         &mut self,
-        rpc_client: &'a mut RpcClient,
-    ) -> Option<Result<(P, &'a [u8])>> {
-        match rpc_client.take_reply(self.chan_id) {
-            None => None,
-            Some((_rep_header, rep_body_buf, opt_buf)) => Some(
-                postcard::from_bytes(rep_body_buf)
-                    .map(|r| (r, opt_buf))
-                    .map_err(|e| e.into()),
-            ),
-        }
+        rpc_client: &'a mut RpcClient<S>,
+    ) -> Option<MethodResult<(P, &'a [u8]), E>> {
+        rpc_client
+            .take_reply(self.chan_id)
+            .map(|(rep_header, rep_body_buf, opt_buf)| {
+                reply_from_bytes(&rep_header, rep_body_buf).map(|r| (r, opt_buf))
+            })
+    }
+
+    /// Try to take the in place reply for this request from the RPC Client and the receive
+    /// buffer `rcv_buf` in which it was parsed.  The optional buffer is borrowed from `rcv_buf`
+    /// without copying it.  See [`RpcClient::take_reply_in_place`].
+    pub fn take_reply_in_place<'b, S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        rcv_buf: &'b [u8],
+    ) -> Option<MethodResult<(P, &'b [u8]), E>> {
+        rpc_client.take_reply_in_place(self.chan_id, rcv_buf).map(
+            |(rep_header, rep_body_buf, opt_buf)| {
+                reply_from_bytes(&rep_header, rep_body_buf).map(|r| (r, opt_buf))
+            },
+        )
     }
 }
 
-impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf>
-    RequestType<M, Q, QB, P, OptBufNo>
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf, E: DeserializeOwned>
+    RequestType<M, Q, QB, P, OptBufNo, E>
 {
     /// Try to take the reply for this request from the RPC Client.  If no such reply exists,
     /// returns None.
-    pub fn take_reply(&mut self, rpc_client: &mut RpcClient) -> Option<Result<P>> {
-        match rpc_client.take_reply(self.chan_id) {
-            None => None,
-            Some((_rep_header, rep_body_buf, _opt_buf)) => {
-                Some(postcard::from_bytes(&rep_body_buf).map_err(|e| e.into()))
-            }
-        }
+    pub fn take_reply<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+    ) -> Option<MethodResult<P, E>> {
+        rpc_client
+            .take_reply(self.chan_id)
+            .map(|(rep_header, rep_body_buf, _opt_buf)| reply_from_bytes(&rep_header, rep_body_buf))
+    }
+
+    /// Try to take the in place reply for this request from the RPC Client and the receive
+    /// buffer `rcv_buf` in which it was parsed.  See [`RpcClient::take_reply_in_place`].
+    pub fn take_reply_in_place<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        rcv_buf: &[u8],
+    ) -> Option<MethodResult<P, E>> {
+        rpc_client
+            .take_reply_in_place(self.chan_id, rcv_buf)
+            .map(|(rep_header, rep_body_buf, _opt_buf)| reply_from_bytes(&rep_header, rep_body_buf))
     }
 }
 
+/// Type used to build a Request for a stream RPC Call, which gets replies until the server ends
+/// the stream.
 #[derive(Debug)]
+pub struct StreamType<
+    M: MethodId,
+    Q: Serialize,
+    QB: OptBuf,
+    P: DeserializeOwned,
+    PB: OptBuf,
+    E: DeserializeOwned = (),
+> {
+    chan_id: u8,
+    body: Q,
+    done: bool,
+    phantom: PhantomData<(M, QB, P, PB, E)>,
+}
+
+impl<
+        M: MethodId,
+        Q: Serialize,
+        QB: OptBuf,
+        P: DeserializeOwned,
+        PB: OptBuf,
+        E: DeserializeOwned,
+    > StreamType<M, Q, QB, P, PB, E>
+{
+    pub fn new(req: Q) -> Self {
+        Self {
+            chan_id: 0,
+            body: req,
+            done: false,
+            phantom: PhantomData::<(M, QB, P, PB, E)>,
+        }
+    }
+
+    pub fn chan_id(&self) -> u8 {
+        self.chan_id
+    }
+
+    /// Returns true once the server has ended the stream or the stream has been cancelled.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Build a packet that cancels this stream and serialize it into buf.  See
+    /// [`RpcClient::cancel`].
+    pub fn cancel<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.done = true;
+        rpc_client.cancel(self.chan_id, buf)
+    }
+
+    /// Handle the next reply of the stream taken from the RPC Client, marking the stream as done
+    /// if it's the last one.  Returns None if there's no reply or if the reply ends the stream.
+    fn next_reply<'a>(
+        &mut self,
+        reply: Option<(ReplyHeader, &'a [u8], &'a [u8])>,
+    ) -> Option<(ReplyHeader, &'a [u8], &'a [u8])> {
+        let (rep_header, rep_body_buf, opt_buf) = reply?;
+        let is_err = rep_header.is_err() || rep_header.is_err_code();
+        if rep_header.is_once() || is_err {
+            self.done = true;
+        }
+        if rep_header.is_once() && !is_err {
+            return None;
+        }
+        Some((rep_header, rep_body_buf, opt_buf))
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf, E: DeserializeOwned>
+    StreamType<M, Q, OptBufNo, P, PB, E>
+{
+    /// Build a request that opens the stream and serialize it into buf.
+    pub fn request<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut header = RequestHeader::new(M::METHOD_ID);
+        let n = rpc_client.req_stream(&mut header, &self.body, None, PB::opt_buf(), buf)?;
+        self.chan_id = header.chan_id;
+        Ok(n)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, PB: OptBuf, E: DeserializeOwned>
+    StreamType<M, Q, OptBufYes, P, PB, E>
+{
+    /// Build a request that opens the stream and serialize it into buf.
+    pub fn request<S: ReplyStorage>(
+        &mut self,
+        req_body_buf: &[u8],
+        rpc_client: &mut RpcClient<S>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut header = RequestHeader::new(M::METHOD_ID);
+        let n = rpc_client.req_stream(
+            &mut header,
+            &self.body,
+            Some(req_body_buf),
+            PB::opt_buf(),
+            buf,
+        )?;
+        self.chan_id = header.chan_id;
+        Ok(n)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf, E: DeserializeOwned>
+    StreamType<M, Q, QB, P, OptBufYes, E>
+{
+    /// Try to take the next item of the stream from the RPC Client.  If no item has been
+    /// received yet or the stream has ended, returns None.
+    pub fn take_item<'a, S: ReplyStorage>(
+        &mut self,
+        rpc_client: &'a mut RpcClient<S>,
+    ) -> Option<MethodResult<(P, &'a [u8]), E>> {
+        self.next_reply(rpc_client.take_reply(self.chan_id)).map(
+            |(rep_header, rep_body_buf, opt_buf)| {
+                reply_from_bytes(&rep_header, rep_body_buf).map(|r| (r, opt_buf))
+            },
+        )
+    }
+
+    /// Try to take the next in place item of the stream from the RPC Client and the receive
+    /// buffer `rcv_buf` in which it was parsed.  The optional buffer is borrowed from `rcv_buf`
+    /// without copying it.  See [`RpcClient::take_reply_in_place`].
+    pub fn take_item_in_place<'b, S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        rcv_buf: &'b [u8],
+    ) -> Option<MethodResult<(P, &'b [u8]), E>> {
+        self.next_reply(rpc_client.take_reply_in_place(self.chan_id, rcv_buf))
+            .map(|(rep_header, rep_body_buf, opt_buf)| {
+                reply_from_bytes(&rep_header, rep_body_buf).map(|r| (r, opt_buf))
+            })
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, QB: OptBuf, E: DeserializeOwned>
+    StreamType<M, Q, QB, P, OptBufNo, E>
+{
+    /// Try to take the next item of the stream from the RPC Client.  If no item has been
+    /// received yet or the stream has ended, returns None.
+    pub fn take_item<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+    ) -> Option<MethodResult<P, E>> {
+        self.next_reply(rpc_client.take_reply(self.chan_id))
+            .map(|(rep_header, rep_body_buf, _opt_buf)| reply_from_bytes(&rep_header, rep_body_buf))
+    }
+
+    /// Try to take the next in place item of the stream from the RPC Client and the receive
+    /// buffer `rcv_buf` in which it was parsed.  See [`RpcClient::take_reply_in_place`].
+    pub fn take_item_in_place<S: ReplyStorage>(
+        &mut self,
+        rpc_client: &mut RpcClient<S>,
+        rcv_buf: &[u8],
+    ) -> Option<MethodResult<P, E>> {
+        self.next_reply(rpc_client.take_reply_in_place(self.chan_id, rcv_buf))
+            .map(|(rep_header, rep_body_buf, _opt_buf)| reply_from_bytes(&rep_header, rep_body_buf))
+    }
+}
+
 enum State {
-    Idle,
-    WaitHeader { chan_id: u8, opt_buf: bool },
-    WaitBody { header: ReplyHeader },
-    WaitTakeReply { header: ReplyHeader },
+    WaitHeader,
+    /// Waiting for the rest of the reply, with the CRC of the header if it has a trailer.
+    WaitBody {
+        header: ReplyHeader,
+        digest: Option<Digest<'static, u16>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SlotState {
+    Free,
+    WaitReply { opt_buf: bool, stream: bool },
+    Done,
+    Cancelled { stream: bool },
+}
+
+/// Buffer in which an [`RpcClient`] stores the parsed replies until they are taken.  Each
+/// reply takes [`REPLY_RECORD_LEN`] bytes, followed by its body and optional buffer unless it's
+/// an in place reply (see [`RpcClient::set_in_place`]).
+pub trait ReplyStorage {
+    fn buf(&self) -> &[u8];
+    fn buf_mut(&mut self) -> &mut [u8];
+    /// Make room for `len` bytes in the buffer.  Returns false if the storage can't hold them.
+    fn reserve(&mut self, len: usize) -> bool {
+        len <= self.buf().len()
+    }
 }
 
+impl ReplyStorage for &mut [u8] {
+    fn buf(&self) -> &[u8] {
+        self
+    }
+    fn buf_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl<const N: usize> ReplyStorage for [u8; N] {
+    fn buf(&self) -> &[u8] {
+        self
+    }
+    fn buf_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+/// Growable storage that never runs out of room.
+#[cfg(feature = "std")]
+impl ReplyStorage for Vec<u8> {
+    fn buf(&self) -> &[u8] {
+        self
+    }
+    fn buf_mut(&mut self) -> &mut [u8] {
+        self
+    }
+    fn reserve(&mut self, len: usize) -> bool {
+        if len > self.len() {
+            self.resize(len, 0);
+        }
+        true
+    }
+}
+
+/// Reply storage of an [`RpcClient`] built without naming one: a growable `Vec<u8>` with the
+/// `std` feature, and a `'static` buffer without it.
+#[cfg(feature = "std")]
+pub type DefaultReplyStorage = Vec<u8>;
+#[cfg(not(feature = "std"))]
+pub type DefaultReplyStorage = &'static mut [u8];
+
+/// Length of the record of a reply in the reply storage: the reply header followed by the
+/// offset of in place replies in their receive buffer, or `0xff` for copied replies, whose
+/// body and optional buffer follow the record.
+pub const REPLY_RECORD_LEN: usize = REP_HEADER_LEN + 1;
+const COPIED: u8 = 0xff;
+
+/// Maximum number of requests that can be waiting for a reply at the same time.  Channel id 0 is
+/// never used.
+pub const MAX_IN_FLIGHT: usize = 255;
+
 /// Main component of the RPC Client.  The client keeps the state of the parsed bytes and stores
-/// replies that requests can retreive later.
-pub struct RpcClient {
+/// replies that requests can retreive later.  Up to [`MAX_IN_FLIGHT`] requests can be waiting
+/// for a reply at the same time, each one in its own channel id, and replies can be taken in any
+/// order.  The replies are stored in a [`ReplyStorage`], which is a growable buffer for clients
+/// built with [`RpcClient::new`], or a caller provided buffer for clients built with
+/// [`RpcClient::with_storage`].
+///
+/// # Examples
+///
+/// ```
+/// use urpc::{client_requests, client, consts};
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut send_buf = vec![0; 32];
+///
+/// let mut req1 = cli::Ping::new([0, 1, 2, 3]);
+/// req1.request(&mut rpc_client, &mut send_buf).unwrap();
+/// let mut req2 = cli::Ping::new([4, 5, 6, 7]);
+/// req2.request(&mut rpc_client, &mut send_buf).unwrap();
+/// assert_ne!(req1.chan_id(), req2.chan_id());
+///
+/// // The server replies to the second request first
+/// let recv_buf = [
+///     0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x05, 0x06, 0x07,
+///     0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+/// ];
+/// let mut pos = 0;
+/// let mut read_len = consts::REP_HEADER_LEN;
+/// while pos < recv_buf.len() {
+///     let buf = &recv_buf[pos..pos + read_len];
+///     pos += read_len;
+///     read_len = rpc_client.parse(&buf).unwrap().0;
+/// }
+///
+/// assert_eq!(req1.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+/// assert_eq!(req2.take_reply(&mut rpc_client).unwrap().unwrap(), [4, 5, 6, 7]);
+/// ```
+pub struct RpcClient<S: ReplyStorage = DefaultReplyStorage> {
     chan_id: u8,
+    crc: bool,
+    in_place: bool,
+    max_buf_len: usize,
     state: State,
-    buf: Vec<u8>,
+    slots: [SlotState; MAX_IN_FLIGHT + 1],
+    storage: S,
+    /// Length of the reply records at the start of `storage`.
+    used: usize,
 }
 
-impl RpcClient {
-    /// Create a new RPC Client.
+#[cfg(feature = "std")]
+impl RpcClient<Vec<u8>> {
+    /// Create a new RPC Client that stores the replies in a growable buffer.
     pub fn new(max_buf_len: u16) -> Self {
+        Self::with_storage(max_buf_len, Vec::new())
+    }
+}
+
+impl<S: ReplyStorage> RpcClient<S> {
+    /// Create a new RPC Client that stores the replies in `storage` until they are taken, which
+    /// doesn't need a heap.  A reply that doesn't fit in the storage is rejected with
+    /// [`Error::ReplyStorageFull`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use urpc::{client, consts};
+    ///
+    /// mod cli {
+    ///     use urpc::client_requests;
+    ///
+    ///     client_requests! {
+    ///         client_requests;
+    ///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+    ///     }
+    /// }
+    ///
+    /// // Room for a single reply
+    /// let mut rpc_client = client::RpcClient::with_storage(32, [0; client::REPLY_RECORD_LEN + 4]);
+    /// let mut send_buf = [0; 32];
+    ///
+    /// let mut req1 = cli::Ping::new([0, 1, 2, 3]);
+    /// req1.request(&mut rpc_client, &mut send_buf).unwrap();
+    /// let mut req2 = cli::Ping::new([4, 5, 6, 7]);
+    /// req2.request(&mut rpc_client, &mut send_buf).unwrap();
+    ///
+    /// let recv_buf = [
+    ///     0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+    ///     0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x05, 0x06, 0x07,
+    /// ];
+    /// rpc_client.parse(&recv_buf[..consts::REP_HEADER_LEN]).unwrap();
+    /// rpc_client.parse(&recv_buf[consts::REP_HEADER_LEN..10]).unwrap();
+    /// rpc_client.parse(&recv_buf[10..10 + consts::REP_HEADER_LEN]).unwrap();
+    /// match rpc_client.parse(&recv_buf[10 + consts::REP_HEADER_LEN..]) {
+    ///     Err(client::Error::ReplyStorageFull) => {}
+    ///     r => panic!("unexpected result: {:?}", r),
+    /// }
+    /// assert_eq!(req1.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+    /// ```
+    pub fn with_storage(max_buf_len: u16, storage: S) -> Self {
         RpcClient {
-            chan_id: 1, // Use 1 to avoid a successful parse of a zeroed buffer.
-            state: State::Idle,
-            buf: vec![0; max_buf_len as usize],
+            chan_id: 0,
+            crc: false,
+            in_place: false,
+            max_buf_len: max_buf_len as usize,
+            state: State::WaitHeader,
+            slots: [SlotState::Free; MAX_IN_FLIGHT + 1],
+            storage,
+            used: 0,
+        }
+    }
+
+    /// Returns the reply storage.
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Enable or disable the CRC trailer in the following requests.  The server replies to
+    /// requests with a CRC trailer with replies that also have it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use urpc::{client, consts, server::{self, Request}, server_requests};
+    ///
+    /// mod cli {
+    ///     use urpc::client_requests;
+    ///
+    ///     client_requests! {
+    ///         client_requests;
+    ///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+    ///     }
+    /// }
+    ///
+    /// server_requests! {
+    ///     ServerRequest;
+    ///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    ///     (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+    /// }
+    ///
+    /// let mut rpc_client = client::RpcClient::new(32);
+    /// let mut rpc_server = server::RpcServer::new(32);
+    /// let mut send_buf = vec![0; 32];
+    /// let mut reply_buf = vec![0; 32];
+    /// rpc_client.set_crc(true);
+    ///
+    /// let mut req = cli::Ping::new([0, 1, 2, 3]);
+    /// let n = req.request(&mut rpc_client, &mut send_buf).unwrap();
+    /// // A bit flips in the body on the way to the server
+    /// send_buf[consts::REQ_HEADER_LEN] ^= 0x01;
+    ///
+    /// let read_len = match ServerRequest::from_rpc(&mut rpc_server, &send_buf[..consts::REQ_HEADER_LEN]) {
+    ///     Ok(server::ParseResult::NeedBytes(n)) => n,
+    ///     _ => panic!("expected NeedBytes"),
+    /// };
+    /// let err = ServerRequest::from_rpc(&mut rpc_server, &send_buf[consts::REQ_HEADER_LEN..n])
+    ///     .unwrap_err();
+    /// assert_eq!(read_len, n - consts::REQ_HEADER_LEN);
+    /// let reply_len = rpc_server.reply_error_code(err.code().unwrap(), &mut reply_buf).unwrap().unwrap();
+    ///
+    /// let read_len = rpc_client.parse(&reply_buf[..consts::REP_HEADER_LEN]).unwrap().0;
+    /// rpc_client.parse(&reply_buf[consts::REP_HEADER_LEN..reply_len]).unwrap();
+    /// assert_eq!(read_len, reply_len - consts::REP_HEADER_LEN);
+    /// match req.take_reply(&mut rpc_client).unwrap() {
+    ///     Err(client::MethodError::Client(client::Error::Resend)) => {}
+    ///     r => panic!("unexpected reply: {:?}", r),
+    /// }
+    /// ```
+    pub fn set_crc(&mut self, crc: bool) {
+        self.crc = crc;
+    }
+
+    /// Enable or disable the in place mode for the following replies.  By default the body and
+    /// the optional buffer of a reply are copied into its slot by [`RpcClient::parse`], and
+    /// taken with [`RpcClient::take_reply`].  In place replies are not copied: the receive
+    /// buffer passed to the `parse` call that completes the reply (or the frame passed to
+    /// [`RpcClient::parse_frame`]) is their slot, and they are taken with
+    /// [`RpcClient::take_reply_in_place`], which borrows the body and optional buffer from it.
+    /// The receive buffer must then be kept untouched until the reply is taken.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use urpc::{client, consts};
+    ///
+    /// mod cli {
+    ///     use urpc::client_requests;
+    ///
+    ///     client_requests! {
+    ///         client_requests;
+    ///         (0, recv_bytes, RecvBytes(u8, OptBufNo, u32, OptBufYes))
+    ///     }
+    /// }
+    ///
+    /// let mut rpc_client = client::RpcClient::new(32);
+    /// let mut send_buf = vec![0; 32];
+    /// rpc_client.set_in_place(true);
+    ///
+    /// let mut req = cli::RecvBytes::new(3);
+    /// req.request(&mut rpc_client, &mut send_buf).unwrap();
+    ///
+    /// let recv_buf = [
+    ///     0x01, 0x00, 0x04, 0x00, 0x03, 0x00, 0x0a, 0x0b, 0x0c, 0x03, 0x00, 0x00, 0x00,
+    /// ];
+    /// let (header, body) = recv_buf.split_at(consts::REP_HEADER_LEN);
+    /// rpc_client.parse(header).unwrap();
+    /// rpc_client.parse(body).unwrap();
+    ///
+    /// let (n, buf) = req.take_reply_in_place(&mut rpc_client, body).unwrap().unwrap();
+    /// assert_eq!(n, 3);
+    /// assert_eq!(buf, &[0x0a, 0x0b, 0x0c]);
+    /// // The optional buffer is a slice of the receive buffer
+    /// assert_eq!(buf.as_ptr(), body.as_ptr());
+    /// ```
+    pub fn set_in_place(&mut self, in_place: bool) {
+        self.in_place = in_place;
+    }
+
+    /// Serialize a request header into `buf`, followed by a CRC trailer if enabled.  The body
+    /// and optional buffer must already be in `buf`.  Returns the length of the request.
+    fn serialize_header(&self, header: &mut RequestHeader, buf: &mut [u8]) -> Result<usize> {
+        header.opts = Opts {
+            crc: self.crc,
+            ..Opts::from_bits_truncate(header.opts)
+        }
+        .into();
+        postcard::to_slice(&header, buf)?;
+        let len = REQ_HEADER_LEN + header.body_len() + header.buf_len();
+        if self.crc {
+            return Ok(append_crc(buf, len)?);
         }
+        Ok(len)
+    }
+
+    /// Find a free channel id, starting after the last one handed out.
+    /// Channels of cancelled requests are only reused when there are no free ones left.
+    fn next_chan_id(&mut self) -> Result<u8> {
+        for reuse_cancelled in [false, true].iter() {
+            for _ in 0..MAX_IN_FLIGHT {
+                // Skip 0 to avoid a successful parse of a zeroed buffer.
+                self.chan_id = self.chan_id.wrapping_add(1).max(1);
+                match self.slots[self.chan_id as usize] {
+                    SlotState::Free => return Ok(self.chan_id),
+                    SlotState::Cancelled { .. } if *reuse_cancelled => return Ok(self.chan_id),
+                    _ => {}
+                }
+            }
+        }
+        Err(Error::NoFreeChanId)
+    }
+
+    /// Number of requests that are waiting for a reply or whose reply hasn't been taken yet.
+    pub fn in_flight(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|state| match state {
+                SlotState::WaitReply { .. } | SlotState::Done => true,
+                SlotState::Free | SlotState::Cancelled { .. } => false,
+            })
+            .count()
+    }
+
+    /// Drop any partially parsed reply, so that the next bytes parsed are a reply header.  The
+    /// complete replies and the requests waiting for a reply are kept.  Useful to recover when
+    /// the server stops sending in the middle of a reply.
+    pub fn reset(&mut self) {
+        self.state = State::WaitHeader;
+    }
+
+    /// Returns true if a request is waiting for replies in channel `chan_id`.
+    #[cfg(feature = "async")]
+    pub(crate) fn is_waiting(&self, chan_id: u8) -> bool {
+        matches!(self.slots[chan_id as usize], SlotState::WaitReply { .. })
+    }
+
+    /// Returns true if the parsed header is followed by a body, which has to be parsed next.
+    #[cfg(feature = "async")]
+    pub(crate) fn is_wait_body(&self) -> bool {
+        matches!(self.state, State::WaitBody { .. })
+    }
+
+    /// Serialize a packet that cancels the request in channel `chan_id` into `buf`.  The slot is
+    /// freed right away, dropping any reply not taken yet, and late replies in the channel are
+    /// dropped too.  Returns the number of bytes written to `buf`, which is 0 if the server
+    /// already sent all the replies of the request.
+    pub fn cancel(&mut self, chan_id: u8, buf: &mut [u8]) -> Result<usize> {
+        let n = match self.slots[chan_id as usize] {
+            SlotState::WaitReply { stream, .. } => {
+                let mut header = RequestHeader::new(0);
+                header.chan_id = chan_id;
+                header.opts = Opts {
+                    cancel: true,
+                    ..Opts::default()
+                }
+                .into();
+                let n = self.serialize_header(&mut header, buf)?;
+                self.slots[chan_id as usize] = SlotState::Cancelled { stream };
+                n
+            }
+            SlotState::Done => {
+                self.slots[chan_id as usize] = SlotState::Free;
+                0
+            }
+            SlotState::Free | SlotState::Cancelled { .. } => {
+                return Err(Error::UnexpectedChanId(chan_id))
+            }
+        };
+        while let Some((pos, rep_header, offset)) = self.find_reply(chan_id) {
+            self.remove_reply(pos, &rep_header, offset);
+        }
+        Ok(n)
     }
 
     /// Serialize a request packet built from (`header`, `body`, `req_body_buf`) into `buf`.
-    /// Prepare a reply slot with (`rep_body_buf`, `rep_opt_buf`).  Returns the number of bytes
-    /// written to `buf`.
-    pub fn req<S: Serialize>(
+    /// Prepare a reply slot in a free channel id that expects an optional buffer if
+    /// `rep_opt_buf` is true.  Returns the number of bytes written to `buf`.
+    pub fn req<T: Serialize>(
         &mut self,
         header: &mut RequestHeader,
-        body: &S,
+        body: &T,
         req_body_buf: Option<&[u8]>,
         rep_opt_buf: bool,
-        mut buf: &mut [u8],
+        buf: &mut [u8],
     ) -> Result<usize> {
-        match self.state {
-            State::Idle => {}
-            _ => return Err(Error::NotIdle),
-        }
+        self.req_slot(header, body, req_body_buf, rep_opt_buf, false, buf)
+    }
+
+    /// Like [`RpcClient::req`], but prepare a reply slot for a stream, which receives replies
+    /// until the server ends the stream.
+    pub fn req_stream<T: Serialize>(
+        &mut self,
+        header: &mut RequestHeader,
+        body: &T,
+        req_body_buf: Option<&[u8]>,
+        rep_opt_buf: bool,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.req_slot(header, body, req_body_buf, rep_opt_buf, true, buf)
+    }
+
+    fn req_slot<T: Serialize>(
+        &mut self,
+        header: &mut RequestHeader,
+        body: &T,
+        req_body_buf: Option<&[u8]>,
+        rep_opt_buf: bool,
+        stream: bool,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let chan_id = self.next_chan_id()?;
         let body_buf = postcard::to_slice(&body, &mut buf[REQ_HEADER_LEN..])?;
         header.body_len = body_buf.len() as u16;
-        header.chan_id = self.chan_id;
-        self.state = State::WaitHeader {
-            chan_id: header.chan_id,
-            opt_buf: rep_opt_buf,
-        };
+        header.chan_id = chan_id;
         // Serialize the request (with the optional buffer)
         if let Some(req_body_buf) = req_body_buf {
             header.buf_len = req_body_buf.len() as u16;
             buf[REQ_HEADER_LEN + header.body_len()
                 ..REQ_HEADER_LEN + header.body_len() + req_body_buf.len()]
-                .copy_from_slice(&req_body_buf);
+                .copy_from_slice(req_body_buf);
         }
-        postcard::to_slice(&header, &mut buf)?;
-        Ok(REQ_HEADER_LEN + header.body_len() + header.buf_len())
+        let len = self.serialize_header(header, buf)?;
+        self.slots[chan_id as usize] = SlotState::WaitReply {
+            opt_buf: rep_opt_buf,
+            stream,
+        };
+        Ok(len)
     }
 
     /// Parse an received buffer in order to advance the deserialization of a reply.  Returns the
     /// number of bytes needed to keep advancing, and optionally the channel number of the completed
     /// deserialized reply.
     pub fn parse(&mut self, rcv_buf: &[u8]) -> Result<(usize, Option<u8>)> {
-        let rcv_buf = rcv_buf;
-        loop {
-            let mut state = State::Idle;
-            swap(&mut state, &mut self.state);
-            match state {
-                // Initial state: waiting for the header bytes
-                State::WaitHeader { chan_id, opt_buf } => {
-                    let rep_header = rep_header_from_bytes(&rcv_buf)?;
-                    if rep_header.chan_id != chan_id {
-                        return Err(Error::TODO);
-                    }
-                    // Check that the body buffer will fit in the reply slot.
-                    if rep_header.body_len() > self.buf.len() {
-                        return Err(Error::ReplyBodyTooLong);
-                    }
-                    if !opt_buf && rep_header.buf_len != 0 {
-                        return Err(Error::ReplyOptBufUnexpected);
-                    }
-                    let n = rep_header.body_len() + rep_header.buf_len();
-                    if opt_buf && n > self.buf.len() {
-                        return Err(Error::ReplyOptBufTooLong);
-                    }
-                    self.state = State::WaitBody { header: rep_header };
-                    if n != 0 {
-                        return Ok((n, None));
-                    }
+        self.parse_at(rcv_buf, 0)
+    }
+
+    /// Like [`RpcClient::parse`], where `rcv_buf` is at `offset` of the buffer in which in place
+    /// replies are taken.
+    fn parse_at(&mut self, rcv_buf: &[u8], offset: usize) -> Result<(usize, Option<u8>)> {
+        let mut state = State::WaitHeader;
+        swap(&mut state, &mut self.state);
+        match state {
+            // Initial state: waiting for the header bytes
+            State::WaitHeader => {
+                let rep_header = rep_header_from_bytes(rcv_buf)?;
+                Opts::try_from(rep_header.opts)?;
+                let opt_buf = match self.slots[rep_header.chan_id as usize] {
+                    SlotState::WaitReply { opt_buf, .. } => opt_buf,
+                    // Late replies of a cancelled request are read and dropped.
+                    SlotState::Cancelled { .. } => true,
+                    _ => return Err(Error::UnexpectedChanId(rep_header.chan_id)),
+                };
+                // Check that the body buffer will fit in the reply slot.
+                if rep_header.body_len() > self.max_buf_len {
+                    return Err(Error::ReplyBodyTooLong);
                 }
-                // Received body bytes
-                State::WaitBody { header: rep_header } => {
-                    let n = rep_header.body_len() + rep_header.buf_len();
-                    if n > rcv_buf.len() {
-                        return Err(Error::ReceivedBufTooShort);
+                if (!opt_buf || rep_header.is_err() || rep_header.is_err_code())
+                    && rep_header.buf_len != 0
+                {
+                    return Err(Error::ReplyOptBufUnexpected);
+                }
+                let n = rep_header.body_len() + rep_header.buf_len();
+                if opt_buf && n > self.max_buf_len {
+                    return Err(Error::ReplyOptBufTooLong);
+                }
+                if rep_header.is_crc() {
+                    let digest = Some(crc_digest(&rcv_buf[..REP_HEADER_LEN]));
+                    self.state = State::WaitBody {
+                        header: rep_header,
+                        digest,
+                    };
+                    return Ok((n + CRC_LEN, None));
+                }
+                if n != 0 {
+                    self.state = State::WaitBody {
+                        header: rep_header,
+                        digest: None,
+                    };
+                    return Ok((n, None));
+                }
+                self.complete(rep_header, &[], offset)
+            }
+            // Received body bytes
+            State::WaitBody {
+                header: rep_header,
+                digest,
+            } => {
+                let n = rep_header.body_len() + rep_header.buf_len();
+                let rcv_buf = match digest {
+                    Some(digest) => {
+                        if n + CRC_LEN > rcv_buf.len() {
+                            return Err(Error::ReceivedBufTooShort);
+                        }
+                        split_crc(digest, &rcv_buf[..n + CRC_LEN]).ok_or(Error::ChecksumMismatch)?
                     }
-                    self.buf[..n].copy_from_slice(&rcv_buf[..n]);
-                    let chan_id = rep_header.chan_id;
-                    self.state = State::WaitTakeReply { header: rep_header };
-                    return Ok((REP_HEADER_LEN, Some(chan_id)));
+                    None => rcv_buf,
+                };
+                if n > rcv_buf.len() {
+                    return Err(Error::ReceivedBufTooShort);
                 }
-                _ => return Err(Error::NotExpectingBytes),
+                self.complete(rep_header, &rcv_buf[..n], offset)
             }
         }
     }
 
-    /// Take the reply of the slot in a channel id if it's complete.
-    pub fn take_reply<'a>(&'a mut self, chan_id: u8) -> Option<(ReplyHeader, &'a [u8], &'a [u8])> {
-        let mut state = State::Idle;
-        swap(&mut state, &mut self.state);
-        match state {
-            State::WaitTakeReply { header: rep_header } if rep_header.chan_id == chan_id => {
-                let body_len = rep_header.body_len as usize;
-                let buf_len = rep_header.buf_len as usize;
-                return Some((
-                    rep_header,
-                    &self.buf[buf_len..buf_len + body_len],
-                    &self.buf[..buf_len],
-                ));
+    /// Store a complete reply in the reply storage.  The slot stops accepting replies after the
+    /// first one, or after the last one for streams.  Replies of cancelled requests are dropped,
+    /// and their slot is freed after the last one.  In place replies are left in `rcv_buf`, at
+    /// `offset` of the buffer in which they are taken.
+    fn complete(
+        &mut self,
+        rep_header: ReplyHeader,
+        rcv_buf: &[u8],
+        offset: usize,
+    ) -> Result<(usize, Option<u8>)> {
+        let chan_id = rep_header.chan_id;
+        let last = rep_header.is_once() || rep_header.is_err() || rep_header.is_err_code();
+        let stream = match self.slots[chan_id as usize] {
+            SlotState::WaitReply { stream, .. } => stream,
+            SlotState::Cancelled { stream } => {
+                if !stream || last {
+                    self.slots[chan_id as usize] = SlotState::Free;
+                }
+                return Ok((REP_HEADER_LEN, None));
+            }
+            SlotState::Free | SlotState::Done => return Ok((REP_HEADER_LEN, None)),
+        };
+        let (tag, data) = if self.in_place {
+            (offset as u8, &[][..])
+        } else {
+            (COPIED, rcv_buf)
+        };
+        let len = REPLY_RECORD_LEN + data.len();
+        if !self.storage.reserve(self.used + len) {
+            return Err(Error::ReplyStorageFull);
+        }
+        let record = &mut self.storage.buf_mut()[self.used..self.used + len];
+        postcard::to_slice(&rep_header, record)?;
+        record[REP_HEADER_LEN] = tag;
+        record[REPLY_RECORD_LEN..].copy_from_slice(data);
+        self.used += len;
+        if !stream || last {
+            self.slots[chan_id as usize] = SlotState::Done;
+        }
+        Ok((REP_HEADER_LEN, Some(chan_id)))
+    }
+
+    /// Find the first reply of the slot in a channel id in the reply storage.  Returns the
+    /// position of its record, its header and its offset tag.
+    fn find_reply(&self, chan_id: u8) -> Option<(usize, ReplyHeader, u8)> {
+        let buf = self.storage.buf();
+        let mut pos = 0;
+        while pos < self.used {
+            let rep_header = rep_header_from_bytes(&buf[pos..]).ok()?;
+            let offset = buf[pos + REP_HEADER_LEN];
+            if rep_header.chan_id == chan_id {
+                return Some((pos, rep_header, offset));
             }
-            _ => {} // TODO: Error
+            pos += record_len(&rep_header, offset);
         }
-        self.state = state;
         None
     }
-}
 
-use std::io;
+    /// Remove the reply record at `pos` from the reply storage, freeing its slot once its last
+    /// reply is removed.  The record is moved right after the remaining records, where it stays
+    /// until the next reply is stored.  Returns its new position.
+    fn remove_reply(&mut self, pos: usize, rep_header: &ReplyHeader, offset: u8) -> usize {
+        let len = record_len(rep_header, offset);
+        self.storage.buf_mut()[pos..self.used].rotate_left(len);
+        self.used -= len;
+        let chan_id = rep_header.chan_id;
+        if let SlotState::Done = self.slots[chan_id as usize] {
+            if self.find_reply(chan_id).is_none() {
+                self.slots[chan_id as usize] = SlotState::Free;
+            }
+        }
+        self.used
+    }
 
-pub struct RpcClientIO<S: io::Read + io::Write> {
-    pub client: RpcClient,
-    stream: S,
-    pub stream_buf: Vec<u8>,
-    pub buf_len: usize,
-    // pub body_buf: Option<Vec<u8>>,
-    // pub opt_buf: Option<Vec<u8>>,
-}
+    /// Parse a whole reply packet, as found in a frame decoded by
+    /// [`framing::Decoder`](../framing/struct.Decoder.html).  Any partially parsed reply is
+    /// discarded first, so a corrupted frame doesn't affect the following ones.  Returns the
+    /// channel number of the completed deserialized reply, if it's not dropped.
+    pub fn parse_frame(&mut self, frame: &[u8]) -> Result<Option<u8>> {
+        self.reset();
+        let rep_header = rep_header_from_bytes(frame)?;
+        let crc_len = if rep_header.is_crc() { CRC_LEN } else { 0 };
+        if frame.len() != REP_HEADER_LEN + rep_header.body_len() + rep_header.buf_len() + crc_len {
+            return Err(Error::FrameLength);
+        }
+        let (_, chan_id) = self.parse(&frame[..REP_HEADER_LEN])?;
+        if frame.len() == REP_HEADER_LEN {
+            return Ok(chan_id);
+        }
+        Ok(self.parse_at(&frame[REP_HEADER_LEN..], REP_HEADER_LEN)?.1)
+    }
 
-#[derive(Debug)]
-pub enum RpcClientIOError {
-    Io(io::Error),
-    Urpc(Error),
-}
+    /// Returns true if the reply of the slot in a channel id is complete and can be taken.
+    pub fn has_reply(&self, chan_id: u8) -> bool {
+        self.find_reply(chan_id).is_some()
+    }
 
-impl From<io::Error> for RpcClientIOError {
-    fn from(err: io::Error) -> Self {
-        Self::Io(err)
+    /// Take the next reply of the slot in a channel id if it's complete.  The slot is freed once
+    /// its last reply is taken.  Returns None for in place replies, see
+    /// [`RpcClient::set_in_place`].
+    pub fn take_reply(&mut self, chan_id: u8) -> Option<(ReplyHeader, &[u8], &[u8])> {
+        let (pos, rep_header, offset) = self.find_reply(chan_id)?;
+        if offset != COPIED {
+            return None;
+        }
+        let pos = self.remove_reply(pos, &rep_header, offset) + REPLY_RECORD_LEN;
+        let body_len = rep_header.body_len();
+        let buf_len = rep_header.buf_len();
+        let buf = &self.storage.buf()[pos..pos + buf_len + body_len];
+        Some((rep_header, &buf[buf_len..], &buf[..buf_len]))
     }
-}
 
-impl From<Error> for RpcClientIOError {
-    fn from(err: Error) -> Self {
-        Self::Urpc(err)
+    /// Take the next in place reply of the slot in a channel id if it's complete, borrowing its
+    /// body and optional buffer from `rcv_buf`, which must be the receive buffer in which the
+    /// reply was parsed.  The slot is freed once its last reply is taken.  Returns None for
+    /// copied replies, or if `rcv_buf` is too short to hold the reply.  See
+    /// [`RpcClient::set_in_place`].
+    pub fn take_reply_in_place<'b>(
+        &mut self,
+        chan_id: u8,
+        rcv_buf: &'b [u8],
+    ) -> Option<(ReplyHeader, &'b [u8], &'b [u8])> {
+        let (pos, rep_header, offset) = self.find_reply(chan_id)?;
+        if offset == COPIED {
+            return None;
+        }
+        let body_len = rep_header.body_len();
+        let buf_len = rep_header.buf_len();
+        let start = offset as usize;
+        let buf = rcv_buf.get(start..start + buf_len + body_len)?;
+        self.remove_reply(pos, &rep_header, offset);
+        Some((rep_header, &buf[buf_len..], &buf[..buf_len]))
     }
 }
 
-impl<S: io::Read + io::Write> RpcClientIO<S> {
-    pub fn new(stream: S, buf_len: usize) -> Self {
+/// Push style parser of the replies received by an [`RpcClient`], for receivers that get bytes
+/// in chunks of any length, like the interrupt handler of a UART.  The reply packets are
+/// buffered until they are complete, so the chunks don't need to match the lengths asked by
+/// [`RpcClient::parse`].  Replies that are rejected while parsing their header are dropped along
+/// with the rest of their bytes, and replies that don't fit in the buffer are rejected with
+/// [`Error::ReplyBodyTooLong`].
+///
+/// In place replies (see [`RpcClient::set_in_place`]) are taken from [`Receiver::packet`]
+/// before pushing more bytes.
+///
+/// # Examples
+///
+/// ```
+/// use urpc::client;
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut send_buf = vec![0; 32];
+///
+/// let mut req1 = cli::Ping::new([0, 1, 2, 3]);
+/// req1.request(&mut rpc_client, &mut send_buf).unwrap();
+/// let mut req2 = cli::Ping::new([4, 5, 6, 7]);
+/// req2.request(&mut rpc_client, &mut send_buf).unwrap();
+///
+/// // Both replies arrive in a single chunk
+/// let chunk = [
+///     0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x05, 0x06, 0x07,
+///     0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+/// ];
+/// let mut recv_buf = vec![0; 16];
+/// let mut receiver = client::Receiver::new(&mut recv_buf);
+/// let mut chan_ids = Vec::new();
+/// let mut pos = 0;
+/// while pos < chunk.len() {
+///     let (n, result) = receiver.push_slice(&mut rpc_client, &chunk[pos..]);
+///     pos += n;
+///     if let Some(chan_id) = result.transpose().unwrap().flatten() {
+///         chan_ids.push(chan_id);
+///     }
+/// }
+///
+/// assert_eq!(chan_ids, [req2.chan_id(), req1.chan_id()]);
+/// assert_eq!(req1.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+/// assert_eq!(req2.take_reply(&mut rpc_client).unwrap().unwrap(), [4, 5, 6, 7]);
+/// ```
+pub struct Receiver<'a> {
+    packet: PacketBuf<'a>,
+}
+
+impl<'a> Receiver<'a> {
+    /// Create a receiver that buffers the reply packets in `buf`, which must hold at least a
+    /// reply header.
+    pub fn new(buf: &'a mut [u8]) -> Self {
         Self {
-            client: RpcClient::new(buf_len as u16),
-            stream: stream,
-            stream_buf: vec![0; buf_len],
-            buf_len: buf_len,
-            // body_buf: Some(vec![0; buf_len]),
-            // opt_buf: Some(vec![0; buf_len]),
+            packet: PacketBuf::new(buf, REP_HEADER_LEN),
         }
     }
 
-    pub fn request(
+    /// Drop any partially received reply, in the receiver and in `rpc_client`.
+    pub fn reset<S: ReplyStorage>(&mut self, rpc_client: &mut RpcClient<S>) {
+        self.packet.reset();
+        rpc_client.reset();
+    }
+
+    /// Push received bytes, parsing them with `rpc_client` until the end of a reply.  Returns
+    /// the number of bytes consumed, and the channel number of the reply if it's complete (None
+    /// if it's dropped), or the error that rejected it.  The bytes left, which may hold the
+    /// following replies, have to be pushed again.
+    pub fn push_slice<S: ReplyStorage>(
         &mut self,
-        chan_id: u8,
-        write_len: usize,
-    ) -> core::result::Result<(), RpcClientIOError> {
-        self.stream.write_all(&self.stream_buf[..write_len])?;
-        self.stream.flush()?;
-
-        let mut read_len = consts::REP_HEADER_LEN;
-        loop {
-            let mut buf = &mut self.stream_buf[..read_len];
-            self.stream.read_exact(&mut buf)?;
-            read_len = match self.client.parse(&buf)? {
-                (n, None) => n,
-                (n, Some(_chan_id)) => {
-                    if _chan_id == chan_id {
-                        return Ok(());
+        rpc_client: &mut RpcClient<S>,
+        bytes: &[u8],
+    ) -> (usize, Option<Result<Option<u8>>>) {
+        let (mut pos, full) = self.packet.fill(bytes);
+        if !full {
+            return (pos, None);
+        }
+        if self.packet.is_header() {
+            let header_buf = &self.packet.buf[..REP_HEADER_LEN];
+            let rest_len = rep_header_from_bytes(header_buf)
+                .map(|header| {
+                    let crc_len = if header.is_crc() { CRC_LEN } else { 0 };
+                    header.body_len() + header.buf_len() + crc_len
+                })
+                .unwrap_or(0);
+            match rpc_client.parse_at(header_buf, 0) {
+                Ok((n, _)) if matches!(rpc_client.state, State::WaitBody { .. }) => {
+                    if !self.packet.expect(n) {
+                        rpc_client.reset();
+                        self.packet.reject(n);
+                        return (pos, Some(Err(Error::ReplyBodyTooLong)));
                     }
-                    n
+                    let (n, full) = self.packet.fill(&bytes[pos..]);
+                    pos += n;
+                    if !full {
+                        return (pos, None);
+                    }
+                }
+                Ok((_, chan_id)) => {
+                    self.packet.done = true;
+                    return (pos, Some(Ok(chan_id)));
+                }
+                Err(err) => {
+                    self.packet.reject(rest_len);
+                    return (pos, Some(Err(err)));
                 }
             }
         }
+        self.packet.done = true;
+        let body_buf = &self.packet.buf[REP_HEADER_LEN..self.packet.len];
+        let result = rpc_client.parse_at(body_buf, REP_HEADER_LEN);
+        (pos, Some(result.map(|(_, chan_id)| chan_id)))
+    }
+
+    /// Last received reply packet, empty if no reply has been completed since the last pushed
+    /// bytes.  In place replies are taken from it.
+    pub fn packet(&self) -> &[u8] {
+        self.packet.packet()
+    }
+}
+
+/// Length of the record of a reply in the reply storage, with its body and optional buffer if
+/// it's a copied reply.
+fn record_len(rep_header: &ReplyHeader, offset: u8) -> usize {
+    match offset {
+        COPIED => REPLY_RECORD_LEN + rep_header.body_len() + rep_header.buf_len(),
+        _ => REPLY_RECORD_LEN,
     }
 }
+
+#[cfg(feature = "std")]
+mod io;
+
+#[cfg(feature = "std")]
+pub use io::*;
@@ -0,0 +1,369 @@
+use super::*;
+use crate::schema::Schema;
+use crate::timeout::{ReadError, TimedReader, Timeout, Timeouts};
+
+use std::io;
+
+pub struct RpcClientIO<S: io::Read + io::Write> {
+    pub client: RpcClient,
+    stream: S,
+    pub stream_buf: Vec<u8>,
+    pub buf_len: usize,
+    timeouts: Timeouts,
+    // pub body_buf: Option<Vec<u8>>,
+    // pub opt_buf: Option<Vec<u8>>,
+}
+
+/// Error of a request made over a stream, where `E` is the custom error of the method.
+#[derive(Debug)]
+pub enum RpcClientIOError<E = ()> {
+    Io(io::Error),
+    Urpc(Error),
+    Method(E),
+    /// A timeout expired while waiting for a reply, whose partially received bytes have been
+    /// dropped.
+    Timeout(Timeout),
+}
+
+/// Result of a request made over a stream, where `E` is the custom error of the method.
+pub type RpcClientIOResult<T, E = ()> = core::result::Result<T, RpcClientIOError<E>>;
+
+impl<E> From<io::Error> for RpcClientIOError<E> {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl<E> From<Error> for RpcClientIOError<E> {
+    fn from(err: Error) -> Self {
+        Self::Urpc(err)
+    }
+}
+
+impl<E> From<ReadError> for RpcClientIOError<E> {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Io(err) => Self::Io(err),
+            ReadError::Timeout(timeout) => Self::Timeout(timeout),
+        }
+    }
+}
+
+impl<E> From<MethodError<E>> for RpcClientIOError<E> {
+    fn from(err: MethodError<E>) -> Self {
+        match err {
+            MethodError::Method(err) => Self::Method(err),
+            MethodError::Client(err) => Self::Urpc(err),
+        }
+    }
+}
+
+impl<S: io::Read + io::Write> RpcClientIO<S> {
+    pub fn new(stream: S, buf_len: usize) -> Self {
+        Self {
+            client: RpcClient::new(buf_len as u16),
+            stream,
+            stream_buf: vec![0; buf_len],
+            buf_len,
+            timeouts: Timeouts::default(),
+            // body_buf: Some(vec![0; buf_len]),
+            // opt_buf: Some(vec![0; buf_len]),
+        }
+    }
+
+    /// Build an [`RpcClientIO`] like [`RpcClientIO::new`], and check that the server speaks the
+    /// service of `schema` with [`RpcClientIO::check_schema`].
+    pub fn connect(stream: S, buf_len: usize, schema: &Schema) -> RpcClientIOResult<Self> {
+        let mut rpc = Self::new(stream, buf_len);
+        rpc.check_schema(schema)?;
+        Ok(rpc)
+    }
+
+    /// Consume the RPC Client and return its stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Set the timeouts to receive a reply, which are disabled by default.  When a reply times
+    /// out, [`RpcClientIO::wait_reply`] returns [`RpcClientIOError::Timeout`].  The requests
+    /// made with the `call_io` methods are cancelled then, while the other ones keep waiting
+    /// for a reply until they are cancelled.  See the [`timeout`](../timeout/index.html) module
+    /// for the read timeout that the stream needs.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Check that the server is alive with the built-in [`Ping`] method.
+    pub fn ping(&mut self) -> RpcClientIOResult<()> {
+        Ping::new(()).call_io(self)
+    }
+
+    /// Request the server info.
+    pub fn get_info(&mut self) -> RpcClientIOResult<ServerInfo> {
+        GetInfo::new(()).call_io(self)
+    }
+
+    /// Request the maximum buffer length of the server, to size the requests.
+    pub fn max_buf_len(&mut self) -> RpcClientIOResult<u16> {
+        MaxBufLen::new(()).call_io(self)
+    }
+
+    /// Request the schema hash of the server service.
+    pub fn schema_hash(&mut self) -> RpcClientIOResult<u32> {
+        SchemaHash::new(()).call_io(self)
+    }
+
+    /// Check that the schema hash of the server service matches the one of `schema`.  Returns
+    /// [`Error::SchemaMismatch`] with the server schema hash otherwise.
+    pub fn check_schema(&mut self, schema: &Schema) -> RpcClientIOResult<()> {
+        let hash = self.schema_hash()?;
+        if hash != schema.hash {
+            return Err(Error::SchemaMismatch(hash).into());
+        }
+        Ok(())
+    }
+
+    /// Send the request serialized in `stream_buf` and wait for its reply.
+    pub fn request(
+        &mut self,
+        chan_id: u8,
+        write_len: usize,
+    ) -> core::result::Result<(), RpcClientIOError> {
+        self.send(write_len)?;
+        self.wait_reply(chan_id)
+    }
+
+    /// Send the request serialized in `stream_buf`.
+    pub fn send<E>(&mut self, write_len: usize) -> core::result::Result<(), RpcClientIOError<E>> {
+        self.stream.write_all(&self.stream_buf[..write_len])?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Cancel the request in the channel `chan_id`.  See [`RpcClient::cancel`].
+    pub fn cancel<E>(&mut self, chan_id: u8) -> core::result::Result<(), RpcClientIOError<E>> {
+        let write_len = self.client.cancel(chan_id, &mut self.stream_buf)?;
+        self.send(write_len)
+    }
+
+    /// Read replies until there's one for the channel `chan_id`.  The request timeout starts
+    /// now.
+    pub fn wait_reply<E>(&mut self, chan_id: u8) -> core::result::Result<(), RpcClientIOError<E>> {
+        let mut reader = TimedReader::new(self.timeouts);
+        reader.start();
+        let mut read_len = consts::REP_HEADER_LEN;
+        while !self.client.has_reply(chan_id) {
+            let buf = &mut self.stream_buf[..read_len];
+            if let Err(err) = reader.read_exact(&mut self.stream, buf) {
+                self.client.reset();
+                return Err(err.into());
+            }
+            read_len = self.client.parse(buf)?.0;
+            if let State::WaitHeader = self.client.state {
+                reader.end_packet();
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a request made by a `call_io` method, cancelling the request if it can't be sent so
+    /// that its channel is released.
+    fn send_call<E>(
+        &mut self,
+        chan_id: u8,
+        write_len: usize,
+    ) -> core::result::Result<(), RpcClientIOError<E>> {
+        let result = self.send(write_len);
+        if result.is_err() {
+            self.cancel::<E>(chan_id).ok();
+        }
+        result
+    }
+
+    /// Wait for the reply of a request made by a `call_io` method, cancelling the request if
+    /// the reply can't be received so that its channel is released.
+    fn wait_call_reply<E>(&mut self, chan_id: u8) -> core::result::Result<(), RpcClientIOError<E>> {
+        let result = self.wait_reply(chan_id);
+        if result.is_err() {
+            self.cancel::<E>(chan_id).ok();
+        }
+        result
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufNo, P, OptBufNo, E>
+{
+    /// Send the request through an [`RpcClientIO`] and wait for its reply.
+    pub fn call_io<S: io::Read + io::Write>(
+        mut self,
+        rpc: &mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<P, E> {
+        let write_len = self.request(&mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        rpc.wait_call_reply(self.chan_id())?;
+        if let Some(reply) = self.take_reply(&mut rpc.client) {
+            return Ok(reply?);
+        }
+        Ok(self
+            .take_reply_in_place(&mut rpc.client, &rpc.stream_buf)
+            .ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufYes, P, OptBufNo, E>
+{
+    /// Send the request with the optional buffer `req_buf` through an [`RpcClientIO`] and wait
+    /// for its reply.
+    pub fn call_io<S: io::Read + io::Write>(
+        mut self,
+        req_buf: &[u8],
+        rpc: &mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<P, E> {
+        let write_len = self.request(req_buf, &mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        rpc.wait_call_reply(self.chan_id())?;
+        if let Some(reply) = self.take_reply(&mut rpc.client) {
+            return Ok(reply?);
+        }
+        Ok(self
+            .take_reply_in_place(&mut rpc.client, &rpc.stream_buf)
+            .ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufNo, P, OptBufYes, E>
+{
+    /// Send the request through an [`RpcClientIO`] and wait for its reply, which contains an
+    /// optional buffer.
+    pub fn call_io<S: io::Read + io::Write>(
+        mut self,
+        rpc: &mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<(P, Vec<u8>), E> {
+        let write_len = self.request(&mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        rpc.wait_call_reply(self.chan_id())?;
+        if let Some(reply) = self.take_reply(&mut rpc.client) {
+            let (r, buf) = reply?;
+            return Ok((r, buf.to_vec()));
+        }
+        let (r, buf) = self
+            .take_reply_in_place(&mut rpc.client, &rpc.stream_buf)
+            .ok_or(Error::ReceivedBufTooShort)??;
+        Ok((r, buf.to_vec()))
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufYes, P, OptBufYes, E>
+{
+    /// Send the request with the optional buffer `req_buf` through an [`RpcClientIO`] and wait
+    /// for its reply, which contains an optional buffer.
+    pub fn call_io<S: io::Read + io::Write>(
+        mut self,
+        req_buf: &[u8],
+        rpc: &mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<(P, Vec<u8>), E> {
+        let write_len = self.request(req_buf, &mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        rpc.wait_call_reply(self.chan_id())?;
+        if let Some(reply) = self.take_reply(&mut rpc.client) {
+            let (r, buf) = reply?;
+            return Ok((r, buf.to_vec()));
+        }
+        let (r, buf) = self
+            .take_reply_in_place(&mut rpc.client, &rpc.stream_buf)
+            .ok_or(Error::ReceivedBufTooShort)??;
+        Ok((r, buf.to_vec()))
+    }
+}
+
+/// Iterator over the items of a stream received through an [`RpcClientIO`].  Dropping it
+/// before the stream ends cancels the stream.
+pub struct StreamIter<'a, S, M, Q, QB, P, E>
+where
+    S: io::Read + io::Write,
+    M: MethodId,
+    Q: Serialize,
+    QB: OptBuf,
+    P: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    rpc: &'a mut RpcClientIO<S>,
+    stream: StreamType<M, Q, QB, P, OptBufNo, E>,
+}
+
+impl<'a, S, M, Q, QB, P, E> Iterator for StreamIter<'a, S, M, Q, QB, P, E>
+where
+    S: io::Read + io::Write,
+    M: MethodId,
+    Q: Serialize,
+    QB: OptBuf,
+    P: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    type Item = core::result::Result<P, RpcClientIOError<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stream.is_done() {
+            return None;
+        }
+        if let Err(err) = self.rpc.wait_call_reply(self.stream.chan_id()) {
+            self.stream.done = true;
+            return Some(Err(err));
+        }
+        let (rpc, stream) = (&mut *self.rpc, &mut self.stream);
+        stream
+            .take_item(&mut rpc.client)
+            .or_else(|| stream.take_item_in_place(&mut rpc.client, &rpc.stream_buf))
+            .map(|r| r.map_err(|e| e.into()))
+    }
+}
+
+impl<'a, S, M, Q, QB, P, E> Drop for StreamIter<'a, S, M, Q, QB, P, E>
+where
+    S: io::Read + io::Write,
+    M: MethodId,
+    Q: Serialize,
+    QB: OptBuf,
+    P: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    fn drop(&mut self) {
+        if !self.stream.is_done() {
+            self.rpc.cancel::<E>(self.stream.chan_id()).ok();
+        }
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    StreamType<M, Q, OptBufNo, P, OptBufNo, E>
+{
+    /// Open the stream through an [`RpcClientIO`] and return an iterator over its items.
+    pub fn call_io<S: io::Read + io::Write>(
+        mut self,
+        rpc: &mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<StreamIter<'_, S, M, Q, OptBufNo, P, E>, E> {
+        let write_len = self.request(&mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        Ok(StreamIter { rpc, stream: self })
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    StreamType<M, Q, OptBufYes, P, OptBufNo, E>
+{
+    /// Open the stream with the optional buffer `req_buf` through an [`RpcClientIO`] and return
+    /// an iterator over its items.
+    pub fn call_io<'a, S: io::Read + io::Write>(
+        mut self,
+        req_buf: &[u8],
+        rpc: &'a mut RpcClientIO<S>,
+    ) -> RpcClientIOResult<StreamIter<'a, S, M, Q, OptBufYes, P, E>, E> {
+        let write_len = self.request(req_buf, &mut rpc.client, &mut rpc.stream_buf)?;
+        rpc.send_call(self.chan_id(), write_len)?;
+        Ok(StreamIter { rpc, stream: self })
+    }
+}
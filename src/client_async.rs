@@ -0,0 +1,504 @@
+use super::client::{
+    Error, MethodId, RequestType, Result, RpcClient, RpcClientIOError, StreamType,
+};
+use super::consts::*;
+use super::*;
+
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::{self, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// State shared between the callers and the reader task.
+struct Shared {
+    client: RpcClient,
+    wakers: Vec<Option<Waker>>,
+    /// Error that rejected a reply of the request waiting in each channel.
+    errors: Vec<Option<Error>>,
+    /// Cancel packets to send before the next request.
+    cancels: Vec<u8>,
+    closed: bool,
+}
+
+impl Shared {
+    /// Cancel the request in channel `chan_id` locally, and queue its cancel packet to be sent
+    /// before the next request.
+    fn cancel_later(&mut self, chan_id: u8) {
+        let mut buf = [0; REQ_HEADER_LEN + CRC_LEN];
+        if let Ok(n) = self.client.cancel(chan_id, &mut buf) {
+            self.cancels.extend_from_slice(&buf[..n]);
+        }
+        self.wakers[chan_id as usize] = None;
+        self.errors[chan_id as usize] = None;
+    }
+
+    /// Fail the request waiting in channel `chan_id` with `err`, cancelling it.
+    fn fail(&mut self, chan_id: u8, err: Error) {
+        if self.client.is_waiting(chan_id) {
+            self.cancel_later(chan_id);
+            self.errors[chan_id as usize] = Some(err);
+            self.wake(chan_id);
+        }
+    }
+
+    fn wake(&mut self, chan_id: u8) {
+        if let Some(waker) = self.wakers[chan_id as usize].take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.wakers
+            .iter_mut()
+            .filter_map(|waker| waker.take())
+            .for_each(|waker| waker.wake());
+    }
+}
+
+/// Asynchronous RPC Client over a stream that implements `AsyncRead + AsyncWrite`.  The client
+/// can be shared between several callers that make requests at the same time; the replies are
+/// read by a reader task, returned from [`RpcClientAsync::new`], that must be spawned or polled
+/// alongside the requests.
+///
+/// # Examples
+///
+/// ```
+/// use futures::{executor::block_on, io::Cursor, join};
+/// use urpc::client_async::RpcClientAsync;
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+///     }
+/// }
+///
+/// // We use a stream with precalculated replies to simulate a server that replies to the second
+/// // request first
+/// let replies = Cursor::new(vec![
+///     0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x05, 0x06, 0x07,
+///     0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+/// ]);
+/// let (rpc_client, reader) = RpcClientAsync::from_split(replies, futures::io::sink(), 32);
+///
+/// let (r1, r2, _) = block_on(async {
+///     join!(
+///         cli::Ping::new([0, 1, 2, 3]).call_async(&rpc_client),
+///         cli::Ping::new([4, 5, 6, 7]).call_async(&rpc_client),
+///         reader,
+///     )
+/// });
+/// assert_eq!(r1.unwrap(), [0, 1, 2, 3]);
+/// assert_eq!(r2.unwrap(), [4, 5, 6, 7]);
+/// ```
+pub struct RpcClientAsync<W: AsyncWrite + Unpin> {
+    shared: Arc<Mutex<Shared>>,
+    writer: AsyncMutex<(W, Vec<u8>)>,
+}
+
+impl<S: AsyncRead + AsyncWrite> RpcClientAsync<WriteHalf<S>> {
+    /// Create a new asynchronous RPC Client over `stream`.  Returns the client and the reader
+    /// task, which completes with an error once the stream fails or is closed.
+    pub fn new(
+        stream: S,
+        buf_len: usize,
+    ) -> (
+        Self,
+        impl Future<Output = core::result::Result<(), RpcClientIOError>>,
+    ) {
+        let (reader, writer) = stream.split();
+        Self::from_split(reader, writer, buf_len)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> RpcClientAsync<W> {
+    /// Create a new asynchronous RPC Client from the reading and writing halves of a stream.
+    /// Returns the client and the reader task, which completes with an error once the stream
+    /// fails or is closed.
+    pub fn from_split<R: AsyncRead + Unpin>(
+        reader: R,
+        writer: W,
+        buf_len: usize,
+    ) -> (
+        Self,
+        impl Future<Output = core::result::Result<(), RpcClientIOError>>,
+    ) {
+        let shared = Arc::new(Mutex::new(Shared {
+            client: RpcClient::new(buf_len as u16),
+            wakers: (0..=client::MAX_IN_FLIGHT).map(|_| None).collect(),
+            errors: (0..=client::MAX_IN_FLIGHT).map(|_| None).collect(),
+            cancels: Vec::new(),
+            closed: false,
+        }));
+        let rpc_client = Self {
+            shared: shared.clone(),
+            writer: AsyncMutex::new((writer, vec![0; buf_len])),
+        };
+        (rpc_client, read_replies(reader, shared, buf_len))
+    }
+
+    /// Serialize a request with `build`, which gets the RPC Client and the buffer to serialize
+    /// the request into, and send it.  The cancel packets of the dropped calls are sent first.
+    pub async fn send<E, F>(&self, build: F) -> core::result::Result<(), RpcClientIOError<E>>
+    where
+        F: FnOnce(&mut RpcClient, &mut [u8]) -> Result<usize>,
+    {
+        self.send_with(|shared, buf| Ok((build(&mut shared.client, buf)?, ())))
+            .await
+    }
+
+    /// Send the request of a call serialized with `build`, which returns its length and channel.
+    /// Returns the call, which cancels the request if it's dropped before it's done.
+    async fn send_call<E, F>(&self, build: F) -> core::result::Result<Call<'_>, RpcClientIOError<E>>
+    where
+        F: FnOnce(&mut RpcClient, &mut [u8]) -> Result<(usize, u8)>,
+    {
+        self.send_with(|shared, buf| {
+            let (n, chan_id) = build(&mut shared.client, buf)?;
+            shared.errors[chan_id as usize] = None;
+            let call = Call {
+                shared: &self.shared,
+                chan_id,
+                done: false,
+            };
+            Ok((n, call))
+        })
+        .await
+    }
+
+    /// Serialize a packet with `build` and send it after the pending cancel packets.  The value
+    /// returned by `build` is dropped if the packet can't be sent.
+    async fn send_with<T, E, F>(&self, build: F) -> core::result::Result<T, RpcClientIOError<E>>
+    where
+        F: FnOnce(&mut Shared, &mut [u8]) -> Result<(usize, T)>,
+    {
+        let mut writer = self.writer.lock().await;
+        let (stream, buf) = &mut *writer;
+        let (n, value, cancels) = {
+            let mut shared = self.shared.lock().unwrap();
+            let (n, value) = build(&mut shared, buf)?;
+            (n, value, mem::take(&mut shared.cancels))
+        };
+        stream.write_all(&cancels).await?;
+        stream.write_all(&buf[..n]).await?;
+        stream.flush().await?;
+        Ok(value)
+    }
+
+    /// Cancel the request in channel `chan_id`.  See [`RpcClient::cancel`].  The calls made with
+    /// `call_async` cancel their request when they fail or their future is dropped.
+    pub async fn cancel(&self, chan_id: u8) -> core::result::Result<(), RpcClientIOError> {
+        self.send(|client, buf| client.cancel(chan_id, buf)).await?;
+        let mut shared = self.shared.lock().unwrap();
+        shared.wakers[chan_id as usize] = None;
+        shared.errors[chan_id as usize] = None;
+        Ok(())
+    }
+
+    /// Wait until the reply of the request in channel `chan_id` has been received.
+    pub fn wait_reply<E>(&self, chan_id: u8) -> WaitReply<'_, E> {
+        WaitReply {
+            shared: &self.shared,
+            chan_id,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Access the RPC Client, for example to take a reply.  In place replies aren't supported,
+    /// since the reader task reuses its buffer for the next reply.
+    pub fn with_client<T, F: FnOnce(&mut RpcClient) -> T>(&self, f: F) -> T {
+        f(&mut self.shared.lock().unwrap().client)
+    }
+}
+
+/// Read replies from `reader` and wake the requests waiting for them.  A rejected reply is
+/// skipped and fails only the request waiting in its channel, if any.
+async fn read_replies<R: AsyncRead + Unpin>(
+    mut reader: R,
+    shared: Arc<Mutex<Shared>>,
+    buf_len: usize,
+) -> core::result::Result<(), RpcClientIOError> {
+    let mut buf = vec![0; buf_len + CRC_LEN];
+    let result = loop {
+        if let Err(err) = reader.read_exact(&mut buf[..REP_HEADER_LEN]).await {
+            break Err(err.into());
+        }
+        let header = rep_header_from_bytes(&buf[..REP_HEADER_LEN]).ok();
+        let parsed = {
+            let mut shared = shared.lock().unwrap();
+            let parsed = shared.client.parse(&buf[..REP_HEADER_LEN]);
+            parsed.map(|(n, chan_id)| (n, chan_id, shared.client.is_wait_body()))
+        };
+        let parsed = match parsed {
+            // The reply has a body
+            Ok((n, _, true)) => match reader.read_exact(&mut buf[..n]).await {
+                Ok(()) => shared.lock().unwrap().client.parse(&buf[..n]),
+                Err(err) => break Err(err.into()),
+            },
+            Ok((n, chan_id, false)) => Ok((n, chan_id)),
+            Err(err) => {
+                // Skip the rest of the rejected reply
+                let mut rest_len = header.as_ref().map_or(0, |header| {
+                    let crc_len = if header.is_crc() { CRC_LEN } else { 0 };
+                    header.body_len() + header.buf_len() + crc_len
+                });
+                while rest_len != 0 {
+                    let n = rest_len.min(buf.len());
+                    if let Err(err) = reader.read_exact(&mut buf[..n]).await {
+                        return close(&shared, Err(err.into()));
+                    }
+                    rest_len -= n;
+                }
+                Err(err)
+            }
+        };
+        let mut shared = shared.lock().unwrap();
+        match parsed {
+            Ok((_, Some(chan_id))) => shared.wake(chan_id),
+            Ok((_, None)) => {}
+            Err(err) => {
+                shared.client.reset();
+                if let Some(header) = header {
+                    shared.fail(header.chan_id(), err);
+                }
+            }
+        }
+    };
+    close(&shared, result)
+}
+
+/// Mark the client as closed, waking all the waiting requests, and return `result`.
+fn close(
+    shared: &Mutex<Shared>,
+    result: core::result::Result<(), RpcClientIOError>,
+) -> core::result::Result<(), RpcClientIOError> {
+    let mut shared = shared.lock().unwrap();
+    shared.closed = true;
+    shared.wake_all();
+    result
+}
+
+/// Future that resolves once the reply of a request has been received.
+pub struct WaitReply<'a, E> {
+    shared: &'a Mutex<Shared>,
+    chan_id: u8,
+    phantom: PhantomData<fn() -> E>,
+}
+
+impl<'a, E> Future for WaitReply<'a, E> {
+    type Output = core::result::Result<(), RpcClientIOError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.client.has_reply(self.chan_id) {
+            Poll::Ready(Ok(()))
+        } else if let Some(err) = shared.errors[self.chan_id as usize].take() {
+            Poll::Ready(Err(err.into()))
+        } else if shared.closed {
+            Poll::Ready(Err(RpcClientIOError::Io(io::ErrorKind::BrokenPipe.into())))
+        } else {
+            shared.wakers[self.chan_id as usize] = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Channel of a request sent by `call_async`.  The request is cancelled if the call is dropped
+/// before it's done, because it failed or its future was dropped.
+struct Call<'a> {
+    shared: &'a Mutex<Shared>,
+    chan_id: u8,
+    done: bool,
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.cancel_later(self.chan_id);
+        }
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufNo, P, OptBufNo, E>
+{
+    /// Send the request through an asynchronous RPC Client and wait for its reply.
+    pub async fn call_async<W: AsyncWrite + Unpin>(
+        mut self,
+        rpc_client: &RpcClientAsync<W>,
+    ) -> core::result::Result<P, RpcClientIOError<E>> {
+        let mut call = rpc_client
+            .send_call::<E, _>(|client, buf| Ok((self.request(client, buf)?, self.chan_id())))
+            .await?;
+        rpc_client.wait_reply::<E>(call.chan_id).await?;
+        let reply = rpc_client.with_client(|client| self.take_reply(client));
+        call.done = reply.is_some();
+        Ok(reply.ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufYes, P, OptBufNo, E>
+{
+    /// Send the request with the optional buffer `req_buf` through an asynchronous RPC Client
+    /// and wait for its reply.
+    pub async fn call_async<W: AsyncWrite + Unpin>(
+        mut self,
+        req_buf: &[u8],
+        rpc_client: &RpcClientAsync<W>,
+    ) -> core::result::Result<P, RpcClientIOError<E>> {
+        let mut call = rpc_client
+            .send_call::<E, _>(|client, buf| {
+                Ok((self.request(req_buf, client, buf)?, self.chan_id()))
+            })
+            .await?;
+        rpc_client.wait_reply::<E>(call.chan_id).await?;
+        let reply = rpc_client.with_client(|client| self.take_reply(client));
+        call.done = reply.is_some();
+        Ok(reply.ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufNo, P, OptBufYes, E>
+{
+    /// Send the request through an asynchronous RPC Client and wait for its reply, which
+    /// contains an optional buffer.
+    pub async fn call_async<W: AsyncWrite + Unpin>(
+        mut self,
+        rpc_client: &RpcClientAsync<W>,
+    ) -> core::result::Result<(P, Vec<u8>), RpcClientIOError<E>> {
+        let mut call = rpc_client
+            .send_call::<E, _>(|client, buf| Ok((self.request(client, buf)?, self.chan_id())))
+            .await?;
+        rpc_client.wait_reply::<E>(call.chan_id).await?;
+        let reply = rpc_client.with_client(|client| {
+            let reply = self.take_reply(client);
+            reply.map(|reply| reply.map(|(r, buf)| (r, buf.to_vec())))
+        });
+        call.done = reply.is_some();
+        Ok(reply.ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    RequestType<M, Q, OptBufYes, P, OptBufYes, E>
+{
+    /// Send the request with the optional buffer `req_buf` through an asynchronous RPC Client
+    /// and wait for its reply, which contains an optional buffer.
+    pub async fn call_async<W: AsyncWrite + Unpin>(
+        mut self,
+        req_buf: &[u8],
+        rpc_client: &RpcClientAsync<W>,
+    ) -> core::result::Result<(P, Vec<u8>), RpcClientIOError<E>> {
+        let mut call = rpc_client
+            .send_call::<E, _>(|client, buf| {
+                Ok((self.request(req_buf, client, buf)?, self.chan_id()))
+            })
+            .await?;
+        rpc_client.wait_reply::<E>(call.chan_id).await?;
+        let reply = rpc_client.with_client(|client| {
+            let reply = self.take_reply(client);
+            reply.map(|reply| reply.map(|(r, buf)| (r, buf.to_vec())))
+        });
+        call.done = reply.is_some();
+        Ok(reply.ok_or(Error::ReceivedBufTooShort)??)
+    }
+}
+
+/// Stream of the items received for a stream request, ending once the server ends the stream.
+/// Dropping it before the end cancels the stream.
+fn stream_items<'a, W, M, Q, QB, P, E>(
+    stream_req: StreamType<M, Q, QB, P, OptBufNo, E>,
+    call: Call<'a>,
+    rpc_client: &'a RpcClientAsync<W>,
+) -> impl Stream<Item = core::result::Result<P, RpcClientIOError<E>>> + 'a
+where
+    W: AsyncWrite + Unpin,
+    M: MethodId + 'a,
+    Q: Serialize + 'a,
+    QB: OptBuf + 'a,
+    P: DeserializeOwned + 'a,
+    E: DeserializeOwned + 'a,
+{
+    stream::unfold(Some((stream_req, call)), move |state| async move {
+        let (mut stream_req, mut call) = state?;
+        if let Err(err) = rpc_client.wait_reply::<E>(call.chan_id).await {
+            return Some((Err(err), None));
+        }
+        let item = rpc_client.with_client(|client| stream_req.take_item(client));
+        call.done = stream_req.is_done();
+        let state = if call.done {
+            None
+        } else {
+            Some((stream_req, call))
+        };
+        Some((item?.map_err(|e| e.into()), state))
+    })
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    StreamType<M, Q, OptBufNo, P, OptBufNo, E>
+{
+    /// Open the stream through an asynchronous RPC Client and return a stream of its items.
+    pub async fn call_async<'a, W: AsyncWrite + Unpin>(
+        mut self,
+        rpc_client: &'a RpcClientAsync<W>,
+    ) -> core::result::Result<
+        impl Stream<Item = core::result::Result<P, RpcClientIOError<E>>> + 'a,
+        RpcClientIOError<E>,
+    >
+    where
+        M: 'a,
+        Q: 'a,
+        P: 'a,
+        E: 'a,
+    {
+        let call = rpc_client
+            .send_call::<E, _>(|client, buf| Ok((self.request(client, buf)?, self.chan_id())))
+            .await?;
+        Ok(stream_items(self, call, rpc_client))
+    }
+}
+
+impl<M: MethodId, Q: Serialize, P: DeserializeOwned, E: DeserializeOwned>
+    StreamType<M, Q, OptBufYes, P, OptBufNo, E>
+{
+    /// Open the stream with the optional buffer `req_buf` through an asynchronous RPC Client and
+    /// return a stream of its items.
+    pub async fn call_async<'a, W: AsyncWrite + Unpin>(
+        mut self,
+        req_buf: &[u8],
+        rpc_client: &'a RpcClientAsync<W>,
+    ) -> core::result::Result<
+        impl Stream<Item = core::result::Result<P, RpcClientIOError<E>>> + 'a,
+        RpcClientIOError<E>,
+    >
+    where
+        M: 'a,
+        Q: 'a,
+        P: 'a,
+        E: 'a,
+    {
+        let call = rpc_client
+            .send_call::<E, _>(|client, buf| {
+                Ok((self.request(req_buf, client, buf)?, self.chan_id()))
+            })
+            .await?;
+        Ok(stream_items(self, call, rpc_client))
+    }
+}
@@ -3,3 +3,25 @@ pub const REQ_HEADER_LEN: usize = 7;
 
 /// Size in bytes of the reply packet header
 pub const REP_HEADER_LEN: usize = 6;
+
+/// Size in bytes of the CRC trailer of packets with the `crc` option
+pub const CRC_LEN: usize = 2;
+
+/// Version of the uRPC protocol, replied by the built-in get info method
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// First method id of the range reserved for built-in methods, which ends at 0xff.  Built-in
+/// methods are replied by the server runtime before dispatching the request to the handler.
+pub const BUILTIN_METHOD_ID_START: u8 = 0xf8;
+
+/// Method id of the built-in method that replies nothing, to check that the server is alive
+pub const METHOD_PING: u8 = 0xf8;
+
+/// Method id of the built-in method that replies the server info
+pub const METHOD_GET_INFO: u8 = 0xf9;
+
+/// Method id of the built-in method that replies the maximum buffer length of the server
+pub const METHOD_MAX_BUF_LEN: u8 = 0xfa;
+
+/// Method id of the built-in method that replies the schema hash of the server service
+pub const METHOD_SCHEMA_HASH: u8 = 0xff;
@@ -0,0 +1,281 @@
+//!
+//! Packets are sent back to back over a byte stream, so a reader that loses a single byte (for
+//! example because of line noise on a UART) misreads every header that follows.  This module
+//! wraps each packet in a [COBS] frame terminated by a zero byte.  A reader can always resync at
+//! the next zero byte, dropping only the corrupted frame.
+//!
+//! Frames are encoded with [`Encoder`](crate::framing::Encoder) (or
+//! [`encode`](crate::framing::encode)) and decoded with [`Decoder`](crate::framing::Decoder) (or
+//! [`decode`](crate::framing::decode)).  Decoded frames hold exactly one packet, which is parsed
+//! with [`RpcServer::parse_frame`](../server/struct.RpcServer.html#method.parse_frame) and
+//! `RpcClient::parse_frame`.  Both discard any partially parsed packet first.
+//!
+//! [COBS]: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+//!
+//! # Examples
+//!
+//! ```
+//! use urpc::{client, framing, server::{self, Request}, server_requests};
+//!
+//! mod cli {
+//!     use urpc::client_requests;
+//!
+//!     client_requests! {
+//!         client_requests;
+//!         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+//!     }
+//! }
+//!
+//! server_requests! {
+//!     ServerRequest;
+//!     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+//!     (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+//! }
+//!
+//! let mut rpc_client = client::RpcClient::new(32);
+//! let mut rpc_server = server::RpcServer::new(32);
+//! let mut buf = vec![0; 32];
+//! let mut line = Vec::new();
+//!
+//! // A frame cut in half, followed by a frame holding noise
+//! line.extend_from_slice(&[0x05, 0x13, 0x00, 0x02, 0x13, 0x00]);
+//! let mut req = cli::Ping::new([0, 1, 2, 3]);
+//! let n = req.request(&mut rpc_client, &mut buf).unwrap();
+//! let mut frame_buf = vec![0; framing::max_encoded_len(n)];
+//! let frame_len = framing::encode(&buf[..n], &mut frame_buf).unwrap();
+//! line.extend_from_slice(&frame_buf[..frame_len]);
+//!
+//! let mut recv_buf = vec![0; 32];
+//! let mut decoder = framing::Decoder::new(&mut recv_buf);
+//! let mut pings = 0;
+//! let mut pos = 0;
+//! while pos < line.len() {
+//!     let (n, frame) = decoder.push_slice(&line[pos..]);
+//!     pos += n;
+//!     match frame {
+//!         Some(Ok(_)) => match ServerRequest::from_rpc_frame(&mut rpc_server, decoder.frame()) {
+//!             Ok(server::ParseResult::Request(ServerRequest::Ping(ping))) => {
+//!                 assert_eq!(ping.body, [0, 1, 2, 3]);
+//!                 pings += 1;
+//!             }
+//!             // The noise frame is too short to hold a request
+//!             Err(server::Error::Deserialize(_)) => {}
+//!             r => panic!("unexpected parse result: {:?}", r.map(|_| ())),
+//!         },
+//!         Some(Err(err)) => assert_eq!(err, framing::Error::InvalidFrame),
+//!         None => {}
+//!     }
+//! }
+//! assert_eq!(pings, 1);
+//! ```
+
+/// Error while encoding or decoding a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too short to hold the frame.
+    BufTooShort,
+    /// The frame ended in the middle of a COBS block.
+    InvalidFrame,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Byte that delimits frames.
+pub const DELIMITER: u8 = 0x00;
+
+/// Maximum length of a COBS block, including its code byte.
+const MAX_BLOCK_LEN: usize = 0xff;
+
+/// Maximum length of the frame that encodes `len` bytes, including the delimiter.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / (MAX_BLOCK_LEN - 1) + 2
+}
+
+/// Encode `src` into a frame written in `dst`.  Returns the number of bytes written to `dst`.
+pub fn encode(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut encoder = Encoder::new(dst);
+    encoder.push_slice(src)?;
+    encoder.finish()
+}
+
+/// Decode a frame from `src` into `dst`.  The delimiter at the end of the frame is optional.
+/// Returns the number of bytes written to `dst`.
+pub fn decode(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut decoder = Decoder::new(dst);
+    for &byte in src {
+        if let Some(result) = decoder.push(byte) {
+            return result;
+        }
+    }
+    decoder.push(DELIMITER).unwrap_or(Ok(0))
+}
+
+/// Streaming frame encoder that writes into a buffer.  The packet can be pushed in several
+/// chunks, for example the header and the body.
+pub struct Encoder<'a> {
+    dst: &'a mut [u8],
+    code_idx: usize,
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(dst: &'a mut [u8]) -> Self {
+        Self {
+            dst,
+            code_idx: 0,
+            pos: 1,
+        }
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        *self.dst.get_mut(self.pos).ok_or(Error::BufTooShort)? = byte;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn close_block(&mut self) -> Result<()> {
+        *self.dst.get_mut(self.code_idx).ok_or(Error::BufTooShort)? =
+            (self.pos - self.code_idx) as u8;
+        self.code_idx = self.pos;
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Encode one byte of the packet.
+    pub fn push(&mut self, byte: u8) -> Result<()> {
+        if byte == DELIMITER {
+            return self.close_block();
+        }
+        self.write(byte)?;
+        if self.pos - self.code_idx == MAX_BLOCK_LEN {
+            self.close_block()?;
+        }
+        Ok(())
+    }
+
+    /// Encode a chunk of the packet.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> Result<()> {
+        bytes.iter().try_for_each(|&byte| self.push(byte))
+    }
+
+    /// Terminate the frame.  Returns the number of bytes written to the buffer.
+    pub fn finish(mut self) -> Result<usize> {
+        self.close_block()?;
+        self.pos -= 1;
+        self.write(DELIMITER)?;
+        Ok(self.pos)
+    }
+}
+
+enum DecoderState {
+    /// Waiting for the code byte of a block.  Holds the code of the previous block.
+    Code(Option<u8>),
+    /// Reading a block.  Holds the block code and the number of data bytes left.
+    Block(u8, u8),
+    /// The frame is being dropped until the next delimiter.
+    Skip(Error),
+    /// A frame has been decoded and is available in the buffer.
+    Done,
+}
+
+/// Streaming frame decoder that writes into a buffer.  Bytes are pushed as they are received,
+/// and a decoded frame is available through [`Decoder::frame`] until the next byte is pushed.
+/// Corrupted frames are reported and dropped, and decoding resumes with the next frame.
+pub struct Decoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    state: DecoderState,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            state: DecoderState::Code(None),
+        }
+    }
+
+    /// Drop any partially decoded frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.state = DecoderState::Code(None);
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        *self.buf.get_mut(self.len).ok_or(Error::BufTooShort)? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn push_data(&mut self, byte: u8) -> Result<()> {
+        match self.state {
+            DecoderState::Code(prev_code) => {
+                // A block shorter than the maximum ends with an implicit zero, unless it's the
+                // last block of the frame.
+                if matches!(prev_code, Some(code) if code as usize != MAX_BLOCK_LEN) {
+                    self.write(0)?;
+                }
+                self.state = match byte - 1 {
+                    0 => DecoderState::Code(Some(byte)),
+                    left => DecoderState::Block(byte, left),
+                };
+            }
+            DecoderState::Block(code, left) => {
+                self.write(byte)?;
+                self.state = match left - 1 {
+                    0 => DecoderState::Code(Some(code)),
+                    left => DecoderState::Block(code, left),
+                };
+            }
+            DecoderState::Skip(_) | DecoderState::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Decode one byte.  Returns the length of the decoded frame when `byte` is a delimiter that
+    /// ends a non empty frame, or the error that made the frame be dropped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<usize>> {
+        if let DecoderState::Done = self.state {
+            self.reset();
+        }
+        if byte != DELIMITER {
+            if let Err(err) = self.push_data(byte) {
+                self.state = DecoderState::Skip(err);
+            }
+            return None;
+        }
+        let result = match self.state {
+            // Empty frames are ignored
+            DecoderState::Code(None) => return None,
+            DecoderState::Code(Some(_)) => Ok(self.len),
+            DecoderState::Block(..) => Err(Error::InvalidFrame),
+            DecoderState::Skip(err) => Err(err),
+            DecoderState::Done => unreachable!(),
+        };
+        match result {
+            Ok(_) => self.state = DecoderState::Done,
+            Err(_) => self.reset(),
+        }
+        Some(result)
+    }
+
+    /// Decode bytes until the end of a frame.  Returns the number of bytes consumed, and the
+    /// result of [`Decoder::push`] for the delimiter if one was found.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> (usize, Option<Result<usize>>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(result) = self.push(byte) {
+                return (i + 1, Some(result));
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    /// Last decoded frame, empty if no frame has been decoded since the last pushed byte.
+    pub fn frame(&self) -> &[u8] {
+        match self.state {
+            DecoderState::Done => &self.buf[..self.len],
+            _ => &[],
+        }
+    }
+}
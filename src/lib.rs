@@ -1,12 +1,15 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
 //! uRPC (pronounced micro RPC) is a simple and lightweight RPC framework designed with embedded
-//! systems in mind.  The server side works in a heapless environment with `no_std` and is
-//! supposed to use very low resources.  The current client side implementation requires `std`.
+//! systems in mind.  The server and the client sides work in a heapless environment with
+//! `no_std` and are supposed to use very low resources.  The blocking and asynchronous clients
+//! over I/O streams require `std`.
 //!
 //! # Features
 //!
-//! - ✓ Support for 256 different methods.
+//! - ✓ Support for 248 user methods; the method ids 0xf8..=0xff
+//!   ([`BUILTIN_METHOD_ID_START`](consts/constant.BUILTIN_METHOD_ID_START.html)) are reserved
+//!   for the built-in methods.
 //! - ✓ Low level primitives for the server side regarding request parsing and
 //!   reply serializing.
 //! - ✓ Single argument of any `sedre::Serialize + serde::DeserializeOwned` type.
@@ -14,13 +17,23 @@
 //!     - This feature is designed to optimize the transfer of bytes between client
 //!       and server minimizing the amount of used memory in the server.
 //! - ✓ Single return value of any `sedre::Serialize + serde::DeserializeOwned` type.
-//! - ✓ Optional byte buffer for the reply ~~that doesn't involve any buffer copy~~.
+//! - ✓ Optional byte buffer for the reply that doesn't involve any buffer copy
+//!   (client side with the in place reply mode).
 //!     - This feature is designed to optimize the transfer of bytes between client
-//!       and server ~~minimizing the amount of used memory in the client~~.
-//! - ✗ Methods can return custom errors.
-//! - ✗ Asyncrhonous methods.
-//!     - ✗ Support for holding 255 async uncompleted requests.
-//! - ✗ Stream methods.
+//!       and server minimizing the amount of used memory in the client.
+//! - ✓ Methods can return custom errors.
+//! - ✓ Asynchronous methods (client side with the `async` feature).
+//!     - ✓ Support for holding 255 async uncompleted requests.
+//! - ✓ Stream methods.
+//! - ✓ Optional self-synchronizing COBS framing for byte streams.
+//! - ✓ Push style parsers for bytes received in chunks of any length.
+//! - ✓ Timeouts that drop partial packets in the blocking runtimes over streams.
+//! - ✓ Optional CRC-16 integrity check of packets.
+//! - ✓ Services defined on traits (with the `macros` feature).
+//! - ✓ Static service schema, whose hash the client can check against the server.
+//! - ✓ Built-in methods for liveness ping, server info and maximum buffer length.
+//! - ✓ Serial port transports over `embedded-io` and `embedded-hal-nb` (with the features of
+//!   the same name).
 //!
 //! # Packet format
 //!
@@ -37,16 +50,19 @@
 //! -------|-----
 //! 8b | method index
 //! 8b | channel id
-//! 8b | options
+//! 8b | options (see [`Opts`])
 //! 16b | body length (little endian)
 //! 16b | optional buffer length (little endian)
 //!
+//! Packets with the `crc` option end with a 16b CRC-16/IBM-3740 trailer (little endian) of
+//! the whole packet.
+//!
 //! ## Reply
 //!
 //! length | desc
 //! -------|-----
 //! 8b | channel id
-//! 8b | options
+//! 8b | options (see [`Opts`])
 //! 16b | body length (little endian)
 //! 16b | optional buffer length (little endian)
 //!
@@ -54,40 +70,166 @@
 //!
 //! The best way to use this library is by using the macros `server_requets` and `client_requests`.
 //! You can see complete examples in the documentation: [server_requests](macro.server_requests.html),
-//! [client_requests](macro.client_requests.html).
+//! [client_requests](macro.client_requests.html).  When the client and the server are built from
+//! the same crate, the [service_requests](macro.service_requests.html) macro defines both from a
+//! single method table, and is also available as [`rules::service`].  With the `macros` feature, the [service](attr.service.html) attribute
+//! defines them from a trait instead, whose methods can carry doc comments.
 
 #[macro_use]
 mod macros;
 
-#[cfg(feature = "std")]
+#[cfg(feature = "macros")]
+/// Define a service from a trait
+pub use urpc_macros::service;
+
+/// The `service!` macro, which can't be exported at the crate root next to the `service`
+/// attribute, since both share the macro namespace.  Import it to write
+/// `service! { Foo; ... }`.
+pub mod rules {
+    /// Build the client and server types of a service from a single method table, like
+    /// [`service_requests`](../macro.service_requests.html).
+    pub use crate::service_requests as service;
+}
+
 /// Client side implementation
 pub mod client;
 
+#[cfg(feature = "async")]
+/// Asynchronous client side implementation
+pub mod client_async;
+
 /// Constant parameters
 pub mod consts;
 
+/// Self-synchronizing framing of packets over byte streams
+pub mod framing;
+
+/// Static description of the methods of a service
+pub mod schema;
+
+#[cfg(any(feature = "embedded-io", feature = "embedded-hal-nb"))]
+/// Serial port transports
+pub mod serial;
+
 /// Server side implementation
 pub mod server;
 
+#[cfg(feature = "std")]
+/// Timeouts of the blocking runtimes over streams
+pub mod timeout;
+
+use core::convert::TryFrom;
+
+use consts::CRC_LEN;
+use crc::{Crc, Digest, CRC_16_IBM_3740};
 use postcard::from_bytes;
 use serde::{Deserialize, Serialize};
 
-// Auto
-// enum Error {
-//     InvalidMethod,
-//     InvalidBody,
-//     Busy,
-// }
+/// Protocol level error sent by the server in reply to a request it couldn't handle.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// The request method index doesn't match any method.
+    UnknownMethod,
+    /// The request body couldn't be deserialized or has an unexpected optional buffer.
+    InvalidBody,
+    /// The request body or optional buffer doesn't fit in the server buffer.
+    TooLong,
+    /// The server can't handle the request right now.
+    Busy,
+    /// The request failed the CRC check and should be sent again.
+    Resend,
+}
+
+/// Information about the server, replied by the built-in
+/// [`METHOD_GET_INFO`](consts/constant.METHOD_GET_INFO.html) method.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ServerInfo {
+    /// Protocol version of the server, see
+    /// [`PROTOCOL_VERSION`](consts/constant.PROTOCOL_VERSION.html).
+    pub version: u8,
+    /// Number of methods of the server service, not counting the built-in ones.
+    pub methods: u8,
+}
 
 // pub type Result<T> = postcard::Result<T>;
 // pub type Error = postcard::Error;
 
-/// Options of a request/reply packet
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Options of a request/reply packet, encoded as a bitfield in the header options byte.
+///
+/// bit | flag
+/// ----|-----
+/// 0 | `err`: the reply body contains the method error.
+/// 1 | `err_code`: the reply body contains an [`ErrorCode`].
+/// 2 | `once`: no more replies follow in the channel.
+/// 3 | `cancel`: the request cancels the one in the same channel.
+/// 4 | `crc`: the packet ends with a CRC-16 trailer.  The server sets it in the replies to
+///   requests that set it.
+/// 5 | `sync`: the request is synchronous.  Carried in the header but not interpreted by the
+///   client or the server.
+/// 6-7 | reserved, must be 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Opts {
     pub sync: bool,
+    pub err: bool,
+    pub err_code: bool,
     pub once: bool,
     pub cancel: bool,
+    pub crc: bool,
+}
+
+const OPTS_ERR: u8 = 0x01;
+const OPTS_ERR_CODE: u8 = 0x02;
+const OPTS_ONCE: u8 = 0x04;
+const OPTS_CANCEL: u8 = 0x08;
+const OPTS_CRC: u8 = 0x10;
+const OPTS_SYNC: u8 = 0x20;
+const OPTS_RESERVED: u8 =
+    !(OPTS_ERR | OPTS_ERR_CODE | OPTS_ONCE | OPTS_CANCEL | OPTS_CRC | OPTS_SYNC);
+
+/// Error returned when decoding an options byte with reserved bits set.  Holds the options byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InvalidOpts(pub u8);
+
+impl Opts {
+    /// Decode an options byte ignoring the reserved bits.
+    pub fn from_bits_truncate(bits: u8) -> Self {
+        Self {
+            sync: bits & OPTS_SYNC != 0,
+            err: bits & OPTS_ERR != 0,
+            err_code: bits & OPTS_ERR_CODE != 0,
+            once: bits & OPTS_ONCE != 0,
+            cancel: bits & OPTS_CANCEL != 0,
+            crc: bits & OPTS_CRC != 0,
+        }
+    }
+
+    /// Encode the options into a byte.
+    pub fn bits(&self) -> u8 {
+        let flag = |set: bool, bit: u8| if set { bit } else { 0 };
+        flag(self.err, OPTS_ERR)
+            | flag(self.err_code, OPTS_ERR_CODE)
+            | flag(self.once, OPTS_ONCE)
+            | flag(self.cancel, OPTS_CANCEL)
+            | flag(self.crc, OPTS_CRC)
+            | flag(self.sync, OPTS_SYNC)
+    }
+}
+
+impl TryFrom<u8> for Opts {
+    type Error = InvalidOpts;
+
+    fn try_from(bits: u8) -> Result<Self, InvalidOpts> {
+        if bits & OPTS_RESERVED != 0 {
+            return Err(InvalidOpts(bits));
+        }
+        Ok(Self::from_bits_truncate(bits))
+    }
+}
+
+impl From<Opts> for u8 {
+    fn from(opts: Opts) -> u8 {
+        opts.bits()
+    }
 }
 
 /// Header of a request packet
@@ -101,6 +243,30 @@ pub struct RequestHeader {
 }
 
 impl RequestHeader {
+    fn new(method_idx: u8) -> Self {
+        Self {
+            method_idx,
+            chan_id: 0,
+            opts: 0,
+            body_len: 0,
+            buf_len: 0,
+        }
+    }
+    pub fn chan_id(&self) -> u8 {
+        self.chan_id
+    }
+    /// Options of the request.  Headers with reserved bits set are rejected when parsed.
+    pub fn opts(&self) -> Opts {
+        Opts::from_bits_truncate(self.opts)
+    }
+    /// Returns true if the request cancels the one in the same channel.
+    pub fn is_cancel(&self) -> bool {
+        self.opts().cancel
+    }
+    /// Returns true if the request ends with a CRC trailer.
+    pub fn is_crc(&self) -> bool {
+        self.opts().crc
+    }
     pub fn body_len(&self) -> usize {
         self.body_len as usize
     }
@@ -119,6 +285,29 @@ pub struct ReplyHeader {
 }
 
 impl ReplyHeader {
+    pub fn chan_id(&self) -> u8 {
+        self.chan_id
+    }
+    /// Options of the reply.  Headers with reserved bits set are rejected when parsed.
+    pub fn opts(&self) -> Opts {
+        Opts::from_bits_truncate(self.opts)
+    }
+    /// Returns true if the reply body contains an error instead of the method result.
+    pub fn is_err(&self) -> bool {
+        self.opts().err
+    }
+    /// Returns true if the reply body contains an [`ErrorCode`] instead of the method result.
+    pub fn is_err_code(&self) -> bool {
+        self.opts().err_code
+    }
+    /// Returns true if this is the last reply in the channel.
+    pub fn is_once(&self) -> bool {
+        self.opts().once
+    }
+    /// Returns true if the reply ends with a CRC trailer.
+    pub fn is_crc(&self) -> bool {
+        self.opts().crc
+    }
     pub fn body_len(&self) -> usize {
         self.body_len as usize
     }
@@ -141,6 +330,125 @@ fn rep_header_from_bytes(buf: &[u8]) -> Result<ReplyHeader, postcard::Error> {
     from_bytes(buf)
 }
 
+/// CRC of the trailer of packets with the `crc` option.
+static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+/// Start the CRC of a packet with its header bytes.
+fn crc_digest(header_buf: &[u8]) -> Digest<'static, u16> {
+    let mut digest = CRC16.digest();
+    digest.update(header_buf);
+    digest
+}
+
+/// Append the CRC trailer of the packet in `buf[..len]`.  Returns the packet length with the
+/// trailer.
+fn append_crc(buf: &mut [u8], len: usize) -> Result<usize, postcard::Error> {
+    let crc = CRC16.checksum(&buf[..len]);
+    buf.get_mut(len..len + CRC_LEN)
+        .ok_or(postcard::Error::SerializeBufferFull)?
+        .copy_from_slice(&crc.to_le_bytes());
+    Ok(len + CRC_LEN)
+}
+
+/// Split the CRC trailer from the end of `buf`, which holds the packet after the header, and
+/// check it.  Returns `buf` without the trailer, or None if the CRC doesn't match.
+fn split_crc<'a>(mut digest: Digest<'static, u16>, buf: &'a [u8]) -> Option<&'a [u8]> {
+    if buf.len() < CRC_LEN {
+        return None;
+    }
+    let (body, trailer) = buf.split_at(buf.len() - CRC_LEN);
+    digest.update(body);
+    if digest.finalize().to_le_bytes() != trailer {
+        return None;
+    }
+    Some(body)
+}
+
+/// Buffer of a packet received in chunks of any length, used by the push style parsers of the
+/// server and the client.  The packet header is buffered first, then the rest of the packet
+/// once its length is known.
+struct PacketBuf<'a> {
+    buf: &'a mut [u8],
+    header_len: usize,
+    /// Number of buffered bytes of the packet.
+    len: usize,
+    /// Number of bytes of the packet to buffer before parsing them.
+    need: usize,
+    /// Number of bytes left of a rejected packet, which are dropped.
+    skip: usize,
+    /// Whether the buffered packet is complete.
+    done: bool,
+}
+
+impl<'a> PacketBuf<'a> {
+    fn new(buf: &'a mut [u8], header_len: usize) -> Self {
+        Self {
+            buf,
+            header_len,
+            len: 0,
+            need: header_len,
+            skip: 0,
+            done: false,
+        }
+    }
+
+    /// Drop the buffered bytes and wait for the header of the next packet.
+    fn reset(&mut self) {
+        self.len = 0;
+        self.need = self.header_len;
+        self.skip = 0;
+        self.done = false;
+    }
+
+    /// Returns true if the buffered bytes are the packet header.
+    fn is_header(&self) -> bool {
+        self.len == self.header_len
+    }
+
+    /// Consume the bytes left of a rejected packet, and buffer the needed bytes of the packet
+    /// being received.  A complete packet is dropped first.  Returns the number of bytes
+    /// consumed, and true if all the needed bytes are buffered.
+    fn fill(&mut self, bytes: &[u8]) -> (usize, bool) {
+        if self.done {
+            self.reset();
+        }
+        let skip = self.skip.min(bytes.len());
+        self.skip -= skip;
+        let n = (self.need - self.len).min(bytes.len() - skip);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[skip..skip + n]);
+        self.len += n;
+        (skip + n, self.skip == 0 && self.len == self.need)
+    }
+
+    /// Need `n` more bytes of the packet.  Returns false if they don't fit in the buffer.
+    fn expect(&mut self, n: usize) -> bool {
+        if self.len + n > self.buf.len() {
+            return false;
+        }
+        self.need = self.len + n;
+        true
+    }
+
+    /// Drop the packet being received, along with its `skip` bytes left.
+    fn reject(&mut self, skip: usize) {
+        self.reset();
+        self.skip = skip;
+    }
+
+    /// Maximum length of the rest of a packet after its header.
+    fn max_len(&self) -> usize {
+        self.buf.len().saturating_sub(self.header_len)
+    }
+
+    /// Complete packet, empty if the packet being received isn't complete.
+    fn packet(&self) -> &[u8] {
+        match self.done {
+            true => &self.buf[..self.len],
+            false => &[],
+        }
+    }
+}
+
 /// Trait used to allow building RPC calls with optional buffer.
 pub trait OptBuf {
     fn opt_buf() -> bool;
@@ -27,10 +27,37 @@ macro_rules! client_request_export {
 
 /// Macro that builds the required types to make calls via RPC from the client.
 ///
+/// Each method can optionally name a custom error type as a fifth parameter, as in
+/// `(2, read, Read(u8, OptBufNo, u32, OptBufNo, ReadError))`.  The `take_reply` of its
+/// `RequestType` then returns `MethodError::Method` when the server replies with an error.
+///
+/// A method followed by `stream`, as in `(3, samples, Samples(u8, OptBufNo, u16, OptBufNo)
+/// stream)`, is a stream method: its type is a `client::StreamType` whose request opens a channel
+/// in which the server replies items until it ends the stream.
+///
+/// The macro also defines a `SCHEMA` constant with the [`schema::Schema`] of the requests,
+/// which can be checked against the server with `client::RpcClientIO::check_schema`.  The
+/// lifetimes of the type names don't take part in the schema hash, so a request body that the
+/// server borrows as `&'a str` is written `&'static str` on the client.
+///
+/// [`schema::Schema`]: schema/struct.Schema.html
+///
+/// Method ids must fit in a `u8`, be unique and differ from the built-in method ids, otherwise
+/// the macro fails to compile with an error that names the offending methods:
+///
+/// ```compile_fail
+/// use urpc::client_requests;
+///
+/// client_requests! {
+///     client_requests;
+///     (256, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+/// }
+/// ```
+///
 /// # Examples
 ///
 /// ```
-/// use urpc::{client_requests, client, consts, OptBufNo, OptBufYes};
+/// use urpc::{client_requests, client, consts};
 ///
 /// mod cli {
 ///     use urpc::client_requests;
@@ -82,7 +109,7 @@ macro_rules! client_request_export {
 /// // Read from the network into recv_buf
 /// // [...]
 /// // We fill recv_buf with some precalculated replies to simulate a server reply
-/// recv_buf[..10].copy_from_slice(&[0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03]);
+/// recv_buf[..10].copy_from_slice(&[0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03]);
 ///
 /// // Parse read bytes with rpc_client and try to match replies from each request
 /// let mut pos = 0;
@@ -100,8 +127,25 @@ macro_rules! client_request_export {
 #[macro_export(local_inner_macros)]
 macro_rules! client_requests {
     ($request_mod:ident;
-        $( ($id:expr, $_fn:expr, $method:ident ( $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident)) ),*) => {
-            use urpc::{OptBufNo, OptBufYes};
+        $( ($id:expr, $_fn:expr, $method:ident ( $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident $(, $err_type:ty)?) $($kind:ident)?) ),*) => {
+            check_method_ids! { $($method = $id),* }
+
+            /// Schema of the requests.
+            #[allow(dead_code)]
+            pub const SCHEMA: $crate::schema::Schema = $crate::schema::Schema::new(&[
+                $(
+                    method_schema!(
+                        $id,
+                        $method,
+                        [$($kind)?],
+                        $req_type,
+                        $req_opt_buf,
+                        $rep_type,
+                        $rep_opt_buf
+                        $(, $err_type)?
+                    )
+                ),*
+            ]);
 
             mod methodid {
                 $(
@@ -112,141 +156,439 @@ macro_rules! client_requests {
                 )*
             }
             $(
-                    // client_request_export!($request_mod, $method, $req_opt_buf, $rep_opt_buf);
-                    pub type $method = $crate::client::RequestType<
-                    methodid::$method,
-                    $req_type,
-                    $req_opt_buf,
-                    $rep_type,
-                    $rep_opt_buf,
-                    >;
+                    client_requests_alias!(
+                        $method,
+                        [$($kind)?],
+                        $req_type,
+                        $crate::$req_opt_buf,
+                        $rep_type,
+                        $crate::$rep_opt_buf,
+                        method_error_type!($($err_type)?)
+                    );
             )*
     };
 }
 
-#[macro_export(local_inner_macros)]
+#[doc(hidden)]
+#[macro_export]
 macro_rules! rpc_client_io_fn {
-    ($fn:ident, $method:ident ( $req_type:ty, OptBufNo, $rep_type:ty, OptBufNo)) => {
+    ($fn:ident, $method:ident, [], $req_type:ty, OptBufNo, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        pub fn $fn(
+            &mut self,
+            arg: $req_type,
+        ) -> $crate::client::RpcClientIOResult<$rep_type, $err_type> {
+            $method::new(arg).call_io(&mut self.rpc)
+        }
+    };
+    ($fn:ident, $method:ident, [], $req_type:ty, OptBufYes, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        pub fn $fn(
+            &mut self,
+            arg: $req_type,
+            req_buf: &[u8],
+        ) -> $crate::client::RpcClientIOResult<$rep_type, $err_type> {
+            $method::new(arg).call_io(req_buf, &mut self.rpc)
+        }
+    };
+    ($fn:ident, $method:ident, [], $req_type:ty, OptBufNo, $rep_type:ty, OptBufYes, $err_type:ty) => {
         pub fn $fn(
             &mut self,
             arg: $req_type,
-        ) -> Result<$rep_type, $crate::client::RpcClientIOError> {
-            let mut req = $method::new(arg);
-            let write_len = req.request(
-                &mut self.rpc.client,
-                std::vec![0; self.rpc.buf_len],
-                &mut self.rpc.stream_buf,
-            )?;
-            self.rpc.request(req.chan_id(), write_len)?;
-            let (r, _) = req.take_reply(&mut self.rpc.client).unwrap()?;
-            Ok(r)
+        ) -> $crate::client::RpcClientIOResult<($rep_type, std::vec::Vec<u8>), $err_type> {
+            $method::new(arg).call_io(&mut self.rpc)
         }
     };
-    ($fn:ident, $method:ident ( $req_type:ty, OptBufYes, $rep_type:ty, OptBufNo)) => {
+    ($fn:ident, $method:ident, [], $req_type:ty, OptBufYes, $rep_type:ty, OptBufYes, $err_type:ty) => {
         pub fn $fn(
             &mut self,
             arg: $req_type,
             req_buf: &[u8],
-        ) -> Result<$rep_type, $crate::client::RpcClientIOError> {
-            let mut req = $method::new(arg);
-            let write_len = req.request(
-                req_buf,
-                &mut self.rpc.client,
-                std::vec![0; self.rpc.buf_len],
-                &mut self.rpc.stream_buf,
-            )?;
-            self.rpc.request(req.chan_id(), write_len)?;
-            let (r, _) = req.take_reply(&mut self.rpc.client).unwrap()?;
-            Ok(r)
+        ) -> $crate::client::RpcClientIOResult<($rep_type, std::vec::Vec<u8>), $err_type> {
+            $method::new(arg).call_io(req_buf, &mut self.rpc)
         }
     };
-    ($fn:ident, $method:ident ( $req_type:ty, OptBufNo, $rep_type:ty, OptBufYes)) => {
+    ($fn:ident, $method:ident, [stream], $req_type:ty, OptBufNo, $rep_type:ty, OptBufNo, $err_type:ty) => {
         pub fn $fn(
             &mut self,
             arg: $req_type,
-        ) -> Result<($rep_type, Vec<u8>), $crate::client::RpcClientIOError> {
-            let mut req = $method::new(arg);
-            let write_len = req.request(
-                &mut self.rpc.client,
-                std::vec![0; self.rpc.buf_len],
-                std::vec![0; self.rpc.buf_len],
-                &mut self.rpc.stream_buf,
-            )?;
-            self.rpc.request(req.chan_id(), write_len)?;
-            let (r, buf, _) = req.take_reply(&mut self.rpc.client).unwrap()?;
-            Ok((r, buf))
+        ) -> $crate::client::RpcClientIOResult<
+            impl Iterator<Item = $crate::client::RpcClientIOResult<$rep_type, $err_type>> + '_,
+            $err_type,
+        > {
+            $method::new(arg).call_io(&mut self.rpc)
         }
     };
-    ($fn:ident, $method:ident ( $req_type:ty, OptBufYes, $rep_type:ty, OptBufYes)) => {
+    ($fn:ident, $method:ident, [stream], $req_type:ty, OptBufYes, $rep_type:ty, OptBufNo, $err_type:ty) => {
         pub fn $fn(
             &mut self,
             arg: $req_type,
             req_buf: &[u8],
-        ) -> Result<($rep_type, Vec<u8>), $crate::client::RpcClientIOError> {
-            let mut req = $method::new(arg);
-            let write_len = req.request(
-                req_buf,
-                &mut self.rpc.client,
-                std::vec![0; self.rpc.buf_len],
-                std::vec![0; self.rpc.buf_len],
-                &mut self.rpc.stream_buf,
-            )?;
-            self.rpc.request(req.chan_id(), write_len)?;
-            let (r, buf, _) = req.take_reply(&mut self.rpc.client).unwrap()?;
-            Ok((r, buf))
+        ) -> $crate::client::RpcClientIOResult<
+            impl Iterator<Item = $crate::client::RpcClientIOResult<$rep_type, $err_type>> + '_,
+            $err_type,
+        > {
+            $method::new(arg).call_io(req_buf, &mut self.rpc)
         }
     };
 }
 
+/// Macro that builds a typed blocking client over a [`client::RpcClientIO`], with one method per
+/// RPC call.  It takes the same method table as [`client_requests`], whose types it also
+/// defines.
+///
+/// Methods that take an optional buffer get it as a second argument, and methods whose reply
+/// contains an optional buffer return it next to the reply.  Stream methods return an iterator
+/// over the stream items; those with an optional buffer in the reply aren't supported.
+///
+/// The client is built with `new`, or with `connect`, which also checks that the server replies
+/// the same schema hash as the `SCHEMA` of the method table.  Its reply timeouts are set with
+/// `set_timeouts`.
+///
+/// [`client::RpcClientIO`]: client/struct.RpcClientIO.html
+///
+/// Examples
+///
+/// ```no_run
+/// use urpc::rpc_client_io;
+/// use std::net::TcpStream;
+///
+/// rpc_client_io! {
+///     Client;
+///     client_requests;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo)),
+///     (2, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
+/// }
+///
+/// let stream = TcpStream::connect("127.0.0.1:8080").unwrap();
+/// let mut client = Client::connect(stream, 32).unwrap();
+/// println!("ping: {:?}", client.ping([0, 1, 2, 3]).unwrap());
+/// client.send_bytes((), &[1, 2, 3]).unwrap();
+/// for item in client.samples(4).unwrap() {
+///     println!("sample: {}", item.unwrap());
+/// }
+/// ```
 #[macro_export(local_inner_macros)]
 macro_rules! rpc_client_io {
     ($client:ident;
      $request_mod:ident;
-        $( ($id:expr, $fn:ident, $method:ident ( $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident)) ),*) => {
-        use std::io::{self, Read, Write};
-
+        $( ($id:expr, $fn:ident, $method:ident ( $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident $(, $err_type:ty)?) $($kind:ident)?) ),*) => {
         client_requests! {
             $request_mod;
             $(
-                ($id, $fn, $method ( $req_type, $req_opt_buf, $rep_type, $rep_opt_buf))
+                ($id, $fn, $method ( $req_type, $req_opt_buf, $rep_type, $rep_opt_buf $(, $err_type)?) $($kind)?)
             ),*
         }
 
-        pub struct $client<S: io::Read + io::Write> {
+        pub struct $client<S: std::io::Read + std::io::Write> {
             rpc: $crate::client::RpcClientIO<S>,
         }
 
-        impl<S: Read + Write> $client<S> {
+        impl<S: std::io::Read + std::io::Write> $client<S> {
             pub fn new(stream: S, buf_len: usize) -> Self {
                 Self {
                     rpc: $crate::client::RpcClientIO::new(stream, buf_len),
                 }
             }
+
+            /// Build the client and check that the server speaks the same service.
+            pub fn connect(
+                stream: S,
+                buf_len: usize,
+            ) -> $crate::client::RpcClientIOResult<Self> {
+                Ok(Self {
+                    rpc: $crate::client::RpcClientIO::connect(stream, buf_len, &SCHEMA)?,
+                })
+            }
+
+            /// Set the timeouts to receive a reply.  See `RpcClientIO::set_timeouts`.
+            pub fn set_timeouts(&mut self, timeouts: $crate::timeout::Timeouts) {
+                self.rpc.set_timeouts(timeouts)
+            }
+            $(
+                rpc_client_io_fn!(
+                    $fn,
+                    $method,
+                    [$($kind)?],
+                    $req_type,
+                    $req_opt_buf,
+                    $rep_type,
+                    $rep_opt_buf,
+                    method_error_type!($($err_type)?)
+                );
+            )*
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! check_method_ids {
+    () => {};
+    ($method:ident = $id:expr $(, $rest_method:ident = $rest_id:expr)*) => {
+        const _: () = {
+            ::core::assert!(
+                ($id) as u64 <= 0xff,
+                ::core::concat!(
+                    "method `", ::core::stringify!($method), "` has id ", ::core::stringify!($id),
+                    ", which doesn't fit in a u8"
+                )
+            );
+            ::core::assert!(
+                (($id) as u64) < $crate::consts::BUILTIN_METHOD_ID_START as u64,
+                ::core::concat!(
+                    "method `", ::core::stringify!($method), "` has id ", ::core::stringify!($id),
+                    ", which is reserved for the built-in methods"
+                )
+            );
             $(
-                rpc_client_io_fn!($fn, $method ( $req_type, $req_opt_buf, $rep_type, $rep_opt_buf));
-                // pub fn $fn(&mut self, arg: $req_type) -> Result<$rep_type, $crate::client::RpcClientIOError> {
-                //     let mut req = $method::new(arg);
-                //     let write_len = req.request(
-                //         &mut self.rpc.client,
-                //         self.rpc.body_buf.take().unwrap(),
-                //         &mut self.rpc.stream_buf,
-                //     )?;
-                //     self.rpc.request(req.chan_id(), write_len)?;
-                //     let (r, body_buf) = req.take_reply(&mut self.rpc.client).unwrap()?;
-                //     self.rpc.body_buf = Some(body_buf);
-                //     Ok(r)
-                // }
+                ::core::assert!(
+                    ($id) as u64 != ($rest_id) as u64,
+                    ::core::concat!(
+                        "methods `", ::core::stringify!($method), "` and `",
+                        ::core::stringify!($rest_method), "` have the same id ",
+                        ::core::stringify!($id)
+                    )
+                );
             )*
+        };
+        $crate::check_method_ids! { $($rest_method = $rest_id),* }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! opt_buf_flag {
+    (OptBufNo) => {
+        false
+    };
+    (OptBufYes) => {
+        true
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! method_schema {
+    ($id:expr, $method:ident, [$($kind:ident)?], $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident) => {
+        $crate::method_schema!($id, $method, [$($kind)?], $req_type, $req_opt_buf, $rep_type, $rep_opt_buf, ())
+    };
+    ($id:expr, $method:ident, [$($kind:ident)?], $req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident, $err_type:ty) => {
+        $crate::schema::MethodSchema {
+            id: $id,
+            name: ::core::stringify!($method),
+            req_type: ::core::stringify!($req_type),
+            req_opt_buf: $crate::opt_buf_flag!($req_opt_buf),
+            rep_type: ::core::stringify!($rep_type),
+            rep_opt_buf: $crate::opt_buf_flag!($rep_opt_buf),
+            err_type: ::core::stringify!($err_type),
+            stream: $crate::method_schema!(@stream $($kind)?),
         }
     };
+    (@stream) => {
+        false
+    };
+    (@stream stream) => {
+        true
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! method_error_type {
+    () => {
+        ()
+    };
+    ($err_type:ty) => {
+        $err_type
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! client_requests_alias {
+    ($method:ident, [], $($param:ty),*) => {
+        pub type $method = $crate::client::RequestType<methodid::$method, $($param),*>;
+    };
+    ($method:ident, [stream], $($param:ty),*) => {
+        pub type $method = $crate::client::StreamType<methodid::$method, $($param),*>;
+    };
+}
+
+#[doc(hidden)]
+#[macro_export(local_inner_macros)]
+macro_rules! server_requests_variant {
+    ([], $req_type:ty, OptBufNo, $rep_type:ty, $rep_opt_buf:ident, $err_type:ty) => {
+        $crate::server::RequestType<$req_type, $crate::OptBufNo, $rep_type, $crate::$rep_opt_buf, $err_type>
+    };
+    ([], $req_type:ty, OptBufYes, $rep_type:ty, $rep_opt_buf:ident, $err_type:ty) => {
+        ($crate::server::RequestType<$req_type, $crate::OptBufYes, $rep_type, $crate::$rep_opt_buf, $err_type>, &'a [u8])
+    };
+    ([stream], $req_type:ty, OptBufNo, $rep_type:ty, $rep_opt_buf:ident, $err_type:ty) => {
+        $crate::server::StreamType<$req_type, $crate::OptBufNo, $rep_type, $crate::$rep_opt_buf, $err_type>
+    };
+    ([stream], $req_type:ty, OptBufYes, $rep_type:ty, $rep_opt_buf:ident, $err_type:ty) => {
+        ($crate::server::StreamType<$req_type, $crate::OptBufYes, $rep_type, $crate::$rep_opt_buf, $err_type>, &'a [u8])
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! server_requests_from_bytes {
+    ([], $req_opt_buf:ident, $header:expr, $buf:expr) => {
+        $crate::server::RequestType::<_, $crate::$req_opt_buf, _, _, _>::from_bytes($header, $buf)
+    };
+    ([stream], $req_opt_buf:ident, $header:expr, $buf:expr) => {
+        $crate::server::StreamType::<_, $crate::$req_opt_buf, _, _, _>::from_bytes($header, $buf)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! server_requests_handler_fn {
+    ($fn:ident, [], $req_type:ty, OptBufNo, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        fn $fn<'a>(&mut self, body: $req_type) -> core::result::Result<$rep_type, $err_type>;
+    };
+    ($fn:ident, [], $req_type:ty, OptBufYes, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            buf: &[u8],
+        ) -> core::result::Result<$rep_type, $err_type>;
+    };
+    ($fn:ident, [], $req_type:ty, OptBufNo, $rep_type:ty, OptBufYes, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            out: &mut [u8],
+        ) -> core::result::Result<($rep_type, usize), $err_type>;
+    };
+    ($fn:ident, [], $req_type:ty, OptBufYes, $rep_type:ty, OptBufYes, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            buf: &[u8],
+            out: &mut [u8],
+        ) -> core::result::Result<($rep_type, usize), $err_type>;
+    };
+    ($fn:ident, [stream], $req_type:ty, OptBufNo, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            items: &mut $crate::server::Items<$rep_type>,
+        ) -> core::result::Result<(), $err_type>;
+    };
+    ($fn:ident, [stream], $req_type:ty, OptBufYes, $rep_type:ty, OptBufNo, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            buf: &[u8],
+            items: &mut $crate::server::Items<$rep_type>,
+        ) -> core::result::Result<(), $err_type>;
+    };
+    ($fn:ident, [stream], $req_type:ty, OptBufNo, $rep_type:ty, OptBufYes, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            items: &mut $crate::server::Items<$rep_type, $crate::OptBufYes>,
+        ) -> core::result::Result<(), $err_type>;
+    };
+    ($fn:ident, [stream], $req_type:ty, OptBufYes, $rep_type:ty, OptBufYes, $err_type:ty) => {
+        fn $fn<'a>(
+            &mut self,
+            body: $req_type,
+            buf: &[u8],
+            items: &mut $crate::server::Items<$rep_type, $crate::OptBufYes>,
+        ) -> core::result::Result<(), $err_type>;
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! server_requests_handle {
+    ($handler:ident.$fn:ident, [], OptBufNo, OptBufNo, $req:ident, $writer:ident) => {
+        $req.handle(|body| $handler.$fn(body), $writer)
+    };
+    ($handler:ident.$fn:ident, [], OptBufYes, OptBufNo, $req:ident, $writer:ident) => {{
+        let (req, buf) = $req;
+        req.handle(|body| $handler.$fn(body, buf), $writer)
+    }};
+    ($handler:ident.$fn:ident, [], OptBufNo, OptBufYes, $req:ident, $writer:ident) => {
+        $req.handle(|body, out| $handler.$fn(body, out), $writer)
+    };
+    ($handler:ident.$fn:ident, [], OptBufYes, OptBufYes, $req:ident, $writer:ident) => {{
+        let (req, buf) = $req;
+        req.handle(|body, out| $handler.$fn(body, buf, out), $writer)
+    }};
+    ($handler:ident.$fn:ident, [stream], OptBufNo, $rep_opt_buf:ident, $req:ident, $writer:ident) => {
+        $req.handle(|body, items| $handler.$fn(body, items), $writer)
+    };
+    ($handler:ident.$fn:ident, [stream], OptBufYes, $rep_opt_buf:ident, $req:ident, $writer:ident) => {{
+        let (req, buf) = $req;
+        req.handle(|body, items| $handler.$fn(body, buf, items), $writer)
+    }};
 }
 
 /// Macro that builds the required types to handle calls via RPC from the server.
 ///
+/// Each method can optionally name a custom error type as a fifth parameter, as in
+/// `(2, read, Read(u8, OptBufNo, u32, OptBufNo, ReadError))`, which is serialized into the reply
+/// with `reply_err`.
+///
+/// A method followed by `stream`, as in `(3, samples, Samples(u8, OptBufNo, u16, OptBufNo)
+/// stream)`, is a stream method: its request is a `server::StreamType` that can reply any number
+/// of items with `reply_item` before ending the stream with `end`.
+///
+/// The macro also builds a `Handler` trait with one method per RPC call, named after the
+/// method function name.  Each handler method gets the request body, followed by the request
+/// optional buffer if the method has one.  Methods with an optional buffer in the reply get the
+/// buffer to write it into, and return its length next to the reply.  Stream methods get a
+/// `server::Items` to send the stream items, each with its optional buffer if the reply has
+/// one.  The requests enum implements `server::Dispatch`
+/// for the `Handler` implementors, so that a `server::RpcServerIO` can serve the requests with
+/// a handler.  The built-in method that replies the schema hash of the requests, available in
+/// the `SCHEMA` associated constant of the enum, is replied by `server::dispatch` and
+/// `server::RpcServerIO` before dispatching the request to the handler.
+///
+/// Method ids must fit in a `u8`, be unique and differ from the built-in method ids, otherwise
+/// the macro fails to compile with an error that names the offending methods:
+///
+/// ```compile_fail
+/// use urpc::server_requests;
+///
+/// server_requests! {
+///     ServerRequest;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (0, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+/// }
+/// ```
+///
+/// Request bodies can borrow from the receive buffer with the `'a` lifetime of the requests
+/// enum, so that strings and byte slices are deserialized without copying them:
+///
+/// ```
+/// use urpc::{server_requests, server::{self, Request}, consts};
+///
+/// server_requests! {
+///     ServerRequest;
+///     (0, log, Log(&'a str, OptBufNo, (), OptBufNo))
+/// }
+///
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let recv_buf = [0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o'];
+/// match ServerRequest::from_rpc(&mut rpc_server, &recv_buf[..consts::REQ_HEADER_LEN]).unwrap() {
+///     server::ParseResult::NeedBytes(n) => assert_eq!(n, 6),
+///     r => panic!("unexpected result: {:?}", r),
+/// }
+/// match ServerRequest::from_rpc(&mut rpc_server, &recv_buf[consts::REQ_HEADER_LEN..]).unwrap() {
+///     server::ParseResult::Request(ServerRequest::Log(log)) => assert_eq!(log.body, "hello"),
+///     r => panic!("unexpected result: {:?}", r),
+/// }
+/// ```
+///
 /// Examples
 ///
 /// ```
-/// use urpc::{server_requests, server::{self, Request}, consts, OptBufNo, OptBufYes};
+/// use urpc::{server_requests, server::{self, Request}, consts};
 ///
 /// server_requests! {
 ///     ServerRequest;
@@ -271,6 +613,9 @@ macro_rules! rpc_client_io {
 ///         server::ParseResult::NeedBytes(n) => {
 ///             read_len = n;
 ///         }
+///         server::ParseResult::Cancel(chan_id) => {
+///             println!("request in channel {} cancelled", chan_id);
+///         }
 ///         server::ParseResult::Request(req) => {
 ///             read_len = consts::REQ_HEADER_LEN;
 ///             match req {
@@ -299,40 +644,194 @@ macro_rules! rpc_client_io {
 ///     }
 /// }
 /// ```
-
-#[macro_export(local_inner_macros)]
-macro_rules! server_requests_variant {
-    ($req_type:ty, OptBufNo, $rep_type:ty, $rep_opt_buf:ident) => {
-        $crate::server::RequestType<$req_type, OptBufNo, $rep_type, $rep_opt_buf>
-    };
-    ($req_type:ty, OptBufYes, $rep_type:ty, $rep_opt_buf:ident) => {
-        ($crate::server::RequestType<$req_type, OptBufYes, $rep_type, $rep_opt_buf>, &'a [u8])
-    };
-}
-
 #[macro_export(local_inner_macros)]
 macro_rules! server_requests {
     ($request_enum:ident;
-     $( ($id: expr, $_fn:ident, $method:ident ($req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident)) ),*) => {
+     $( ($id: expr, $fn:ident, $method:ident ($req_type:ty, $req_opt_buf:ident, $rep_type:ty, $rep_opt_buf:ident $(, $err_type:ty)?) $($kind:ident)?) ),*) => {
+        check_method_ids! { $($method = $id),* }
+
         #[derive(Debug)]
         enum $request_enum<'a> {
             $(
-                $method(server_requests_variant!($req_type, $req_opt_buf, $rep_type, $rep_opt_buf)),
+                $method(server_requests_variant!([$($kind)?], $req_type, $req_opt_buf, $rep_type, $rep_opt_buf, method_error_type!($($err_type)?))),
             )*
+            /// Uses the lifetime when no request borrows from the receive buffer.  It can't be
+            /// built, so matches don't need an arm for it.
+            #[doc(hidden)]
+            #[allow(dead_code)]
+            __Lifetime(::core::convert::Infallible, ::core::marker::PhantomData<&'a ()>),
+        }
+
+        impl<'a> $request_enum<'a> {
+            /// Schema of the requests.
+            #[allow(dead_code)]
+            pub const SCHEMA: $crate::schema::Schema = $crate::schema::Schema::new(&[
+                $(
+                    method_schema!(
+                        $id,
+                        $method,
+                        [$($kind)?],
+                        $req_type,
+                        $req_opt_buf,
+                        $rep_type,
+                        $rep_opt_buf
+                        $(, $err_type)?
+                    )
+                ),*
+            ]);
         }
 
-        impl<'a> server::Request<'a> for $request_enum<'a> {
+        impl<'a> $crate::server::Request<'a> for $request_enum<'a> {
             fn from_bytes(header: $crate::RequestHeader, buf: &'a [u8]) -> $crate::server::Result<Self> {
                 Ok(match header.method_idx {
                     $(
                         $id => $request_enum::$method(
-                            $crate::server::RequestType::<_, $req_opt_buf, _, _>::from_bytes(header, buf)?),
+                            server_requests_from_bytes!([$($kind)?], $req_opt_buf, header, buf)?),
                     )*
                     _ => {
-                        return Err($crate::server::Error::WontImplement);
+                        return Err($crate::server::Error::UnknownMethod(header.method_idx));
                     }
                 })
             }
         }
+
+        /// Handler of the requests, with one method per RPC call.
+        #[allow(dead_code)]
+        trait Handler {
+            $(
+                server_requests_handler_fn!(
+                    $fn,
+                    [$($kind)?],
+                    $req_type,
+                    $req_opt_buf,
+                    $rep_type,
+                    $rep_opt_buf,
+                    method_error_type!($($err_type)?)
+                );
+            )*
+        }
+
+        impl<'a, H: Handler + ?Sized> $crate::server::Dispatch<H> for $request_enum<'a> {
+            const SCHEMA: $crate::schema::Schema = Self::SCHEMA;
+
+            fn dispatch(
+                handler: &mut H,
+                header: $crate::RequestHeader,
+                buf: &[u8],
+                writer: &mut dyn $crate::server::ReplyWrite,
+            ) -> $crate::server::Result<()> {
+                match <$request_enum as $crate::server::Request>::from_bytes(header, buf)? {
+                    $(
+                        $request_enum::$method(req) => server_requests_handle!(
+                            handler.$fn,
+                            [$($kind)?],
+                            $req_opt_buf,
+                            $rep_opt_buf,
+                            req,
+                            writer
+                        ),
+                    )*
+                }
+            }
+        }
     }
 }
+
+/// Macro that builds both the client and the server types of a service from a single method
+/// table, so that both ends always agree on the method ids and types.  It takes the same method
+/// table as [`server_requests`], and expands to [`client_requests`] and [`server_requests`],
+/// using the service name as the requests enum name.
+///
+/// Examples
+///
+/// ```
+/// use urpc::{client, consts, server, service_requests};
+///
+/// service_requests! {
+///     Service;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (1, send_bytes, SendBytes((), OptBufYes, u32, OptBufNo))
+/// }
+///
+/// struct Server;
+///
+/// impl Handler for Server {
+///     fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+///         Ok(body)
+///     }
+///
+///     fn send_bytes(&mut self, _body: (), buf: &[u8]) -> Result<u32, ()> {
+///         Ok(buf.len() as u32)
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let mut req_buf = vec![0; 32];
+/// let mut rep_buf = vec![0; 32];
+///
+/// let mut req = Ping::new([0, 1, 2, 3]);
+/// let n = req.request(&mut rpc_client, &mut req_buf).unwrap();
+///
+/// // The server parses the request header first, and then the body
+/// let (header, body) = req_buf[..n].split_at(consts::REQ_HEADER_LEN);
+/// for buf in &[header, body] {
+///     match server::dispatch::<Service, _>(&mut rpc_server, &mut Server, buf, &mut rep_buf) {
+///         Ok(server::ParseResult::NeedBytes(_)) => {}
+///         Ok(server::ParseResult::Request(_)) => {}
+///         r => panic!("unexpected parse result: {:?}", r),
+///     }
+/// }
+///
+/// // The client parses the reply header first, and then the body
+/// let n = rpc_client.parse(&rep_buf[..consts::REP_HEADER_LEN]).unwrap().0;
+/// rpc_client
+///     .parse(&rep_buf[consts::REP_HEADER_LEN..consts::REP_HEADER_LEN + n])
+///     .unwrap();
+/// assert_eq!(req.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+/// ```
+///
+/// The macro is also exported as `urpc::rules::service`, and the methods don't need to borrow
+/// from the receive buffer:
+///
+/// ```
+/// use urpc::{client, consts, rules::service, server};
+///
+/// service! {
+///     Foo;
+///     (0, ping, Ping([u8;4], OptBufNo, [u8;4], OptBufNo))
+/// }
+///
+/// struct Server;
+///
+/// impl Handler for Server {
+///     fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+///         Ok(body)
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let mut req_buf = vec![0; 32];
+/// let mut rep_buf = vec![0; 32];
+///
+/// let mut req = Ping::new([4, 5, 6, 7]);
+/// let n = req.request(&mut rpc_client, &mut req_buf).unwrap();
+/// let (header, body) = req_buf[..n].split_at(consts::REQ_HEADER_LEN);
+/// server::dispatch::<Foo, _>(&mut rpc_server, &mut Server, header, &mut rep_buf).unwrap();
+/// let rep_len = match server::dispatch::<Foo, _>(&mut rpc_server, &mut Server, body, &mut rep_buf) {
+///     Ok(server::ParseResult::Request(n)) => n,
+///     r => panic!("unexpected parse result: {:?}", r),
+/// };
+///
+/// rpc_client.parse(&rep_buf[..consts::REP_HEADER_LEN]).unwrap();
+/// rpc_client.parse(&rep_buf[consts::REP_HEADER_LEN..rep_len]).unwrap();
+/// assert_eq!(req.take_reply(&mut rpc_client).unwrap().unwrap(), [4, 5, 6, 7]);
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! service_requests {
+    ($service:ident; $($methods:tt)*) => {
+        client_requests! { $service; $($methods)* }
+        server_requests! { $service; $($methods)* }
+    };
+}
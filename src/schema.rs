@@ -0,0 +1,84 @@
+//! Static description of the methods of a service, built by the service definition macros.  The
+//! schema hash identifies the wire format of a service, and is replied by the server runtime
+//! through the built-in method [`METHOD_SCHEMA_HASH`](../consts/constant.METHOD_SCHEMA_HASH.html),
+//! so that a client can check that it speaks the same service as the server.
+
+/// Description of a method.  The type names are the ones written in the method table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MethodSchema {
+    pub id: u8,
+    pub name: &'static str,
+    pub req_type: &'static str,
+    pub req_opt_buf: bool,
+    pub rep_type: &'static str,
+    pub rep_opt_buf: bool,
+    /// Method error type name, `()` for methods without a custom error.
+    pub err_type: &'static str,
+    pub stream: bool,
+}
+
+/// Description of the methods of a service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schema {
+    pub methods: &'static [MethodSchema],
+    /// Hash of the method ids, buffer flags and type names, see [`Schema::new`].
+    pub hash: u32,
+}
+
+impl Schema {
+    /// Build the schema of `methods` and compute its hash.  The hash doesn't depend on the order
+    /// of the methods, their names, or the whitespace and lifetimes in the type names, so the
+    /// client and the server can be defined with different macros, and a server body `&'a str`
+    /// matches a client body `&'static str`.
+    pub const fn new(methods: &'static [MethodSchema]) -> Self {
+        let mut hash: u32 = 0;
+        let mut i = 0;
+        while i < methods.len() {
+            hash = hash.wrapping_add(methods[i].hash());
+            i += 1;
+        }
+        Self { methods, hash }
+    }
+}
+
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+/// Byte that separates the type names in the hash, which never appears in a `str`.
+const SEPARATOR: u8 = 0xff;
+
+const fn fnv1a(hash: u32, byte: u8) -> u32 {
+    (hash ^ byte as u32).wrapping_mul(FNV_PRIME)
+}
+
+/// Hash `name` without its whitespace and lifetimes, followed by a separator.
+const fn fnv1a_name(mut hash: u32, name: &str) -> u32 {
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\'' {
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            continue;
+        }
+        if !bytes[i].is_ascii_whitespace() {
+            hash = fnv1a(hash, bytes[i]);
+        }
+        i += 1;
+    }
+    fnv1a(hash, SEPARATOR)
+}
+
+impl MethodSchema {
+    /// FNV-1a hash of the method id, buffer flags and type names.
+    pub const fn hash(&self) -> u32 {
+        let flags =
+            self.req_opt_buf as u8 | (self.rep_opt_buf as u8) << 1 | (self.stream as u8) << 2;
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, self.id);
+        hash = fnv1a(hash, flags);
+        hash = fnv1a_name(hash, self.req_type);
+        hash = fnv1a_name(hash, self.rep_type);
+        fnv1a_name(hash, self.err_type)
+    }
+}
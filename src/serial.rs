@@ -0,0 +1,454 @@
+//! Runtimes that serve and send requests over a serial port, for firmware that plugs a UART
+//! straight into uRPC.  They work without `std` nor heap, on buffers provided by the caller.
+//!
+//! The serial port is any [`Serial`](crate::serial::Serial) implementor, such as:
+//!
+//! - [`IoSerial`](crate::serial::IoSerial), over the blocking `embedded_io::Read` and
+//!   `embedded_io::Write` traits (with the `embedded-io` feature).
+//! - [`NbSerial`](crate::serial::NbSerial), over the nonblocking `embedded_hal_nb::serial::Read`
+//!   and `embedded_hal_nb::serial::Write` traits (with the `embedded-hal-nb` feature).  Its
+//!   runtimes can also be polled, reading the bytes as they arrive without blocking.
+//!
+//! # Examples
+//!
+//! ```
+//! use urpc::{client, serial::{Serial, SerialClient, SerialServer}, server_requests};
+//! use std::collections::VecDeque;
+//!
+//! mod cli {
+//!     use urpc::client_requests;
+//!
+//!     client_requests! {
+//!         client_requests;
+//!         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+//!     }
+//! }
+//!
+//! server_requests! {
+//!     ServerRequests;
+//!     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+//!     (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+//! }
+//!
+//! struct Server;
+//!
+//! impl Handler for Server {
+//!     fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+//!         Ok(body)
+//!     }
+//!     fn send_bytes(&mut self, _body: (), _buf: &[u8]) -> Result<(), ()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! // Serial port whose written bytes are read back, to loop the client to the server
+//! struct Loopback(VecDeque<u8>);
+//!
+//! impl Serial for Loopback {
+//!     type Error = ();
+//!
+//!     fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+//!         for b in buf.iter_mut() {
+//!             *b = self.0.pop_front().ok_or(())?;
+//!         }
+//!         Ok(())
+//!     }
+//!
+//!     fn write_all(&mut self, buf: &[u8]) -> Result<(), ()> {
+//!         self.0.extend(buf);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut send_buf = [0; 32];
+//! let mut storage = [0; 32];
+//! let mut rpc_client = SerialClient::new(
+//!     Loopback(VecDeque::new()),
+//!     client::RpcClient::with_storage(32, &mut storage[..]),
+//!     &mut send_buf,
+//! );
+//!
+//! let mut req = cli::Ping::new([0, 1, 2, 3]);
+//! let n = req.request(&mut rpc_client.client, rpc_client.buf).unwrap();
+//! rpc_client.send(n).unwrap();
+//!
+//! // Serve the request from the other end of the line
+//! let (mut rcv_buf, mut reply_buf) = ([0; 32], [0; 32]);
+//! let mut rpc_server = SerialServer::new(
+//!     Loopback(rpc_client.serial.0.drain(..).collect()),
+//!     &mut rcv_buf,
+//!     &mut reply_buf,
+//! );
+//! rpc_server.serve::<ServerRequests, _>(&mut Server).unwrap();
+//! rpc_client.serial.0 = rpc_server.serial.0.drain(..).collect();
+//!
+//! rpc_client.wait_reply(req.chan_id()).unwrap();
+//! assert_eq!(req.take_reply(&mut rpc_client.client).unwrap().unwrap(), [0, 1, 2, 3]);
+//! ```
+
+use super::client::{self, ReplyStorage, RpcClient};
+use super::consts::*;
+use super::server::{self, serve_bytes, Dispatch, Next, ReplyWrite, RpcServer};
+
+#[cfg(feature = "embedded-hal-nb")]
+use embedded_hal_nb::{nb, serial};
+
+/// Blocking serial port.
+pub trait Serial {
+    type Error;
+
+    /// Read exactly `buf.len()` bytes.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write all the bytes of `buf` and flush them.
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+
+    /// Read and drop `len` bytes.
+    fn skip(&mut self, mut len: usize) -> Result<(), Self::Error> {
+        let mut buf = [0; 16];
+        while len != 0 {
+            let n = len.min(buf.len());
+            self.read_exact(&mut buf[..n])?;
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+/// [`Serial`] over the blocking `embedded_io::Read` and `embedded_io::Write` traits.
+#[cfg(feature = "embedded-io")]
+pub struct IoSerial<T>(pub T);
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Read + embedded_io::Write> Serial for IoSerial<T> {
+    type Error = embedded_io::ReadExactError<T::Error>;
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read_exact(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_all(buf)?;
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// [`Serial`] over the nonblocking `embedded_hal_nb::serial::Read` and
+/// `embedded_hal_nb::serial::Write` traits, which blocks until each byte is read or written.
+#[cfg(feature = "embedded-hal-nb")]
+pub struct NbSerial<T>(pub T);
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<T: serial::Read + serial::Write> Serial for NbSerial<T> {
+    type Error = T::Error;
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        for b in buf.iter_mut() {
+            *b = nb::block!(self.0.read())?;
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        for b in buf {
+            nb::block!(self.0.write(*b))?;
+        }
+        nb::block!(self.0.flush())
+    }
+}
+
+/// [`ReplyWrite`] that writes the replies to a serial port, keeping the error of the port.
+struct SerialReplyWrite<'a, P: Serial> {
+    serial: &'a mut P,
+    buf: &'a mut [u8],
+    err: Option<P::Error>,
+}
+
+impl<'a, P: Serial> ReplyWrite for SerialReplyWrite<'a, P> {
+    fn buf(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    fn send(&mut self, len: usize) -> server::Result<()> {
+        self.serial.write_all(&self.buf[..len]).map_err(|err| {
+            self.err = Some(err);
+            server::Error::Write
+        })
+    }
+}
+
+/// Error of an RPC Server serving over a serial port.
+#[derive(Debug)]
+pub enum SerialServerError<E> {
+    Serial(E),
+    Urpc(server::Error),
+}
+
+impl<E> From<server::Error> for SerialServerError<E> {
+    fn from(err: server::Error) -> Self {
+        Self::Urpc(err)
+    }
+}
+
+/// RPC Server over a serial port.  It reads the requests into the receive buffer, calls their
+/// handler methods and writes back the replies serialized in the reply buffer, replying with
+/// an [`ErrorCode`](../enum.ErrorCode.html) to the requests that can't be handled.
+pub struct SerialServer<'b, P: Serial> {
+    pub server: RpcServer,
+    pub serial: P,
+    rcv_buf: &'b mut [u8],
+    reply_buf: &'b mut [u8],
+    /// What to read next from the serial port, and how many bytes of it have been read, when
+    /// polled.
+    #[cfg(feature = "embedded-hal-nb")]
+    next: Next,
+    #[cfg(feature = "embedded-hal-nb")]
+    pos: usize,
+}
+
+impl<'b, P: Serial> SerialServer<'b, P> {
+    /// Create a new RPC Server over `serial`.  Requests longer than `rcv_buf` are rejected.
+    pub fn new(serial: P, rcv_buf: &'b mut [u8], reply_buf: &'b mut [u8]) -> Self {
+        Self {
+            server: RpcServer::new(rcv_buf.len().min(u16::MAX as usize) as u16),
+            serial,
+            rcv_buf,
+            reply_buf,
+            #[cfg(feature = "embedded-hal-nb")]
+            next: Next::Read(REQ_HEADER_LEN),
+            #[cfg(feature = "embedded-hal-nb")]
+            pos: 0,
+        }
+    }
+
+    /// Consume the RPC Server and return its serial port.
+    pub fn into_inner(self) -> P {
+        self.serial
+    }
+
+    /// Parse the first `len` bytes of the receive buffer and serve the request they complete.
+    fn serve_bytes<D: Dispatch<H>, H: ?Sized>(
+        &mut self,
+        handler: &mut H,
+        len: usize,
+    ) -> Result<Next, SerialServerError<P::Error>> {
+        let mut writer = SerialReplyWrite {
+            serial: &mut self.serial,
+            buf: &mut self.reply_buf[..],
+            err: None,
+        };
+        let result = serve_bytes::<D, H>(
+            &mut self.server,
+            handler,
+            &self.rcv_buf[..len],
+            self.rcv_buf.len(),
+            &mut writer,
+        );
+        if let Some(err) = writer.err {
+            return Err(SerialServerError::Serial(err));
+        }
+        Ok(result?)
+    }
+
+    /// Read one request from the serial port and serve it with `handler`, or reply it if it's a
+    /// built-in method (see [`server::dispatch`]).  `D` is the requests enum built with
+    /// [`server_requests`](../macro.server_requests.html).  Blocks until the whole request has
+    /// been read, so it must not be mixed with a polled request in progress.
+    pub fn serve<D: Dispatch<H>, H: ?Sized>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), SerialServerError<P::Error>> {
+        let mut read_len = REQ_HEADER_LEN;
+        loop {
+            let result = self.serial.read_exact(&mut self.rcv_buf[..read_len]);
+            if result.is_err() {
+                self.server.reset();
+            }
+            result.map_err(SerialServerError::Serial)?;
+            match self.serve_bytes::<D, H>(handler, read_len)? {
+                Next::Read(n) => read_len = n,
+                Next::Skip(n) => {
+                    return self.serial.skip(n).map_err(SerialServerError::Serial);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<'b, T: serial::Read + serial::Write> SerialServer<'b, NbSerial<T>> {
+    /// Read the bytes available in the serial port without blocking, and serve the request
+    /// they complete like [`SerialServer::serve`].  Returns `WouldBlock` until a request has
+    /// been served.  The replies are written blocking.
+    pub fn poll<D: Dispatch<H>, H: ?Sized>(
+        &mut self,
+        handler: &mut H,
+    ) -> nb::Result<(), SerialServerError<T::Error>> {
+        loop {
+            match self.next {
+                Next::Skip(0) => self.next = Next::Read(REQ_HEADER_LEN),
+                Next::Skip(n) => {
+                    read_nb(&mut self.serial.0, SerialServerError::Serial)?;
+                    self.next = Next::Skip(n - 1);
+                }
+                Next::Read(n) => {
+                    while self.pos < n {
+                        self.rcv_buf[self.pos] =
+                            read_nb(&mut self.serial.0, SerialServerError::Serial)?;
+                        self.pos += 1;
+                    }
+                    self.pos = 0;
+                    // Start over with the next request if serving fails
+                    self.next = Next::Read(REQ_HEADER_LEN);
+                    self.next = self.serve_bytes::<D, H>(handler, n)?;
+                    if let Next::Skip(_) = self.next {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read a byte from a nonblocking serial port, wrapping its error with `wrap`.
+#[cfg(feature = "embedded-hal-nb")]
+fn read_nb<T: serial::Read, E>(serial: &mut T, wrap: fn(T::Error) -> E) -> nb::Result<u8, E> {
+    serial.read().map_err(|err| err.map(wrap))
+}
+
+/// Error of an RPC Client over a serial port.
+#[derive(Debug)]
+pub enum SerialClientError<E> {
+    Serial(E),
+    Urpc(client::Error),
+}
+
+impl<E> From<client::Error> for SerialClientError<E> {
+    fn from(err: client::Error) -> Self {
+        Self::Urpc(err)
+    }
+}
+
+/// RPC Client over a serial port.  The requests are serialized into `buf` with the
+/// [`RpcClient`], sent with [`SerialClient::send`], and their replies are read into `buf` with
+/// [`SerialClient::wait_reply`] until they can be taken from the [`RpcClient`].
+pub struct SerialClient<'b, P: Serial, S: ReplyStorage> {
+    pub client: RpcClient<S>,
+    pub serial: P,
+    pub buf: &'b mut [u8],
+    /// How many bytes to read next from the serial port, how many of them have been read, and
+    /// how many bytes are left to skip of a reply that doesn't fit in `buf`, when polled.
+    #[cfg(feature = "embedded-hal-nb")]
+    read_len: usize,
+    #[cfg(feature = "embedded-hal-nb")]
+    pos: usize,
+    #[cfg(feature = "embedded-hal-nb")]
+    skip: usize,
+}
+
+impl<'b, P: Serial, S: ReplyStorage> SerialClient<'b, P, S> {
+    /// Create a new RPC Client over `serial`.  Replies longer than `buf` can't be read.
+    pub fn new(serial: P, client: RpcClient<S>, buf: &'b mut [u8]) -> Self {
+        Self {
+            client,
+            serial,
+            buf,
+            #[cfg(feature = "embedded-hal-nb")]
+            read_len: REP_HEADER_LEN,
+            #[cfg(feature = "embedded-hal-nb")]
+            pos: 0,
+            #[cfg(feature = "embedded-hal-nb")]
+            skip: 0,
+        }
+    }
+
+    /// Consume the RPC Client and return its serial port.
+    pub fn into_inner(self) -> P {
+        self.serial
+    }
+
+    /// Send the request serialized in `buf`.
+    pub fn send(&mut self, write_len: usize) -> Result<(), SerialClientError<P::Error>> {
+        self.serial
+            .write_all(&self.buf[..write_len])
+            .map_err(SerialClientError::Serial)
+    }
+
+    /// Send the request serialized in `buf` and wait for its reply.
+    pub fn request(
+        &mut self,
+        chan_id: u8,
+        write_len: usize,
+    ) -> Result<(), SerialClientError<P::Error>> {
+        self.send(write_len)?;
+        self.wait_reply(chan_id)
+    }
+
+    /// Cancel the request in the channel `chan_id`.  See [`RpcClient::cancel`].
+    pub fn cancel(&mut self, chan_id: u8) -> Result<(), SerialClientError<P::Error>> {
+        let write_len = self.client.cancel(chan_id, self.buf)?;
+        self.send(write_len)
+    }
+
+    /// Read replies until there's one for the channel `chan_id`.  Blocks until the whole reply
+    /// has been read, so it must not be mixed with a polled reply in progress.  A reply that
+    /// doesn't fit in `buf` is skipped, and fails with `ReplyBodyTooLong`.
+    pub fn wait_reply(&mut self, chan_id: u8) -> Result<(), SerialClientError<P::Error>> {
+        let mut read_len = REP_HEADER_LEN;
+        while !self.client.has_reply(chan_id) {
+            if read_len > self.buf.len() {
+                self.client.reset();
+                self.serial
+                    .skip(read_len)
+                    .map_err(SerialClientError::Serial)?;
+                return Err(client::Error::ReplyBodyTooLong.into());
+            }
+            let buf = &mut self.buf[..read_len];
+            if let Err(err) = self.serial.read_exact(buf) {
+                self.client.reset();
+                return Err(SerialClientError::Serial(err));
+            }
+            read_len = self.client.parse(buf)?.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<'b, T: serial::Read + serial::Write, S: ReplyStorage> SerialClient<'b, NbSerial<T>, S> {
+    /// Read the bytes available in the serial port without blocking, and parse the reply they
+    /// complete.  Returns `WouldBlock` until a reply can be taken, and then its channel id.  A
+    /// reply that doesn't fit in `buf` fails with `ReplyBodyTooLong`, and the following polls
+    /// skip the rest of it.
+    pub fn poll(&mut self) -> nb::Result<u8, SerialClientError<T::Error>> {
+        loop {
+            while self.skip != 0 {
+                read_nb(&mut self.serial.0, SerialClientError::Serial)?;
+                self.skip -= 1;
+            }
+            if self.read_len > self.buf.len() {
+                self.client.reset();
+                self.skip = self.read_len;
+                self.read_len = REP_HEADER_LEN;
+                return Err(nb::Error::Other(client::Error::ReplyBodyTooLong.into()));
+            }
+            while self.pos < self.read_len {
+                self.buf[self.pos] = read_nb(&mut self.serial.0, SerialClientError::Serial)?;
+                self.pos += 1;
+            }
+            let len = self.pos;
+            self.pos = 0;
+            // Start over with the next reply if parsing fails
+            self.read_len = REP_HEADER_LEN;
+            let (read_len, chan_id) = self
+                .client
+                .parse(&self.buf[..len])
+                .map_err(|err| nb::Error::Other(err.into()))?;
+            self.read_len = read_len;
+            if let Some(chan_id) = chan_id {
+                return Ok(chan_id);
+            }
+        }
+    }
+}
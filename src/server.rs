@@ -1,120 +1,918 @@
 use super::consts::*;
+use super::schema::Schema;
 use super::*;
 
 use core::marker::PhantomData;
-use core::mem::swap;
+use core::mem::{size_of, swap};
+
+use crc::Digest;
 
 use postcard;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{Deserialize, Serialize};
+
+/// Errors produced by the RPC Server while parsing requests and serializing replies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The request method index doesn't match any method.
+    UnknownMethod(u8),
+    /// The request body length exceeds the maximum buffer length of the server.
+    BodyTooLong { len: usize, max: usize },
+    /// The request optional buffer length exceeds the maximum buffer length of the server.
+    OptBufTooLong { len: usize, max: usize },
+    /// The request contains an optional buffer but the method doesn't accept one.
+    UnexpectedOptBuf,
+    /// The request header options have reserved bits set.
+    InvalidOpts(u8),
+    /// The frame length doesn't match the request lengths in its header.
+    FrameLength { len: usize, expected: usize },
+    /// The request CRC trailer doesn't match the request.
+    ChecksumMismatch,
+    /// The request header or body couldn't be deserialized.
+    Deserialize(postcard::Error),
+    /// The reply couldn't be serialized into the reply buffer.
+    Serialize(postcard::Error),
+    /// The reply optional buffer exceeds the room left in the reply buffer for it.
+    ReplyOptBufTooLong { len: usize, max: usize },
+    /// The reply couldn't be sent through its [`ReplyWrite`].
+    Write,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl Error {
+    /// Error code to reply to the client for this error, if the client is to blame.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::UnknownMethod(_) => Some(ErrorCode::UnknownMethod),
+            Self::BodyTooLong { .. } | Self::OptBufTooLong { .. } => Some(ErrorCode::TooLong),
+            Self::UnexpectedOptBuf
+            | Self::InvalidOpts(_)
+            | Self::FrameLength { .. }
+            | Self::Deserialize(_) => Some(ErrorCode::InvalidBody),
+            Self::ChecksumMismatch => Some(ErrorCode::Resend),
+            Self::Serialize(_) | Self::ReplyOptBufTooLong { .. } | Self::Write => None,
+        }
+    }
+}
+
+/// Options of a single reply or of the reply that ends a stream.
+const OPTS_ONCE: Opts = Opts {
+    sync: false,
+    err: false,
+    err_code: false,
+    once: true,
+    cancel: false,
+    crc: false,
+};
+/// Options of a reply with the method error.
+const OPTS_ERR_ONCE: Opts = Opts {
+    err: true,
+    ..OPTS_ONCE
+};
+/// Options of a reply with an [`ErrorCode`].
+const OPTS_ERR_CODE_ONCE: Opts = Opts {
+    err_code: true,
+    ..OPTS_ONCE
+};
+
+/// Serialize a reply packet with `body` placed after an optional buffer of `opt_buf_len` bytes
+/// already written in `reply_buf`.  Returns the number of bytes written to `reply_buf`.
+fn serialize_reply<T: Serialize>(
+    chan_id: u8,
+    opts: Opts,
+    body: &T,
+    opt_buf_len: u16,
+    reply_buf: &mut [u8],
+) -> Result<usize> {
+    let body_buf = postcard::to_slice(
+        body,
+        &mut reply_buf[REP_HEADER_LEN + opt_buf_len as usize..],
+    )
+    .map_err(Error::Serialize)?;
+    let header = ReplyHeader {
+        chan_id,
+        opts: opts.into(),
+        body_len: body_buf.len() as u16,
+        buf_len: opt_buf_len,
+    };
+    postcard::to_slice(&header, reply_buf).map_err(Error::Serialize)?;
+    let len = REP_HEADER_LEN + header.body_len() + header.buf_len();
+    if opts.crc {
+        return append_crc(reply_buf, len).map_err(Error::Serialize);
+    }
+    Ok(len)
+}
+
+/// Serialize an error reply packet carrying `code`, with a CRC trailer if `crc` is set.
+fn serialize_error_code(chan_id: u8, crc: bool, code: ErrorCode, buf: &mut [u8]) -> Result<usize> {
+    serialize_reply(
+        chan_id,
+        Opts {
+            crc,
+            ..OPTS_ERR_CODE_ONCE
+        },
+        &code,
+        0,
+        buf,
+    )
+}
+
+/// Serialize an error reply packet carrying `code` for the request in channel `chan_id`.
+/// Returns the number of bytes written to `reply_buf`.
+pub fn reply_error_code(chan_id: u8, code: ErrorCode, reply_buf: &mut [u8]) -> Result<usize> {
+    serialize_error_code(chan_id, false, code, reply_buf)
+}
+
+/// Room for the optional buffer of a reply in a reply buffer of `reply_buf_len` bytes, once
+/// `body_len` bytes are reserved for the body and the CRC trailer is reserved if `crc` is set.
+/// Returns None if the reply buffer can't even hold the header and the reserved bytes.
+fn opt_buf_room(reply_buf_len: usize, body_len: usize, crc: bool) -> Option<usize> {
+    let trailer_len = if crc { CRC_LEN } else { 0 };
+    let room = reply_buf_len.checked_sub(REP_HEADER_LEN + body_len + trailer_len)?;
+    Some(room.min(u16::MAX as usize))
+}
 
-// TODO Use custom Error
-pub type Result<T> = postcard::Result<T>;
-pub type Error = postcard::Error;
+/// Deserialize the body of a request for a method that doesn't take an optional buffer.
+fn body_from_bytes<'a, Q: Deserialize<'a>>(header: &RequestHeader, buf: &'a [u8]) -> Result<Q> {
+    if header.buf_len() > 0 {
+        return Err(Error::UnexpectedOptBuf);
+    }
+    postcard::from_bytes(buf).map_err(Error::Deserialize)
+}
+
+/// Deserialize the body of a request for a method that takes an optional buffer, returning the
+/// body and the optional buffer.
+fn body_opt_buf_from_bytes<'a, Q: Deserialize<'a>>(
+    header: &RequestHeader,
+    buf: &'a [u8],
+) -> Result<(Q, &'a [u8])> {
+    let buf_start = header.body_len();
+    Ok((
+        postcard::from_bytes(buf).map_err(Error::Deserialize)?,
+        &buf[buf_start..buf_start + header.buf_len()],
+    ))
+}
 
 /// Type used to handle a Request for a particular RPC Call.
 #[derive(Debug)]
-pub struct RequestType<Q: DeserializeOwned, QB: OptBuf, P: Serialize, PB: OptBuf> {
+pub struct RequestType<Q, QB: OptBuf, P: Serialize, PB: OptBuf, E: Serialize = ()> {
     chan_id: u8,
+    crc: bool,
     pub body: Q,
-    phantom: PhantomData<(QB, P, PB)>,
+    phantom: PhantomData<(QB, P, PB, E)>,
 }
 
-impl<Q: DeserializeOwned, P: Serialize, PB: OptBuf> RequestType<Q, OptBufNo, P, PB> {
-    /// Deserialize the body of a Request.
-    pub fn from_bytes(header: RequestHeader, buf: &[u8]) -> Result<Self> {
-        if header.buf_len() > 0 {
-            return Err(postcard::Error::WontImplement);
-        }
+impl<'a, Q: Deserialize<'a>, P: Serialize, PB: OptBuf, E: Serialize>
+    RequestType<Q, OptBufNo, P, PB, E>
+{
+    /// Deserialize the body of a Request.  The body can borrow from `buf`.
+    pub fn from_bytes(header: RequestHeader, buf: &'a [u8]) -> Result<Self> {
         Ok(Self {
             chan_id: header.chan_id,
-            body: postcard::from_bytes(buf)?,
-            phantom: PhantomData::<(OptBufNo, P, PB)>,
+            crc: header.is_crc(),
+            body: body_from_bytes(&header, buf)?,
+            phantom: PhantomData::<(OptBufNo, P, PB, E)>,
         })
     }
 }
 
-impl<Q: DeserializeOwned, P: Serialize, PB: OptBuf> RequestType<Q, OptBufYes, P, PB> {
-    /// Deserialize the body of a Request.
-    pub fn from_bytes<'a>(header: RequestHeader, buf: &'a [u8]) -> Result<(Self, &'a [u8])> {
-        let buf_start = header.body_len();
+impl<'a, Q: Deserialize<'a>, P: Serialize, PB: OptBuf, E: Serialize>
+    RequestType<Q, OptBufYes, P, PB, E>
+{
+    /// Deserialize the body of a Request.  The body can borrow from `buf`.
+    pub fn from_bytes(header: RequestHeader, buf: &'a [u8]) -> Result<(Self, &'a [u8])> {
+        let (body, opt_buf) = body_opt_buf_from_bytes(&header, buf)?;
         Ok((
             Self {
                 chan_id: header.chan_id,
-                body: postcard::from_bytes(buf)?,
-                phantom: PhantomData::<(OptBufYes, P, PB)>,
+                crc: header.is_crc(),
+                body,
+                phantom: PhantomData::<(OptBufYes, P, PB, E)>,
             },
-            &buf[buf_start..buf_start + header.buf_len()],
+            opt_buf,
         ))
     }
 }
 
-impl<Q: DeserializeOwned, QB: OptBuf, P: Serialize> RequestType<Q, QB, P, OptBufNo> {
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> RequestType<Q, QB, P, OptBufNo, E> {
     /// Serialize a reply packet build from a payload.  Returns the number of bytes written to
     /// `reply_buf`.
-    pub fn reply(self, payload: P, mut reply_buf: &mut [u8]) -> Result<usize> {
-        let body_buf = postcard::to_slice(&payload, &mut reply_buf[REP_HEADER_LEN..])?;
-        let header = ReplyHeader {
-            chan_id: self.chan_id,
-            opts: 0,
-            body_len: body_buf.len() as u16,
-            buf_len: 0,
-        };
-        postcard::to_slice(&header, &mut reply_buf)?;
-        Ok(REP_HEADER_LEN + header.body_len() + header.buf_len())
+    pub fn reply(self, payload: P, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..OPTS_ONCE
+            },
+            &payload,
+            0,
+            reply_buf,
+        )
     }
 }
 
-impl<Q: DeserializeOwned, QB: OptBuf, P: Serialize> RequestType<Q, QB, P, OptBufYes> {
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> RequestType<Q, QB, P, OptBufYes, E> {
+    /// Start the reply by writing its optional buffer in place in `reply_buf`, with room for a
+    /// body of up to `body_len` bytes.  The returned [`OptBufWriter`] builds the reply.  Fails
+    /// if `reply_buf` can't hold the reply header and the body.
+    pub fn opt_buf_writer(
+        self,
+        body_len: usize,
+        reply_buf: &mut [u8],
+    ) -> Result<OptBufWriter<'_, Self>> {
+        let crc = self.crc;
+        OptBufWriter::new(self, body_len, crc, reply_buf)
+    }
+
+    #[deprecated(note = "use `opt_buf_writer`, which checks the optional buffer length")]
     pub fn get_opt_buf<'a>(&self, reply_buf: &'a mut [u8]) -> &'a mut [u8] {
         &mut reply_buf[REP_HEADER_LEN..]
     }
 
     /// Serialize a reply packet build from a payload.  Returns the number of bytes written to
     /// `reply_buf`.
-    pub fn reply(self, payload: P, opt_buf_len: u16, mut reply_buf: &mut [u8]) -> Result<usize> {
-        let body_buf = postcard::to_slice(
+    #[deprecated(note = "use `opt_buf_writer`, which checks the optional buffer length")]
+    pub fn reply(self, payload: P, opt_buf_len: u16, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..OPTS_ONCE
+            },
             &payload,
-            &mut reply_buf[REP_HEADER_LEN + opt_buf_len as usize..],
-        )?;
-        let header = ReplyHeader {
-            chan_id: self.chan_id,
-            opts: 0,
-            body_len: body_buf.len() as u16,
-            buf_len: opt_buf_len,
+            opt_buf_len,
+            reply_buf,
+        )
+    }
+}
+
+impl<Q, QB: OptBuf, P: Serialize, PB: OptBuf, E: Serialize> RequestType<Q, QB, P, PB, E> {
+    /// Serialize an error reply packet carrying the method error `err`.  Error replies never
+    /// contain an optional buffer.  Returns the number of bytes written to `reply_buf`.
+    pub fn reply_err(self, err: E, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..OPTS_ERR_ONCE
+            },
+            &err,
+            0,
+            reply_buf,
+        )
+    }
+
+    /// Serialize an error reply packet carrying the protocol error `code`, for example
+    /// [`ErrorCode::Busy`].  Returns the number of bytes written to `reply_buf`.
+    pub fn reply_error_code(self, code: ErrorCode, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_error_code(self.chan_id, self.crc, code, reply_buf)
+    }
+
+    /// Channel id of the request.
+    pub fn chan_id(&self) -> u8 {
+        self.chan_id
+    }
+}
+
+/// Type used to handle a Request for a stream RPC Call.  The server can reply any number of items
+/// in the channel of the request until it ends the stream.
+#[derive(Debug)]
+pub struct StreamType<Q, QB: OptBuf, P: Serialize, PB: OptBuf, E: Serialize = ()> {
+    chan_id: u8,
+    crc: bool,
+    pub body: Q,
+    phantom: PhantomData<(QB, P, PB, E)>,
+}
+
+impl<'a, Q: Deserialize<'a>, P: Serialize, PB: OptBuf, E: Serialize>
+    StreamType<Q, OptBufNo, P, PB, E>
+{
+    /// Deserialize the body of a Request.  The body can borrow from `buf`.
+    pub fn from_bytes(header: RequestHeader, buf: &'a [u8]) -> Result<Self> {
+        Ok(Self {
+            chan_id: header.chan_id,
+            crc: header.is_crc(),
+            body: body_from_bytes(&header, buf)?,
+            phantom: PhantomData::<(OptBufNo, P, PB, E)>,
+        })
+    }
+}
+
+impl<'a, Q: Deserialize<'a>, P: Serialize, PB: OptBuf, E: Serialize>
+    StreamType<Q, OptBufYes, P, PB, E>
+{
+    /// Deserialize the body of a Request.  The body can borrow from `buf`.
+    pub fn from_bytes(header: RequestHeader, buf: &'a [u8]) -> Result<(Self, &'a [u8])> {
+        let (body, opt_buf) = body_opt_buf_from_bytes(&header, buf)?;
+        Ok((
+            Self {
+                chan_id: header.chan_id,
+                crc: header.is_crc(),
+                body,
+                phantom: PhantomData::<(OptBufYes, P, PB, E)>,
+            },
+            opt_buf,
+        ))
+    }
+}
+
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> StreamType<Q, QB, P, OptBufNo, E> {
+    /// Serialize a reply packet with an item of the stream.  Returns the number of bytes written
+    /// to `reply_buf`.
+    pub fn reply_item(&self, payload: P, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..Opts::default()
+            },
+            &payload,
+            0,
+            reply_buf,
+        )
+    }
+}
+
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> StreamType<Q, QB, P, OptBufYes, E> {
+    /// Start a reply with an item of the stream by writing its optional buffer in place in
+    /// `reply_buf`, with room for a body of up to `body_len` bytes.  The returned
+    /// [`OptBufWriter`] builds the reply.  Fails if `reply_buf` can't hold the reply header and
+    /// the body.
+    pub fn item_writer<'a>(
+        &self,
+        body_len: usize,
+        reply_buf: &'a mut [u8],
+    ) -> Result<OptBufWriter<'a, &Self>> {
+        OptBufWriter::new(self, body_len, self.crc, reply_buf)
+    }
+
+    #[deprecated(note = "use `item_writer`, which checks the optional buffer length")]
+    pub fn get_opt_buf<'a>(&self, reply_buf: &'a mut [u8]) -> &'a mut [u8] {
+        &mut reply_buf[REP_HEADER_LEN..]
+    }
+
+    /// Serialize a reply packet with an item of the stream.  Returns the number of bytes written
+    /// to `reply_buf`.
+    #[deprecated(note = "use `item_writer`, which checks the optional buffer length")]
+    pub fn reply_item(&self, payload: P, opt_buf_len: u16, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..Opts::default()
+            },
+            &payload,
+            opt_buf_len,
+            reply_buf,
+        )
+    }
+}
+
+impl<Q, QB: OptBuf, P: Serialize, PB: OptBuf, E: Serialize> StreamType<Q, QB, P, PB, E> {
+    /// Serialize the reply packet that ends the stream.  Returns the number of bytes written to
+    /// `reply_buf`.
+    pub fn end(self, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..OPTS_ONCE
+            },
+            &(),
+            0,
+            reply_buf,
+        )
+    }
+
+    /// Serialize an error reply packet carrying the method error `err`, which ends the stream.
+    /// Returns the number of bytes written to `reply_buf`.
+    pub fn reply_err(self, err: E, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_reply(
+            self.chan_id,
+            Opts {
+                crc: self.crc,
+                ..OPTS_ERR_ONCE
+            },
+            &err,
+            0,
+            reply_buf,
+        )
+    }
+
+    /// Serialize an error reply packet carrying the protocol error `code`, which ends the
+    /// stream.  Returns the number of bytes written to `reply_buf`.
+    pub fn reply_error_code(self, code: ErrorCode, reply_buf: &mut [u8]) -> Result<usize> {
+        serialize_error_code(self.chan_id, self.crc, code, reply_buf)
+    }
+
+    /// Channel id of the request.
+    pub fn chan_id(&self) -> u8 {
+        self.chan_id
+    }
+}
+
+/// Writer of the optional buffer of a reply, in place in the reply buffer, returned by
+/// [`RequestType::opt_buf_writer`] and [`StreamType::item_writer`].  The writes can't exceed
+/// the room left for the optional buffer in front of the body, and the reply packet is built
+/// with the length written.
+pub struct OptBufWriter<'a, R> {
+    request: R,
+    reply_buf: &'a mut [u8],
+    len: usize,
+    room: usize,
+}
+
+impl<'a, R> OptBufWriter<'a, R> {
+    fn new(request: R, body_len: usize, crc: bool, reply_buf: &'a mut [u8]) -> Result<Self> {
+        let room = opt_buf_room(reply_buf.len(), body_len, crc)
+            .ok_or(Error::Serialize(postcard::Error::SerializeBufferFull))?;
+        Ok(Self {
+            request,
+            reply_buf,
+            len: 0,
+            room,
+        })
+    }
+
+    /// Number of bytes written to the optional buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no byte has been written to the optional buffer.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maximum length of the optional buffer.
+    pub fn capacity(&self) -> usize {
+        self.room
+    }
+
+    /// Bytes written to the optional buffer.
+    pub fn written(&self) -> &[u8] {
+        &self.reply_buf[REP_HEADER_LEN..REP_HEADER_LEN + self.len]
+    }
+
+    /// Append `bytes` to the optional buffer.  Nothing is written if they don't fit.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.check_len(bytes.len())?;
+        self.spare()[..bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Room left in the optional buffer, to write bytes in place before committing them with
+    /// [`OptBufWriter::advance`].
+    pub fn spare(&mut self) -> &mut [u8] {
+        &mut self.reply_buf[REP_HEADER_LEN + self.len..REP_HEADER_LEN + self.room]
+    }
+
+    /// Commit `n` bytes written at the start of [`OptBufWriter::spare`] to the optional buffer.
+    pub fn advance(&mut self, n: usize) -> Result<()> {
+        self.check_len(n)?;
+        self.len += n;
+        Ok(())
+    }
+
+    fn check_len(&self, n: usize) -> Result<()> {
+        if n > self.room - self.len {
+            return Err(Error::ReplyOptBufTooLong {
+                len: self.len + n,
+                max: self.room,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<'a, Q, QB: OptBuf, P: Serialize, E: Serialize>
+    OptBufWriter<'a, RequestType<Q, QB, P, OptBufYes, E>>
+{
+    /// Serialize the reply packet with the payload after the optional buffer written.  Returns
+    /// the number of bytes written to the reply buffer.
+    pub fn reply(self, payload: P) -> Result<usize> {
+        let opts = Opts {
+            crc: self.request.crc,
+            ..OPTS_ONCE
+        };
+        let chan_id = self.request.chan_id;
+        serialize_reply(chan_id, opts, &payload, self.len as u16, self.reply_buf)
+    }
+
+    /// Drop the optional buffer written and serialize an error reply packet carrying the method
+    /// error `err`.  See [`RequestType::reply_err`].
+    pub fn reply_err(self, err: E) -> Result<usize> {
+        self.request.reply_err(err, self.reply_buf)
+    }
+}
+
+impl<'a, 's, Q, QB: OptBuf, P: Serialize, E: Serialize>
+    OptBufWriter<'a, &'s StreamType<Q, QB, P, OptBufYes, E>>
+{
+    /// Serialize the reply packet with an item of the stream after the optional buffer written.
+    /// Returns the number of bytes written to the reply buffer.
+    pub fn reply_item(self, payload: P) -> Result<usize> {
+        let opts = Opts {
+            crc: self.request.crc,
+            ..Opts::default()
+        };
+        let chan_id = self.request.chan_id;
+        serialize_reply(chan_id, opts, &payload, self.len as u16, self.reply_buf)
+    }
+}
+
+/// Destination of the reply packets of a request handled through [`Dispatch`].
+pub trait ReplyWrite {
+    /// Buffer to serialize the next reply packet into.
+    fn buf(&mut self) -> &mut [u8];
+    /// Send the reply packet serialized in the first `len` bytes of the buffer.
+    fn send(&mut self, len: usize) -> Result<()>;
+}
+
+/// Sender of the items of a stream request, given to its handler.  The items carry an optional
+/// buffer if `PB` is [`OptBufYes`].
+pub struct Items<'a, P: Serialize, PB: OptBuf = OptBufNo> {
+    chan_id: u8,
+    crc: bool,
+    writer: &'a mut dyn ReplyWrite,
+    err: Option<Error>,
+    phantom: PhantomData<(P, PB)>,
+}
+
+impl<'a, P: Serialize, PB: OptBuf> Items<'a, P, PB> {
+    /// Serialize an item after an optional buffer of `opt_buf_len` bytes and send it.
+    fn send_reply(&mut self, item: P, opt_buf_len: u16) -> Result<()> {
+        if let Some(err) = &self.err {
+            return Err(err.clone());
+        }
+        let opts = Opts {
+            crc: self.crc,
+            ..Opts::default()
+        };
+        let result = serialize_reply(self.chan_id, opts, &item, opt_buf_len, self.writer.buf())
+            .and_then(|n| self.writer.send(n));
+        if let Err(err) = &result {
+            self.err = Some(err.clone());
+        }
+        result
+    }
+}
+
+impl<'a, P: Serialize> Items<'a, P, OptBufNo> {
+    /// Send an item of the stream.  Once sending an item fails, the following items are dropped.
+    /// The stream ends with an [`ErrorCode::TooLong`] error reply if the item didn't fit in the
+    /// reply buffer, and is not ended otherwise.
+    pub fn send(&mut self, item: P) -> Result<()> {
+        self.send_reply(item, 0)
+    }
+}
+
+impl<'a, P: Serialize> Items<'a, P, OptBufYes> {
+    /// Send an item of the stream with the optional buffer `buf`, which fits in the reply buffer
+    /// with room for an item as large as `P` in memory.  Once sending an item fails, the
+    /// following items are dropped.  The stream ends with an [`ErrorCode::TooLong`] error reply
+    /// if the item didn't fit in the reply buffer, and is not ended otherwise.
+    pub fn send(&mut self, item: P, buf: &[u8]) -> Result<()> {
+        if self.err.is_none() {
+            let reply_buf = self.writer.buf();
+            let room = opt_buf_room(reply_buf.len(), size_of::<P>(), self.crc).unwrap_or(0);
+            if buf.len() > room {
+                self.err = Some(Error::ReplyOptBufTooLong {
+                    len: buf.len(),
+                    max: room,
+                });
+            } else {
+                reply_buf[REP_HEADER_LEN..REP_HEADER_LEN + buf.len()].copy_from_slice(buf);
+            }
+        }
+        self.send_reply(item, buf.len() as u16)
+    }
+}
+
+/// Send an [`ErrorCode::TooLong`] error reply for the request in `chan_id` if `result` failed
+/// because a reply didn't fit in the reply buffer, so that the client doesn't wait for it.
+fn reply_too_long(
+    chan_id: u8,
+    crc: bool,
+    result: Result<()>,
+    writer: &mut dyn ReplyWrite,
+) -> Result<()> {
+    match result {
+        Err(Error::ReplyOptBufTooLong { .. })
+        | Err(Error::Serialize(postcard::Error::SerializeBufferFull)) => {
+            let n = serialize_error_code(chan_id, crc, ErrorCode::TooLong, writer.buf())?;
+            writer.send(n)
+        }
+        result => result,
+    }
+}
+
+/// Serialize the result of a handler and send it as the reply of the request in `chan_id`.
+fn send_result<P: Serialize, E: Serialize>(
+    chan_id: u8,
+    crc: bool,
+    result: core::result::Result<(P, u16), E>,
+    writer: &mut dyn ReplyWrite,
+) -> Result<()> {
+    let n = match result {
+        Ok((payload, opt_buf_len)) => {
+            let opts = Opts { crc, ..OPTS_ONCE };
+            serialize_reply(chan_id, opts, &payload, opt_buf_len, writer.buf())?
+        }
+        Err(err) => {
+            let opts = Opts {
+                crc,
+                ..OPTS_ERR_ONCE
+            };
+            serialize_reply(chan_id, opts, &err, 0, writer.buf())?
+        }
+    };
+    writer.send(n)
+}
+
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> RequestType<Q, QB, P, OptBufNo, E> {
+    /// Call `handler` with the request body and send its result as the reply.
+    pub fn handle<F>(self, handler: F, writer: &mut dyn ReplyWrite) -> Result<()>
+    where
+        F: FnOnce(Q) -> core::result::Result<P, E>,
+    {
+        let result = handler(self.body).map(|payload| (payload, 0));
+        send_result(self.chan_id, self.crc, result, writer)
+    }
+}
+
+impl<Q, QB: OptBuf, P: Serialize, E: Serialize> RequestType<Q, QB, P, OptBufYes, E> {
+    /// Call `handler` with the request body and the optional buffer of the reply, and send its
+    /// result as the reply.  The handler returns the reply and the length of the optional buffer.
+    /// The buffer leaves room for a body as large as `P` in memory, which holds the serialized
+    /// body of fixed size types.  If the length exceeds the buffer or the body doesn't fit after
+    /// it, an [`ErrorCode::TooLong`] error reply is sent instead.
+    pub fn handle<F>(self, handler: F, writer: &mut dyn ReplyWrite) -> Result<()>
+    where
+        F: FnOnce(Q, &mut [u8]) -> core::result::Result<(P, usize), E>,
+    {
+        let (chan_id, crc) = (self.chan_id, self.crc);
+        let buf = writer.buf();
+        let room = opt_buf_room(buf.len(), size_of::<P>(), crc).unwrap_or(0);
+        let out = buf
+            .get_mut(REP_HEADER_LEN..REP_HEADER_LEN + room)
+            .unwrap_or(&mut []);
+        let result = match handler(self.body, out) {
+            Ok((_, len)) if len > room => Err(Error::ReplyOptBufTooLong { len, max: room }),
+            result => {
+                let result = result.map(|(payload, opt_buf_len)| (payload, opt_buf_len as u16));
+                send_result(chan_id, crc, result, writer)
+            }
         };
-        postcard::to_slice(&header, &mut reply_buf)?;
-        Ok(REP_HEADER_LEN + header.body_len() + header.buf_len())
+        reply_too_long(chan_id, crc, result, writer)
     }
 }
 
-impl<Q: DeserializeOwned, QB: OptBuf, P: Serialize, PB: OptBuf> RequestType<Q, QB, P, PB> {
-    /// Serialize an error reply packet.  Returns the number of bytes written to `reply_buf`.
-    pub fn reply_err(self, _err: u8, mut reply_buf: &mut [u8]) -> Result<usize> {
-        let header = ReplyHeader {
+impl<Q, QB: OptBuf, P: Serialize, PB: OptBuf, E: Serialize> StreamType<Q, QB, P, PB, E> {
+    /// Call `handler` with the request body and the sender of the stream items, and end the
+    /// stream once it returns.
+    pub fn handle<F>(self, handler: F, writer: &mut dyn ReplyWrite) -> Result<()>
+    where
+        F: FnOnce(Q, &mut Items<P, PB>) -> core::result::Result<(), E>,
+    {
+        let mut items = Items {
             chan_id: self.chan_id,
-            opts: 1,
-            body_len: 0,
-            buf_len: 0,
+            crc: self.crc,
+            writer,
+            err: None,
+            phantom: PhantomData,
         };
-        postcard::to_slice(&header, &mut reply_buf)?;
-        Ok(REP_HEADER_LEN)
+        let result = handler(self.body, &mut items);
+        if let Some(err) = items.err {
+            return reply_too_long(self.chan_id, self.crc, Err(err), items.writer);
+        }
+        send_result(
+            self.chan_id,
+            self.crc,
+            result.map(|()| ((), 0)),
+            items.writer,
+        )
+    }
+}
+
+/// Dispatch of requests to a handler of type `H`.  It's implemented by the requests enum built
+/// with [`server_requests`](../macro.server_requests.html) for the `Handler` trait built along
+/// it.
+pub trait Dispatch<H: ?Sized> {
+    /// Schema of the requests, used to reply the built-in
+    /// [`METHOD_GET_INFO`](../consts/constant.METHOD_GET_INFO.html) and
+    /// [`METHOD_SCHEMA_HASH`](../consts/constant.METHOD_SCHEMA_HASH.html) methods.
+    const SCHEMA: Schema;
+
+    /// Deserialize the request in `header` and `buf`, call its handler method and send the
+    /// replies through `writer`.
+    fn dispatch(
+        handler: &mut H,
+        header: RequestHeader,
+        buf: &[u8],
+        writer: &mut dyn ReplyWrite,
+    ) -> Result<()>;
+}
+
+/// Built-in request without body nor optional buffers, that replies a `P`.
+type BuiltinRequest<P> = RequestType<(), OptBufNo, P, OptBufNo>;
+
+/// Reply the request in `header` if it's a built-in method, or dispatch it to `handler`
+/// otherwise.  `max_buf_len` is the one of the server that parsed the request.
+fn dispatch_request<D: Dispatch<H>, H: ?Sized>(
+    handler: &mut H,
+    max_buf_len: u16,
+    header: RequestHeader,
+    buf: &[u8],
+    writer: &mut dyn ReplyWrite,
+) -> Result<()> {
+    match header.method_idx {
+        METHOD_PING => BuiltinRequest::<()>::from_bytes(header, buf)?.handle(|()| Ok(()), writer),
+        METHOD_GET_INFO => BuiltinRequest::from_bytes(header, buf)?.handle(
+            |()| {
+                Ok(ServerInfo {
+                    version: PROTOCOL_VERSION,
+                    methods: D::SCHEMA.methods.len() as u8,
+                })
+            },
+            writer,
+        ),
+        METHOD_MAX_BUF_LEN => {
+            BuiltinRequest::from_bytes(header, buf)?.handle(|()| Ok(max_buf_len), writer)
+        }
+        METHOD_SCHEMA_HASH => {
+            BuiltinRequest::from_bytes(header, buf)?.handle(|()| Ok(D::SCHEMA.hash), writer)
+        }
+        _ => D::dispatch(handler, header, buf, writer),
     }
 }
 
+/// [`ReplyWrite`] that serializes the replies one after the other into a buffer.
+pub struct BufReplyWrite<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufReplyWrite<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Number of bytes of the replies sent so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no reply has been sent.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> ReplyWrite for BufReplyWrite<'a> {
+    fn buf(&mut self) -> &mut [u8] {
+        &mut self.buf[self.len..]
+    }
+
+    fn send(&mut self, len: usize) -> Result<()> {
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Parse the bytes in `rcv_buf` with `rpc_server` and, once a request is complete, call its
+/// method in `handler` and serialize its replies (all the items for stream methods) into
+/// `reply_buf`.  `D` is the requests enum built with
+/// [`server_requests`](../macro.server_requests.html).  Requests that can't be handled get an
+/// error reply.  Returns [`ParseResult::Request`] with the number of bytes written to
+/// `reply_buf`, which have to be sent to the client.
+///
+/// Requests of the built-in methods, whose ids start at
+/// [`BUILTIN_METHOD_ID_START`](../consts/constant.BUILTIN_METHOD_ID_START.html), are replied
+/// without calling `handler`.
+///
+/// # Examples
+///
+/// ```
+/// use urpc::{client, consts, server, server_requests};
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///         (1, recv_bytes, RecvBytes(u8, OptBufNo, (), OptBufYes))
+///     }
+/// }
+///
+/// server_requests! {
+///     ServerRequests;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (1, recv_bytes, RecvBytes(u8, OptBufNo, (), OptBufYes)),
+///     (2, send_bytes, SendBytes((), OptBufYes, usize, OptBufNo))
+/// }
+///
+/// struct Server;
+///
+/// impl Handler for Server {
+///     fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+///         Ok(body)
+///     }
+///     fn recv_bytes(&mut self, body: u8, out: &mut [u8]) -> Result<((), usize), ()> {
+///         let n = body as usize;
+///         out[..n].copy_from_slice(&[0xaa; 256][..n]);
+///         Ok(((), n))
+///     }
+///     fn send_bytes(&mut self, _body: (), buf: &[u8]) -> Result<usize, ()> {
+///         Ok(buf.len())
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let mut send_buf = vec![0; 32];
+/// let mut reply_buf = vec![0; 32];
+///
+/// let mut req = cli::RecvBytes::new(3);
+/// let n = req.request(&mut rpc_client, &mut send_buf).unwrap();
+///
+/// let mut pos = 0;
+/// let mut read_len = consts::REQ_HEADER_LEN;
+/// let reply_len = loop {
+///     let buf = &send_buf[pos..pos + read_len];
+///     pos += read_len;
+///     match server::dispatch::<ServerRequests, _>(&mut rpc_server, &mut Server, buf, &mut reply_buf) {
+///         Ok(server::ParseResult::NeedBytes(n)) => read_len = n,
+///         Ok(server::ParseResult::Request(n)) => break n,
+///         r => panic!("unexpected result: {:?}", r),
+///     }
+/// };
+///
+/// let read_len = rpc_client.parse(&reply_buf[..consts::REP_HEADER_LEN]).unwrap().0;
+/// rpc_client.parse(&reply_buf[consts::REP_HEADER_LEN..reply_len]).unwrap();
+/// assert_eq!(read_len, reply_len - consts::REP_HEADER_LEN);
+/// let (_, opt_buf) = req.take_reply(&mut rpc_client).unwrap().unwrap();
+/// assert_eq!(opt_buf, [0xaa, 0xaa, 0xaa]);
+/// ```
+pub fn dispatch<D: Dispatch<H>, H: ?Sized>(
+    rpc_server: &mut RpcServer,
+    handler: &mut H,
+    rcv_buf: &[u8],
+    reply_buf: &mut [u8],
+) -> Result<ParseResult<usize>> {
+    let parsed = rpc_server.parse(rcv_buf);
+    dispatch_parsed::<D, H>(rpc_server, handler, parsed, reply_buf)
+}
+
+/// Serve the request parsed by `rpc_server`, like [`dispatch`].
+fn dispatch_parsed<D: Dispatch<H>, H: ?Sized>(
+    rpc_server: &RpcServer,
+    handler: &mut H,
+    parsed: Result<ParsedRequest>,
+    reply_buf: &mut [u8],
+) -> Result<ParseResult<usize>> {
+    let mut writer = BufReplyWrite::new(reply_buf);
+    let result = match parsed {
+        Ok(ParseResult::NeedBytes(n)) => return Ok(ParseResult::NeedBytes(n)),
+        Ok(ParseResult::Cancel(chan_id)) => return Ok(ParseResult::Cancel(chan_id)),
+        Ok(ParseResult::Request((header, buf))) => {
+            dispatch_request::<D, H>(handler, rpc_server.max_buf_len, header, buf, &mut writer)
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        let code = err.code().ok_or(err)?;
+        if let Some(n) = rpc_server.reply_error_code(code, writer.buf())? {
+            writer.send(n)?;
+        }
+    }
+    Ok(ParseResult::Request(writer.len()))
+}
+
 enum State {
     WaitHeader,
-    WaitBody(RequestHeader),
+    /// Waiting for the rest of the request, with the CRC of the header if it has a trailer.
+    WaitBody(RequestHeader, Option<Digest<'static, u16>>),
     // RecvdBody(Result<R>, u16),
     // WaitBuf(Result<R>),
     // Request(Result<R>),
 }
 
 /// Result of parsing some bytes by the RPC Server.
+#[derive(Debug)]
 pub enum ParseResult<T> {
     NeedBytes(usize),
     Request(T),
+    /// The client cancelled the request in this channel.  The server should stop replying to it.
+    Cancel(u8),
 }
 
+/// Result of parsing the bytes of a request.  A complete request holds its header and a buffer
+/// with its body followed by its optional buffer.
+pub type ParsedRequest<'a> = ParseResult<(RequestHeader, &'a [u8])>;
+
 /// RPC Call request.
 pub trait Request<'a>
 where
@@ -126,11 +924,24 @@ where
     fn from_bytes(header: RequestHeader, buf: &'a [u8]) -> Result<Self>;
 
     fn from_rpc(rpc_server: &mut RpcServer, rcv_buf: &'a [u8]) -> Result<ParseResult<Self>> {
-        match rpc_server.parse(&rcv_buf)? {
+        match rpc_server.parse(rcv_buf)? {
             ParseResult::NeedBytes(n) => Ok(ParseResult::NeedBytes(n)),
             ParseResult::Request((header, body_buf)) => {
                 Ok(ParseResult::Request(Self::from_bytes(header, body_buf)?))
             }
+            ParseResult::Cancel(chan_id) => Ok(ParseResult::Cancel(chan_id)),
+        }
+    }
+
+    /// Like [`Request::from_rpc`], but parsing a whole request packet from a frame.  See
+    /// [`RpcServer::parse_frame`].
+    fn from_rpc_frame(rpc_server: &mut RpcServer, frame: &'a [u8]) -> Result<ParseResult<Self>> {
+        match rpc_server.parse_frame(frame)? {
+            ParseResult::NeedBytes(n) => Ok(ParseResult::NeedBytes(n)),
+            ParseResult::Request((header, body_buf)) => {
+                Ok(ParseResult::Request(Self::from_bytes(header, body_buf)?))
+            }
+            ParseResult::Cancel(chan_id) => Ok(ParseResult::Cancel(chan_id)),
         }
     }
 }
@@ -140,6 +951,8 @@ where
 pub struct RpcServer {
     max_buf_len: u16,
     state: State,
+    chan_id: Option<u8>,
+    crc: bool,
 }
 
 impl RpcServer {
@@ -147,48 +960,617 @@ impl RpcServer {
         Self {
             max_buf_len,
             state: State::WaitHeader,
+            chan_id: None,
+            crc: false,
+        }
+    }
+
+    /// Channel id of the last request header parsed.
+    pub fn chan_id(&self) -> Option<u8> {
+        self.chan_id
+    }
+
+    /// Drop any partially parsed request, so that the next bytes parsed are a request header.
+    /// Useful to recover when a client stops sending in the middle of a request.
+    pub fn reset(&mut self) {
+        self.state = State::WaitHeader;
+    }
+
+    /// Serialize an error reply packet carrying `code` for the last request header parsed, so
+    /// that the client doesn't wait forever for a request that failed to parse.  Returns the
+    /// number of bytes written to `reply_buf`, or None if no request header has been parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use urpc::{client, consts, server::{self, Request}, server_requests};
+    ///
+    /// mod cli {
+    ///     use urpc::client_requests;
+    ///
+    ///     client_requests! {
+    ///         client_requests;
+    ///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    ///         (1, reset, Reset((), OptBufNo, (), OptBufNo))
+    ///     }
+    /// }
+    ///
+    /// // This server doesn't implement the reset method
+    /// server_requests! {
+    ///     ServerRequest;
+    ///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    ///     (2, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+    /// }
+    ///
+    /// let mut rpc_client = client::RpcClient::new(32);
+    /// let mut rpc_server = server::RpcServer::new(32);
+    /// let mut send_buf = vec![0; 32];
+    /// let mut reply_buf = vec![0; 32];
+    ///
+    /// let mut req = cli::Reset::new(());
+    /// let n = req.request(&mut rpc_client, &mut send_buf).unwrap();
+    ///
+    /// let err = ServerRequest::from_rpc(&mut rpc_server, &send_buf[..n]).unwrap_err();
+    /// let code = err.code().unwrap();
+    /// let reply_len = rpc_server.reply_error_code(code, &mut reply_buf).unwrap().unwrap();
+    ///
+    /// let read_len = rpc_client.parse(&reply_buf[..consts::REP_HEADER_LEN]).unwrap().0;
+    /// rpc_client.parse(&reply_buf[consts::REP_HEADER_LEN..reply_len]).unwrap();
+    /// assert_eq!(read_len, reply_len - consts::REP_HEADER_LEN);
+    /// match req.take_reply(&mut rpc_client).unwrap() {
+    ///     Err(client::MethodError::Client(client::Error::UnknownMethod)) => {}
+    ///     r => panic!("unexpected reply: {:?}", r),
+    /// }
+    /// ```
+    pub fn reply_error_code(&self, code: ErrorCode, reply_buf: &mut [u8]) -> Result<Option<usize>> {
+        self.chan_id
+            .map(|chan_id| serialize_error_code(chan_id, self.crc, code, reply_buf))
+            .transpose()
+    }
+
+    /// Parse incoming bytes and return wether a request has been received, a request has been
+    /// cancelled, or more bytes are needed to build a complete request.
+    pub fn parse<'a>(&mut self, rcv_buf: &'a [u8]) -> Result<ParsedRequest<'a>> {
+        let mut state = State::WaitHeader;
+        swap(&mut state, &mut self.state);
+        match state {
+            State::WaitHeader => {
+                let req_header = req_header_from_bytes(rcv_buf).map_err(Error::Deserialize)?;
+                self.chan_id = Some(req_header.chan_id);
+                self.crc = req_header.is_crc();
+                Opts::try_from(req_header.opts)
+                    .map_err(|InvalidOpts(opts)| Error::InvalidOpts(opts))?;
+                if req_header.body_len >= self.max_buf_len {
+                    return Err(Error::BodyTooLong {
+                        len: req_header.body_len(),
+                        max: self.max_buf_len as usize,
+                    });
+                }
+                if req_header.buf_len >= self.max_buf_len {
+                    return Err(Error::OptBufTooLong {
+                        len: req_header.buf_len(),
+                        max: self.max_buf_len as usize,
+                    });
+                }
+                let req_header_body_len = req_header.body_len;
+                let req_header_buf_len = req_header.buf_len;
+                if req_header_body_len + req_header_buf_len == 0 && !req_header.is_crc() {
+                    // let req = R::from_bytes(req_header, &[]);
+                    self.state = State::WaitHeader;
+                    if req_header.is_cancel() {
+                        return Ok(ParseResult::Cancel(req_header.chan_id));
+                    }
+                    Ok(ParseResult::Request((req_header, &[])))
+                } else {
+                    let mut n = req_header.body_len() + req_header.buf_len();
+                    let digest = if req_header.is_crc() {
+                        n += CRC_LEN;
+                        Some(crc_digest(&rcv_buf[..REQ_HEADER_LEN]))
+                    } else {
+                        None
+                    };
+                    self.state = State::WaitBody(req_header, digest);
+                    Ok(ParseResult::NeedBytes(n))
+                }
+            }
+            State::WaitBody(req_header, digest) => {
+                // let req = R::from_bytes(req_header, &rcv_buf[..]);
+                self.state = State::WaitHeader;
+                let rcv_buf = match digest {
+                    Some(digest) => split_crc(digest, rcv_buf).ok_or(Error::ChecksumMismatch)?,
+                    None => rcv_buf,
+                };
+                if req_header.is_cancel() {
+                    return Ok(ParseResult::Cancel(req_header.chan_id));
+                }
+                Ok(ParseResult::Request((req_header, rcv_buf)))
+            }
+        }
+    }
+
+    /// Parse a whole request packet, as found in a frame decoded by
+    /// [`framing::Decoder`](../framing/struct.Decoder.html).  Any partially parsed request is
+    /// discarded first, so a corrupted frame doesn't affect the following ones.  Never returns
+    /// [`ParseResult::NeedBytes`].
+    pub fn parse_frame<'a>(&mut self, frame: &'a [u8]) -> Result<ParsedRequest<'a>> {
+        self.reset();
+        let req_header = req_header_from_bytes(frame).map_err(Error::Deserialize)?;
+        let crc_len = if req_header.is_crc() { CRC_LEN } else { 0 };
+        let expected = REQ_HEADER_LEN + req_header.body_len() + req_header.buf_len() + crc_len;
+        if frame.len() != expected {
+            self.chan_id = Some(req_header.chan_id);
+            return Err(Error::FrameLength {
+                len: frame.len(),
+                expected,
+            });
+        }
+        match self.parse(&frame[..REQ_HEADER_LEN])? {
+            ParseResult::NeedBytes(_) => self.parse(&frame[REQ_HEADER_LEN..]),
+            result => Ok(result),
+        }
+    }
+}
+
+/// Push style parser of the requests received by an [`RpcServer`], for receivers that get bytes
+/// in chunks of any length, like the interrupt handler of a UART.  The request packets are
+/// buffered until they are complete, so the chunks don't need to match the lengths asked by
+/// [`RpcServer::parse`].  Requests that are rejected while parsing their header are dropped
+/// along with the rest of their bytes, and requests that don't fit in the buffer are rejected
+/// with [`Error::BodyTooLong`].  The error reply of a rejected request can be serialized with
+/// [`RpcServer::reply_error_code`].
+///
+/// # Examples
+///
+/// ```
+/// use urpc::{client, server::{self, Request}, server_requests, ErrorCode};
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///         (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+///     }
+/// }
+///
+/// server_requests! {
+///     ServerRequest;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (1, send_bytes, SendBytes((), OptBufYes, (), OptBufNo))
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(64);
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let mut send_buf = vec![0; 64];
+/// let mut line = Vec::new();
+///
+/// // A request too long for the receiver buffer, followed by two pings
+/// let mut send_bytes = cli::SendBytes::new(());
+/// let n = send_bytes.request(&[0xaa; 40], &mut rpc_client, &mut send_buf).unwrap();
+/// line.extend_from_slice(&send_buf[..n]);
+/// for i in 0..2 {
+///     let mut ping = cli::Ping::new([i; 4]);
+///     let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+///     line.extend_from_slice(&send_buf[..n]);
+/// }
+///
+/// let mut recv_buf = vec![0; 16];
+/// let mut receiver = server::Receiver::new(&mut recv_buf);
+/// let mut pings = Vec::new();
+/// let mut errors = 0;
+/// // The bytes arrive in chunks of 3 bytes
+/// for chunk in line.chunks(3) {
+///     let mut pos = 0;
+///     while pos < chunk.len() {
+///         let (n, result) = receiver.push_slice(&mut rpc_server, &chunk[pos..]);
+///         pos += n;
+///         match result {
+///             Some(Ok(server::ParseResult::Request((header, buf)))) => {
+///                 match ServerRequest::from_bytes(header, buf).unwrap() {
+///                     ServerRequest::Ping(ping) => pings.push(ping.body),
+///                     _ => panic!("unexpected request"),
+///                 }
+///             }
+///             Some(Ok(r)) => panic!("unexpected parse result: {:?}", r),
+///             Some(Err(err)) => {
+///                 assert_eq!(err.code(), Some(ErrorCode::TooLong));
+///                 errors += 1;
+///             }
+///             None => {}
+///         }
+///     }
+/// }
+/// assert_eq!(pings, [[0; 4], [1; 4]]);
+/// assert_eq!(errors, 1);
+/// ```
+pub struct Receiver<'a> {
+    packet: PacketBuf<'a>,
+}
+
+impl<'a> Receiver<'a> {
+    /// Create a receiver that buffers the request packets in `buf`, which must hold at least a
+    /// request header.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            packet: PacketBuf::new(buf, REQ_HEADER_LEN),
         }
     }
 
-    /// Parse incoming bytes and return wether a request has been received, or more bytes are
-    /// needed to build a complete request.
-    pub fn parse<'a>(
+    /// Drop any partially received request, in the receiver and in `rpc_server`.
+    pub fn reset(&mut self, rpc_server: &mut RpcServer) {
+        self.packet.reset();
+        rpc_server.reset();
+    }
+
+    /// Push received bytes, parsing them with `rpc_server` until the end of a request.  Returns
+    /// the number of bytes consumed, and the result of [`RpcServer::parse`] for the request if
+    /// it's complete or rejected.  The bytes left, which may hold the following requests, have
+    /// to be pushed again.
+    pub fn push_slice(
         &mut self,
-        rcv_buf: &'a [u8],
-    ) -> Result<ParseResult<(RequestHeader, &'a [u8])>> {
-        loop {
-            let mut state = State::WaitHeader;
-            swap(&mut state, &mut self.state);
-            match state {
-                State::WaitHeader => {
-                    let req_header = req_header_from_bytes(&rcv_buf)?;
-                    if req_header.body_len >= self.max_buf_len {
-                        // TODO: Make custom error
-                        return Err(postcard::Error::WontImplement);
+        rpc_server: &mut RpcServer,
+        bytes: &[u8],
+    ) -> (usize, Option<Result<ParsedRequest<'_>>>) {
+        let (mut pos, full) = self.packet.fill(bytes);
+        if !full {
+            return (pos, None);
+        }
+        if self.packet.is_header() {
+            let header_buf = &self.packet.buf[..REQ_HEADER_LEN];
+            let rest_len = req_header_from_bytes(header_buf)
+                .map(|header| {
+                    let crc_len = if header.is_crc() { CRC_LEN } else { 0 };
+                    header.body_len() + header.buf_len() + crc_len
+                })
+                .unwrap_or(0);
+            let result = rpc_server.parse(header_buf).map(|result| match result {
+                ParseResult::NeedBytes(n) => ParseResult::NeedBytes(n),
+                ParseResult::Request((header, _)) => ParseResult::Request(header),
+                ParseResult::Cancel(chan_id) => ParseResult::Cancel(chan_id),
+            });
+            match result {
+                Ok(ParseResult::NeedBytes(n)) => {
+                    if !self.packet.expect(n) {
+                        rpc_server.reset();
+                        let max = self.packet.max_len();
+                        self.packet.reject(n);
+                        return (pos, Some(Err(Error::BodyTooLong { len: n, max })));
                     }
-                    if req_header.buf_len >= self.max_buf_len {
-                        // TODO: Make custom error
-                        return Err(postcard::Error::WontImplement);
+                    let (n, full) = self.packet.fill(&bytes[pos..]);
+                    pos += n;
+                    if !full {
+                        return (pos, None);
                     }
-                    let req_header_body_len = req_header.body_len;
-                    let req_header_buf_len = req_header.buf_len;
-                    if req_header_body_len + req_header_buf_len == 0 {
-                        // let req = R::from_bytes(req_header, &[]);
-                        self.state = State::WaitHeader;
-                        return Ok(ParseResult::Request((req_header, &[])));
-                    } else {
-                        let ret =
-                            ParseResult::NeedBytes(req_header.body_len() + req_header.buf_len());
-                        self.state = State::WaitBody(req_header);
-                        return Ok(ret);
+                }
+                Ok(ParseResult::Request(header)) => {
+                    self.packet.done = true;
+                    return (pos, Some(Ok(ParseResult::Request((header, &[])))));
+                }
+                Ok(ParseResult::Cancel(chan_id)) => {
+                    self.packet.done = true;
+                    return (pos, Some(Ok(ParseResult::Cancel(chan_id))));
+                }
+                Err(err) => {
+                    self.packet.reject(rest_len);
+                    return (pos, Some(Err(err)));
+                }
+            }
+        }
+        self.packet.done = true;
+        let packet = self.packet.packet();
+        (pos, Some(rpc_server.parse(&packet[REQ_HEADER_LEN..])))
+    }
+
+    /// Push received bytes like [`Receiver::push_slice`], and serve the request they complete
+    /// like [`dispatch`], serializing its replies or its error reply into `reply_buf`.  Returns
+    /// the number of bytes consumed, and the result of [`dispatch`] if a request is complete or
+    /// rejected.
+    pub fn dispatch<D: Dispatch<H>, H: ?Sized>(
+        &mut self,
+        rpc_server: &mut RpcServer,
+        handler: &mut H,
+        bytes: &[u8],
+        reply_buf: &mut [u8],
+    ) -> (usize, Option<Result<ParseResult<usize>>>) {
+        let (n, parsed) = self.push_slice(rpc_server, bytes);
+        let result =
+            parsed.map(|parsed| dispatch_parsed::<D, H>(rpc_server, handler, parsed, reply_buf));
+        (n, result)
+    }
+}
+
+/// What a server runtime reads next from its byte stream, returned by [`serve_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg(any(feature = "std", feature = "embedded-io", feature = "embedded-hal-nb"))]
+pub(crate) enum Next {
+    /// Read the next `n` bytes of the request.
+    Read(usize),
+    /// The request is over: skip the `n` bytes left of it and read the next request header.
+    Skip(usize),
+}
+
+/// Parse the bytes of a request read by a server runtime into `rcv_buf`, whose length is the
+/// length of the runtime receive buffer, and serve the request they complete with `handler`.
+/// The replies, or the [`ErrorCode`] of a request that can't be handled, are sent with `writer`.
+/// Requests that don't fit in the receive buffer are rejected before reading them.
+#[cfg(any(feature = "std", feature = "embedded-io", feature = "embedded-hal-nb"))]
+pub(crate) fn serve_bytes<D: Dispatch<H>, H: ?Sized>(
+    rpc_server: &mut RpcServer,
+    handler: &mut H,
+    rcv_buf: &[u8],
+    buf_len: usize,
+    writer: &mut dyn ReplyWrite,
+) -> Result<Next> {
+    // Length of the rest of the request, to skip it if the request is rejected before reading
+    // it.
+    let skip_len = match rpc_server.state {
+        State::WaitHeader => {
+            let req_header = req_header_from_bytes(rcv_buf).map_err(Error::Deserialize)?;
+            let crc_len = if req_header.is_crc() { CRC_LEN } else { 0 };
+            req_header.body_len() + req_header.buf_len() + crc_len
+        }
+        State::WaitBody(..) => 0,
+    };
+    let result = match rpc_server.parse(rcv_buf) {
+        Ok(ParseResult::NeedBytes(n)) if n <= buf_len => return Ok(Next::Read(n)),
+        Ok(ParseResult::NeedBytes(n)) => {
+            rpc_server.reset();
+            Err(Error::BodyTooLong {
+                len: n,
+                max: buf_len,
+            })
+        }
+        Ok(ParseResult::Request((header, buf))) => {
+            dispatch_request::<D, H>(handler, rpc_server.max_buf_len, header, buf, writer)
+        }
+        Ok(ParseResult::Cancel(_)) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        let code = err.code().ok_or(err)?;
+        if let Some(n) = rpc_server.reply_error_code(code, writer.buf())? {
+            writer.send(n)?;
+        }
+        return Ok(Next::Skip(skip_len));
+    }
+    Ok(Next::Skip(0))
+}
+
+#[cfg(feature = "std")]
+use crate::timeout::{ReadError, TimedReader, Timeout, Timeouts};
+#[cfg(feature = "std")]
+use std::io;
+
+/// Main component of a blocking RPC Server over a stream.  It reads the requests from the
+/// stream, calls their handler methods and writes back the replies, replying with an
+/// [`ErrorCode`] to the requests that can't be handled.
+///
+/// # Examples
+///
+/// ```
+/// use urpc::{client, consts, server::{self, Items}, server_requests};
+/// use std::io::{self, Cursor, Read, Write};
+///
+/// mod cli {
+///     use urpc::client_requests;
+///
+///     client_requests! {
+///         client_requests;
+///         (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///         (2, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
+///     }
+/// }
+///
+/// server_requests! {
+///     ServerRequests;
+///     (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+///     (1, send_bytes, SendBytes((), OptBufYes, usize, OptBufNo)),
+///     (2, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
+/// }
+///
+/// struct Server;
+///
+/// impl Handler for Server {
+///     fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+///         Ok(body)
+///     }
+///     fn send_bytes(&mut self, _body: (), buf: &[u8]) -> Result<usize, ()> {
+///         Ok(buf.len())
+///     }
+///     fn samples(&mut self, body: u8, items: &mut Items<u16>) -> Result<(), ()> {
+///         for i in 0..body as u16 {
+///             items.send(i * 100).ok();
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// // Stream that reads the requests from a buffer and writes the replies into another one
+/// struct Pipe(Cursor<Vec<u8>>, Vec<u8>);
+/// impl Read for Pipe {
+///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+/// }
+/// impl Write for Pipe {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.1.write(buf) }
+///     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut send_buf = vec![0; 32];
+/// let mut requests = Vec::new();
+/// let mut ping = cli::Ping::new([0, 1, 2, 3]);
+/// let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+/// requests.extend_from_slice(&send_buf[..n]);
+/// let mut samples = cli::Samples::new(3);
+/// let n = samples.request(&mut rpc_client, &mut send_buf).unwrap();
+/// requests.extend_from_slice(&send_buf[..n]);
+///
+/// let mut rpc_server = server::RpcServerIO::new(Pipe(Cursor::new(requests), Vec::new()), 32);
+/// rpc_server.run::<ServerRequests, _>(&mut Server).unwrap();
+///
+/// let Pipe(_, replies) = rpc_server.into_inner();
+/// let mut pos = 0;
+/// let mut read_len = consts::REP_HEADER_LEN;
+/// while pos < replies.len() {
+///     let buf = &replies[pos..pos + read_len];
+///     pos += read_len;
+///     read_len = rpc_client.parse(buf).unwrap().0;
+/// }
+/// assert_eq!(ping.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+/// let items: Vec<_> = std::iter::from_fn(|| samples.take_item(&mut rpc_client))
+///     .map(|item| item.unwrap())
+///     .collect();
+/// assert_eq!(items, [0, 100, 200]);
+/// ```
+#[cfg(feature = "std")]
+pub struct RpcServerIO<S: io::Read + io::Write> {
+    pub server: RpcServer,
+    stream: S,
+    pub stream_buf: Vec<u8>,
+    pub reply_buf: Vec<u8>,
+    timeouts: Timeouts,
+}
+
+/// Error of an RPC Server serving over a stream.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum RpcServerIOError {
+    Io(io::Error),
+    Urpc(Error),
+    /// A timeout expired while receiving a request, which has been dropped.
+    Timeout(Timeout),
+}
+
+/// Result of an RPC Server serving over a stream.
+#[cfg(feature = "std")]
+pub type RpcServerIOResult<T> = core::result::Result<T, RpcServerIOError>;
+
+#[cfg(feature = "std")]
+impl From<io::Error> for RpcServerIOError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for RpcServerIOError {
+    fn from(err: Error) -> Self {
+        Self::Urpc(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ReadError> for RpcServerIOError {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Io(err) => Self::Io(err),
+            ReadError::Timeout(timeout) => Self::Timeout(timeout),
+        }
+    }
+}
+
+/// [`ReplyWrite`] that writes the replies to a stream, keeping the error of the stream.
+#[cfg(feature = "std")]
+struct StreamReplyWrite<'a, S: io::Write> {
+    stream: &'a mut S,
+    buf: &'a mut [u8],
+    err: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, S: io::Write> ReplyWrite for StreamReplyWrite<'a, S> {
+    fn buf(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    fn send(&mut self, len: usize) -> Result<()> {
+        let result = self
+            .stream
+            .write_all(&self.buf[..len])
+            .and_then(|()| self.stream.flush());
+        result.map_err(|err| {
+            self.err = Some(err);
+            Error::Write
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: io::Read + io::Write> RpcServerIO<S> {
+    pub fn new(stream: S, buf_len: usize) -> Self {
+        Self {
+            server: RpcServer::new(buf_len as u16),
+            stream,
+            stream_buf: vec![0; buf_len],
+            reply_buf: vec![0; buf_len],
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Set the timeouts to receive a request, which are disabled by default.  A request that
+    /// times out is dropped, and [`RpcServerIO::serve`] returns
+    /// [`RpcServerIOError::Timeout`].  See the [`timeout`](../timeout/index.html) module for
+    /// the read timeout that the stream needs.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Consume the RPC Server and return its stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Serve requests with `handler` until the stream ends.  `D` is the requests enum built with
+    /// [`server_requests`](../macro.server_requests.html).
+    pub fn run<D: Dispatch<H>, H: ?Sized>(&mut self, handler: &mut H) -> RpcServerIOResult<()> {
+        while self.serve::<D, H>(handler)? {}
+        Ok(())
+    }
+
+    /// Read one request from the stream and serve it with `handler`, or reply it if it's a
+    /// built-in method (see [`dispatch`]).  Returns false if the stream ended before the
+    /// request.
+    pub fn serve<D: Dispatch<H>, H: ?Sized>(&mut self, handler: &mut H) -> RpcServerIOResult<bool> {
+        let mut reader = TimedReader::new(self.timeouts);
+        match reader.read_exact(&mut self.stream, &mut self.stream_buf[..REQ_HEADER_LEN]) {
+            Err(ReadError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(false)
+            }
+            result => result?,
+        }
+        let mut read_len = REQ_HEADER_LEN;
+        loop {
+            let mut writer = StreamReplyWrite {
+                stream: &mut self.stream,
+                buf: &mut self.reply_buf,
+                err: None,
+            };
+            let result = serve_bytes::<D, H>(
+                &mut self.server,
+                handler,
+                &self.stream_buf[..read_len],
+                self.stream_buf.len(),
+                &mut writer,
+            );
+            if let Some(err) = writer.err {
+                return Err(err.into());
+            }
+            match result? {
+                Next::Read(n) => {
+                    let result = reader.read_exact(&mut self.stream, &mut self.stream_buf[..n]);
+                    if result.is_err() {
+                        self.server.reset();
                     }
+                    result?;
+                    read_len = n;
                 }
-                State::WaitBody(req_header) => {
-                    // let req = R::from_bytes(req_header, &rcv_buf[..]);
-                    self.state = State::WaitHeader;
-                    return Ok(ParseResult::Request((req_header, &rcv_buf[..])));
+                Next::Skip(n) => {
+                    reader.skip(&mut self.stream, n)?;
+                    break;
                 }
             }
         }
+        Ok(true)
     }
 }
@@ -0,0 +1,139 @@
+//! A peer that stops sending in the middle of a packet leaves the blocking runtimes over streams
+//! waiting forever for the rest of it.  The runtimes can abort the partial packet after one of
+//! these timeouts, resetting their parser so that the next bytes are read as a new packet.
+//!
+//! The runtimes check the timeouts when a read from the stream fails with `TimedOut` or
+//! `WouldBlock` (see [`io::ErrorKind`](std::io::ErrorKind)), so the stream needs a read timeout
+//! shorter than the configured timeouts, like the one set by
+//! [`TcpStream::set_read_timeout`](std::net::TcpStream::set_read_timeout).  Those read errors
+//! are retried while no timeout has expired.  Without configured timeouts, they are returned
+//! to the caller.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Timeouts of a blocking runtime over a stream.  A timeout set to None never expires.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timeouts {
+    /// Maximum time between two bytes of a packet.
+    pub inter_byte: Option<Duration>,
+    /// Maximum time to receive a whole request, from its first byte, in the server.  Maximum
+    /// time to wait for the reply of a request in the client.
+    pub request: Option<Duration>,
+}
+
+/// Timeout that expired while receiving a packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timeout {
+    /// No byte of the packet was received for [`Timeouts::inter_byte`].
+    InterByte,
+    /// The packet wasn't complete after [`Timeouts::request`].
+    Request,
+}
+
+/// Error of a [`TimedReader`].
+pub(crate) enum ReadError {
+    Io(io::Error),
+    Timeout(Timeout),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Reader of the packets of a stream that enforces the timeouts.  The request timeout starts
+/// with the first byte read, or when [`TimedReader::start`] is called.
+pub(crate) struct TimedReader {
+    timeouts: Timeouts,
+    start: Option<Instant>,
+    /// Time of the last byte read of the packet being received.
+    last: Option<Instant>,
+}
+
+impl TimedReader {
+    pub(crate) fn new(timeouts: Timeouts) -> Self {
+        Self {
+            timeouts,
+            start: None,
+            last: None,
+        }
+    }
+
+    /// Start the request timeout now.
+    pub(crate) fn start(&mut self) {
+        self.start = Some(Instant::now());
+    }
+
+    /// End the packet being received, so that the time until the next packet doesn't count
+    /// for the inter byte timeout.
+    pub(crate) fn end_packet(&mut self) {
+        self.last = None;
+    }
+
+    fn check(&self, now: Instant) -> Result<(), Timeout> {
+        let expired = |timeout: Option<Duration>, since: Option<Instant>| match (timeout, since) {
+            (Some(timeout), Some(since)) => now.duration_since(since) > timeout,
+            _ => false,
+        };
+        if expired(self.timeouts.inter_byte, self.last) {
+            return Err(Timeout::InterByte);
+        }
+        if expired(self.timeouts.request, self.start) {
+            return Err(Timeout::Request);
+        }
+        Ok(())
+    }
+
+    /// Read exactly `buf.len()` bytes from `stream`, like [`io::Read::read_exact`], unless a
+    /// timeout expires.
+    pub(crate) fn read_exact<R: io::Read>(
+        &mut self,
+        stream: &mut R,
+        buf: &mut [u8],
+    ) -> Result<(), ReadError> {
+        let timed = self.timeouts != Timeouts::default();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let result = stream.read(&mut buf[pos..]);
+            let now = Instant::now();
+            match result {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.check(now).map_err(ReadError::Timeout)?;
+                    pos += n;
+                    self.start.get_or_insert(now);
+                    self.last = Some(now);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err)
+                    if timed
+                        && matches!(
+                            err.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        ) =>
+                {
+                    self.check(now).map_err(ReadError::Timeout)?
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and drop `n` bytes from `stream`, unless a timeout expires.
+    pub(crate) fn skip<R: io::Read>(
+        &mut self,
+        stream: &mut R,
+        mut n: usize,
+    ) -> Result<(), ReadError> {
+        let mut buf = [0; 16];
+        while n > 0 {
+            let len = n.min(buf.len());
+            self.read_exact(stream, &mut buf[..len])?;
+            n -= len;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use urpc::{
+    client::{self, RpcClient},
+    consts, server,
+    server::Request,
+    server_requests,
+};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+        (1, count, Count(u8, OptBufNo, u8, OptBufNo) stream)
+    }
+}
+
+server_requests! {
+    ServerRequests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    (1, count, Count(u8, OptBufNo, u8, OptBufNo) stream)
+}
+
+const BUF_LEN: usize = 32;
+
+/// Serve the request serialized in `request`, and return its replies.
+fn serve(request: &[u8]) -> Vec<u8> {
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let mut reply_buf = [0; BUF_LEN];
+    let (header, body) = request.split_at(consts::REQ_HEADER_LEN);
+    match ServerRequests::from_rpc(&mut rpc_server, header).unwrap() {
+        server::ParseResult::NeedBytes(n) => assert_eq!(n, body.len()),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    let mut replies = Vec::new();
+    match ServerRequests::from_rpc(&mut rpc_server, body).unwrap() {
+        server::ParseResult::Request(ServerRequests::Ping(ping)) => {
+            let body = ping.body;
+            let n = ping.reply(body, &mut reply_buf).unwrap();
+            replies.extend_from_slice(&reply_buf[..n]);
+        }
+        server::ParseResult::Request(ServerRequests::Count(count)) => {
+            for i in 0..count.body {
+                let n = count.reply_item(i, &mut reply_buf).unwrap();
+                replies.extend_from_slice(&reply_buf[..n]);
+            }
+            let n = count.end(&mut reply_buf).unwrap();
+            replies.extend_from_slice(&reply_buf[..n]);
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+    replies
+}
+
+/// Parse `replies` into `rpc_client`, returning the channel of each reply that was kept.
+fn receive(rpc_client: &mut RpcClient, replies: &[u8]) -> Vec<Option<u8>> {
+    let mut chan_ids = Vec::new();
+    let mut pos = 0;
+    while pos < replies.len() {
+        let header = &replies[pos..pos + consts::REP_HEADER_LEN];
+        pos += header.len();
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize
+            + u16::from_le_bytes([header[4], header[5]]) as usize;
+        let (_, mut chan_id) = rpc_client.parse(header).unwrap();
+        if len != 0 {
+            chan_id = rpc_client.parse(&replies[pos..pos + len]).unwrap().1;
+            pos += len;
+        }
+        chan_ids.push(chan_id);
+    }
+    chan_ids
+}
+
+#[test]
+fn late_reply_dropped() {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut ping = cli::Ping::new([1, 2, 3, 4]);
+    let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+    let replies = serve(&send_buf[..n]);
+
+    assert!(ping.cancel(&mut rpc_client, &mut send_buf).unwrap() != 0);
+    assert_eq!(rpc_client.in_flight(), 0);
+    // The reply sent before the server got the cancel is dropped
+    assert_eq!(receive(&mut rpc_client, &replies), [None]);
+    assert!(!rpc_client.has_reply(ping.chan_id()));
+    assert!(ping.take_reply(&mut rpc_client).is_none());
+}
+
+#[test]
+fn late_stream_items_dropped() {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut count = cli::Count::new(3);
+    let n = count.request(&mut rpc_client, &mut send_buf).unwrap();
+    let replies = serve(&send_buf[..n]);
+
+    count.cancel(&mut rpc_client, &mut send_buf).unwrap();
+    assert!(count.is_done());
+    // Three items and the reply that ends the stream
+    assert_eq!(receive(&mut rpc_client, &replies), [None; 4]);
+    assert!(count.take_item(&mut rpc_client).is_none());
+
+    // The channel is free once the stream ends, so a further reply is unexpected
+    match rpc_client.parse(&replies[..consts::REP_HEADER_LEN]) {
+        Err(client::Error::UnexpectedChanId(chan_id)) if chan_id == count.chan_id() => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn chan_reused_after_cancel() {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut pings: Vec<_> = (0..client::MAX_IN_FLIGHT)
+        .map(|_| {
+            let mut ping = cli::Ping::new([0; 4]);
+            ping.request(&mut rpc_client, &mut send_buf).unwrap();
+            ping
+        })
+        .collect();
+
+    // The channels of the requests waiting for a reply are never reused
+    match cli::Ping::new([0; 4]).request(&mut rpc_client, &mut send_buf) {
+        Err(client::Error::NoFreeChanId) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let cancelled = &mut pings[7];
+    let chan_id = cancelled.chan_id();
+    cancelled.cancel(&mut rpc_client, &mut send_buf).unwrap();
+    let mut ping = cli::Ping::new([4; 4]);
+    let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+    assert_eq!(ping.chan_id(), chan_id);
+
+    // The channel now belongs to the new request
+    let replies = serve(&send_buf[..n]);
+    assert_eq!(receive(&mut rpc_client, &replies), [Some(chan_id)]);
+    assert_eq!(ping.take_reply(&mut rpc_client).unwrap().unwrap(), [4; 4]);
+}
+
+#[test]
+fn free_chan_preferred_over_cancelled() {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut first = cli::Ping::new([0; 4]);
+    first.request(&mut rpc_client, &mut send_buf).unwrap();
+    first.cancel(&mut rpc_client, &mut send_buf).unwrap();
+
+    // Free channels are used first, so that late replies of the cancelled request are dropped
+    let mut chan_ids = Vec::new();
+    for _ in 1..client::MAX_IN_FLIGHT {
+        let mut ping = cli::Ping::new([0; 4]);
+        ping.request(&mut rpc_client, &mut send_buf).unwrap();
+        chan_ids.push(ping.chan_id());
+    }
+    assert!(!chan_ids.contains(&first.chan_id()));
+}
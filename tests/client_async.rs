@@ -0,0 +1,212 @@
+#![cfg(feature = "async")]
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::{executor::block_on, io::AsyncWrite, io::Cursor, join, poll};
+use urpc::{
+    client::{self, RpcClientIOError},
+    client_async::RpcClientAsync,
+    consts,
+};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+        (1, reset, Reset((), OptBufNo, (), OptBufNo)),
+        (2, count, Count(u8, OptBufNo, u8, OptBufNo) stream)
+    }
+}
+
+/// Writer that keeps the written bytes, or fails every write if `broken`.
+#[derive(Clone, Default)]
+struct Line {
+    written: Arc<Mutex<Vec<u8>>>,
+    broken: bool,
+}
+
+impl AsyncWrite for Line {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.broken {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reply to a ping in channel `chan_id`.
+fn pong(chan_id: u8, body: [u8; 4]) -> Vec<u8> {
+    let mut reply = vec![chan_id, 0x00, 0x04, 0x00, 0x00, 0x00];
+    reply.extend_from_slice(&body);
+    reply
+}
+
+const BUF_LEN: usize = 32;
+
+#[test]
+fn concurrent_calls_out_of_order() {
+    let replies = [pong(3, [8; 4]), pong(1, [0; 4]), pong(2, [4; 4])].concat();
+    let (rpc_client, reader) =
+        RpcClientAsync::from_split(Cursor::new(replies), Line::default(), BUF_LEN);
+
+    let (r1, r2, r3, _) = block_on(async {
+        join!(
+            cli::Ping::new([0; 4]).call_async(&rpc_client),
+            cli::Ping::new([4; 4]).call_async(&rpc_client),
+            cli::Ping::new([8; 4]).call_async(&rpc_client),
+            reader,
+        )
+    });
+    assert_eq!(r1.unwrap(), [0; 4]);
+    assert_eq!(r2.unwrap(), [4; 4]);
+    assert_eq!(r3.unwrap(), [8; 4]);
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
+
+#[test]
+fn rejected_reply_fails_its_call() {
+    let replies = [
+        // Reply in a channel without a request
+        pong(9, [0xff; 4]),
+        // Reply with an unexpected optional buffer
+        vec![
+            0x02, 0x00, 0x04, 0x00, 0x03, 0x00, 4, 4, 4, 4, 0xaa, 0xbb, 0xcc,
+        ],
+        pong(1, [0; 4]),
+    ]
+    .concat();
+    let (rpc_client, reader) =
+        RpcClientAsync::from_split(Cursor::new(replies), Line::default(), BUF_LEN);
+
+    let (r1, r2, r) = block_on(async {
+        join!(
+            cli::Ping::new([0; 4]).call_async(&rpc_client),
+            cli::Ping::new([4; 4]).call_async(&rpc_client),
+            reader,
+        )
+    });
+    assert_eq!(r1.unwrap(), [0; 4]);
+    match r2 {
+        Err(RpcClientIOError::Urpc(client::Error::ReplyOptBufUnexpected)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    // The reader only stops at the end of the stream
+    match r {
+        Err(RpcClientIOError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
+
+#[test]
+fn closed_stream_fails_calls() {
+    let (rpc_client, reader) =
+        RpcClientAsync::from_split(Cursor::new(Vec::new()), Line::default(), BUF_LEN);
+
+    let (r1, _) = block_on(async { join!(cli::Ping::new([0; 4]).call_async(&rpc_client), reader) });
+    match r1 {
+        Err(RpcClientIOError::Io(err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
+
+#[test]
+fn dropped_call_is_cancelled() {
+    let line = Line::default();
+    let (rpc_client, _reader) =
+        RpcClientAsync::from_split(Cursor::new(Vec::new()), line.clone(), BUF_LEN);
+
+    block_on(async {
+        let mut call = Box::pin(cli::Ping::new([0; 4]).call_async(&rpc_client));
+        assert!(poll!(&mut call).is_pending());
+        assert_eq!(rpc_client.with_client(|client| client.in_flight()), 1);
+        drop(call);
+        assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+
+        // The cancel packet is sent before the next request
+        let request_len = line.written.lock().unwrap().len();
+        let mut call = Box::pin(cli::Ping::new([4; 4]).call_async(&rpc_client));
+        assert!(poll!(&mut call).is_pending());
+        let written = line.written.lock().unwrap();
+        assert_eq!(written.len(), 2 * request_len + consts::REQ_HEADER_LEN);
+        let cancel = &written[request_len..request_len + consts::REQ_HEADER_LEN];
+        // Channel 1 with the cancel option
+        assert_eq!(&cancel[1..3], &[1, 0x08]);
+    });
+}
+
+#[test]
+fn failed_send_releases_chan() {
+    let line = Line {
+        broken: true,
+        ..Line::default()
+    };
+    let (rpc_client, _reader) = RpcClientAsync::from_split(Cursor::new(Vec::new()), line, BUF_LEN);
+
+    match block_on(cli::Ping::new([0; 4]).call_async(&rpc_client)) {
+        Err(RpcClientIOError::Io(err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
+
+#[test]
+fn late_empty_reply_dropped() {
+    let replies = [
+        // Late reply of the cancelled reset, without body
+        vec![0x01, 0x04, 0x00, 0x00, 0x00, 0x00],
+        pong(2, [9; 4]),
+    ]
+    .concat();
+    let (rpc_client, reader) =
+        RpcClientAsync::from_split(Cursor::new(replies), Line::default(), BUF_LEN);
+
+    let (r, _) = block_on(async {
+        let mut reset = Box::pin(cli::Reset::new(()).call_async(&rpc_client));
+        assert!(poll!(&mut reset).is_pending());
+        drop(reset);
+        join!(cli::Ping::new([9; 4]).call_async(&rpc_client), reader)
+    });
+    assert_eq!(r.unwrap(), [9; 4]);
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
+
+#[test]
+fn late_stream_end_dropped() {
+    let replies = [
+        // Late item and end of the cancelled stream
+        vec![0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05],
+        vec![0x01, 0x04, 0x00, 0x00, 0x00, 0x00],
+        pong(2, [9; 4]),
+    ]
+    .concat();
+    let (rpc_client, reader) =
+        RpcClientAsync::from_split(Cursor::new(replies), Line::default(), BUF_LEN);
+
+    let (r, _) = block_on(async {
+        let count = cli::Count::new(3).call_async(&rpc_client).await.unwrap();
+        drop(count);
+        join!(cli::Ping::new([9; 4]).call_async(&rpc_client), reader)
+    });
+    assert_eq!(r.unwrap(), [9; 4]);
+    assert_eq!(rpc_client.with_client(|client| client.in_flight()), 0);
+}
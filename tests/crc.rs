@@ -0,0 +1,132 @@
+use urpc::{
+    client::{self, MethodError, RpcClient},
+    consts, server,
+    server::Request,
+    server_requests,
+};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+    }
+}
+
+server_requests! {
+    ServerRequests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+}
+
+const BUF_LEN: usize = 32;
+
+/// Serve the request serialized in `request`, and return its reply.
+fn serve(request: &[u8]) -> Vec<u8> {
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let mut reply_buf = [0; BUF_LEN];
+    let (header, body) = request.split_at(consts::REQ_HEADER_LEN);
+    match ServerRequests::from_rpc(&mut rpc_server, header).unwrap() {
+        server::ParseResult::NeedBytes(n) => assert_eq!(n, body.len()),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    let n = match ServerRequests::from_rpc(&mut rpc_server, body) {
+        Ok(server::ParseResult::Request(ServerRequests::Ping(ping))) => {
+            let body = ping.body;
+            ping.reply(body, &mut reply_buf).unwrap()
+        }
+        // The request is rejected, but its header was parsed
+        Err(err) => rpc_server
+            .reply_error_code(err.code().unwrap(), &mut reply_buf)
+            .unwrap()
+            .unwrap(),
+        r => panic!("unexpected result: {:?}", r),
+    };
+    reply_buf[..n].to_vec()
+}
+
+/// Parse the reply in `reply` into `rpc_client`.
+fn receive(rpc_client: &mut RpcClient, reply: &[u8]) -> client::Result<Option<u8>> {
+    let (header, body) = reply.split_at(consts::REP_HEADER_LEN);
+    let (n, _) = rpc_client.parse(header)?;
+    assert_eq!(n, body.len());
+    Ok(rpc_client.parse(body)?.1)
+}
+
+/// Send a ping whose request packet is altered by `corrupt`, and return its reply.
+fn ping_corrupted<F: FnOnce(&mut [u8])>(corrupt: F) -> Result<[u8; 4], MethodError<()>> {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    rpc_client.set_crc(true);
+    let mut send_buf = [0; BUF_LEN];
+    let mut ping = cli::Ping::new([0, 1, 2, 3]);
+    let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+    corrupt(&mut send_buf[..n]);
+
+    let reply = serve(&send_buf[..n]);
+    assert_eq!(
+        receive(&mut rpc_client, &reply).unwrap(),
+        Some(ping.chan_id())
+    );
+    ping.take_reply(&mut rpc_client).unwrap()
+}
+
+#[test]
+fn request_body_corrupted() {
+    match ping_corrupted(|request| request[consts::REQ_HEADER_LEN] ^= 0x01) {
+        Err(MethodError::Client(client::Error::Resend)) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn request_trailer_corrupted() {
+    match ping_corrupted(|request| *request.last_mut().unwrap() ^= 0x80) {
+        Err(MethodError::Client(client::Error::Resend)) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn request_intact() {
+    assert_eq!(ping_corrupted(|_| {}).unwrap(), [0, 1, 2, 3]);
+}
+
+/// Send a ping and receive its reply altered by `corrupt`, which must be rejected.  The intact
+/// reply is accepted afterwards.
+fn reply_corrupted<F: FnOnce(&mut [u8])>(corrupt: F) {
+    let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+    rpc_client.set_crc(true);
+    let mut send_buf = [0; BUF_LEN];
+    let mut ping = cli::Ping::new([0, 1, 2, 3]);
+    let n = ping.request(&mut rpc_client, &mut send_buf).unwrap();
+    let reply = serve(&send_buf[..n]);
+    assert_eq!(reply.len(), consts::REP_HEADER_LEN + 4 + consts::CRC_LEN);
+
+    let mut corrupted = reply.clone();
+    corrupt(&mut corrupted);
+    match receive(&mut rpc_client, &corrupted) {
+        Err(client::Error::ChecksumMismatch) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(!rpc_client.has_reply(ping.chan_id()));
+
+    // The request still waits for its reply
+    assert_eq!(
+        receive(&mut rpc_client, &reply).unwrap(),
+        Some(ping.chan_id())
+    );
+    assert_eq!(
+        ping.take_reply(&mut rpc_client).unwrap().unwrap(),
+        [0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn reply_body_corrupted() {
+    reply_corrupted(|reply| reply[consts::REP_HEADER_LEN + 2] ^= 0x10);
+}
+
+#[test]
+fn reply_trailer_corrupted() {
+    reply_corrupted(|reply| *reply.last_mut().unwrap() ^= 0x01);
+}
@@ -0,0 +1,184 @@
+use urpc::{
+    client::{self, RpcClient},
+    consts,
+    framing::{self, Decoder, Encoder},
+    server::{self, RpcServer},
+};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo))
+    }
+}
+
+/// Data bytes of a full COBS block, which has no implicit zero.
+const FULL_BLOCK: usize = 254;
+
+/// Encode `packet` and decode it back, checking the frame is delimited and holds no zero bytes.
+fn round_trip(packet: &[u8]) {
+    let mut frame = vec![0; framing::max_encoded_len(packet.len())];
+    let frame_len = framing::encode(packet, &mut frame).unwrap();
+    let frame = &frame[..frame_len];
+    assert_eq!(frame.last(), Some(&framing::DELIMITER));
+    assert!(!frame[..frame_len - 1].contains(&framing::DELIMITER));
+
+    let mut decoded = vec![0; packet.len()];
+    let n = framing::decode(frame, &mut decoded).unwrap();
+    assert_eq!(&decoded[..n], packet);
+}
+
+#[test]
+fn round_trip_zeros() {
+    round_trip(&[0x11, 0x22, 0x00, 0x33]);
+    round_trip(&[0x00, 0x00, 0x11, 0x00]);
+    round_trip(&[0x11, 0x00]);
+}
+
+#[test]
+fn full_block_followed_by_data() {
+    let block: Vec<u8> = (1..=FULL_BLOCK as u8).collect();
+    round_trip(&block);
+    for tail in [&[0x01][..], &[0x00], &[0x00, 0x02], &[0x03, 0x00, 0x04]] {
+        round_trip(&[&block[..], tail].concat());
+    }
+    // Two full blocks back to back
+    round_trip(&[&block[..], &block[..]].concat());
+    round_trip(&[&block[..], &[0x00], &block[..]].concat());
+}
+
+#[test]
+fn max_encoded_len_is_enough() {
+    // Packets without zero bytes have the longest frames
+    for len in 0..4 * FULL_BLOCK {
+        let packet: Vec<u8> = (0..len).map(|i| (i % 0xff) as u8 + 1).collect();
+        let mut frame = vec![0; framing::max_encoded_len(len)];
+        assert!(framing::encode(&packet, &mut frame).is_ok(), "len {}", len);
+    }
+}
+
+#[test]
+fn encoder_buf_too_short() {
+    let packet = [0x11, 0x22, 0x00, 0x33];
+    let len = framing::max_encoded_len(packet.len());
+    for dst_len in 0..len - 1 {
+        let mut frame = vec![0; dst_len];
+        assert_eq!(
+            framing::encode(&packet, &mut frame),
+            Err(framing::Error::BufTooShort),
+            "dst len {}",
+            dst_len
+        );
+    }
+}
+
+#[test]
+fn decoder_buf_too_short() {
+    let mut line = vec![0; 64];
+    let mut n = framing::encode(&[0x11, 0x22, 0x33, 0x44], &mut line).unwrap();
+    n += framing::encode(&[0x55, 0x66], &mut line[n..]).unwrap();
+
+    let mut buf = [0; 3];
+    let mut decoder = Decoder::new(&mut buf);
+    // The frame that doesn't fit is dropped, and the next one is decoded
+    let (m, frame) = decoder.push_slice(&line[..n]);
+    assert_eq!(frame, Some(Err(framing::Error::BufTooShort)));
+    let (_, frame) = decoder.push_slice(&line[m..n]);
+    assert_eq!(frame, Some(Ok(2)));
+    assert_eq!(decoder.frame(), [0x55, 0x66]);
+
+    let mut buf = [0; 3];
+    assert_eq!(
+        framing::decode(&line[..m], &mut buf),
+        Err(framing::Error::BufTooShort)
+    );
+}
+
+#[test]
+fn encoder_chunks() {
+    let packet: Vec<u8> = (0..600).map(|i| (i % 7) as u8).collect();
+    let mut expected = vec![0; framing::max_encoded_len(packet.len())];
+    let expected_len = framing::encode(&packet, &mut expected).unwrap();
+
+    for chunk_len in [1, 2, 7, 253, 254, 255, 600] {
+        let mut frame = vec![0; expected.len()];
+        let mut encoder = Encoder::new(&mut frame);
+        for chunk in packet.chunks(chunk_len) {
+            encoder.push_slice(chunk).unwrap();
+        }
+        let frame_len = encoder.finish().unwrap();
+        assert_eq!(frame[..frame_len], expected[..expected_len]);
+    }
+}
+
+#[test]
+fn server_frame_discards_partial_request() {
+    let mut rpc_client = RpcClient::new(32);
+    let mut rpc_server = RpcServer::new(32);
+    let mut send_buf = [0; 32];
+    let n = cli::Ping::new([1; 4])
+        .request(&mut rpc_client, &mut send_buf)
+        .unwrap();
+    let first = send_buf[..n].to_vec();
+    let n = cli::Ping::new([2; 4])
+        .request(&mut rpc_client, &mut send_buf)
+        .unwrap();
+    let second = &send_buf[..n];
+
+    // The first request is cut after its header
+    match rpc_server.parse(&first[..consts::REQ_HEADER_LEN]) {
+        Ok(server::ParseResult::NeedBytes(n)) => assert_eq!(n, 4),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match rpc_server.parse_frame(second) {
+        Ok(server::ParseResult::Request((header, body))) => {
+            assert_eq!(header.chan_id(), second[1]);
+            assert_eq!(body, [2; 4]);
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+/// Reply to a ping in channel `chan_id`.
+fn pong(chan_id: u8, body: [u8; 4]) -> Vec<u8> {
+    let mut reply = vec![chan_id, 0x00, 0x04, 0x00, 0x00, 0x00];
+    reply.extend_from_slice(&body);
+    reply
+}
+
+#[test]
+fn client_frame_discards_partial_reply() {
+    let mut rpc_client = RpcClient::new(32);
+    let mut send_buf = [0; 32];
+    let mut first = cli::Ping::new([1; 4]);
+    first.request(&mut rpc_client, &mut send_buf).unwrap();
+    let mut second = cli::Ping::new([2; 4]);
+    second.request(&mut rpc_client, &mut send_buf).unwrap();
+
+    // The reply to the first request is cut after its header
+    let first_reply = pong(first.chan_id(), [1; 4]);
+    match rpc_client.parse(&first_reply[..consts::REP_HEADER_LEN]) {
+        Ok((4, None)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert_eq!(
+        rpc_client
+            .parse_frame(&pong(second.chan_id(), [2; 4]))
+            .unwrap(),
+        Some(second.chan_id())
+    );
+    assert_eq!(second.take_reply(&mut rpc_client).unwrap().unwrap(), [2; 4]);
+
+    // The first request still waits for its reply
+    assert_eq!(
+        rpc_client.parse_frame(&first_reply).unwrap(),
+        Some(first.chan_id())
+    );
+    assert_eq!(first.take_reply(&mut rpc_client).unwrap().unwrap(), [1; 4]);
+    match rpc_client.parse_frame(&first_reply) {
+        Err(client::Error::UnexpectedChanId(_)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
@@ -0,0 +1,87 @@
+use std::convert::TryFrom;
+
+use urpc::{client, server, InvalidOpts, Opts};
+
+/// Each flag with the bit that encodes it.
+fn flags() -> [(Opts, u8); 6] {
+    let none = Opts::default();
+    [
+        (Opts { err: true, ..none }, 0x01),
+        (
+            Opts {
+                err_code: true,
+                ..none
+            },
+            0x02,
+        ),
+        (Opts { once: true, ..none }, 0x04),
+        (
+            Opts {
+                cancel: true,
+                ..none
+            },
+            0x08,
+        ),
+        (Opts { crc: true, ..none }, 0x10),
+        (Opts { sync: true, ..none }, 0x20),
+    ]
+}
+
+#[test]
+fn flag_bits() {
+    for (opts, bits) in flags() {
+        assert_eq!(opts.bits(), bits);
+        assert_eq!(u8::from(opts), bits);
+        assert_eq!(Opts::from_bits_truncate(bits), opts);
+        assert_eq!(Opts::try_from(bits), Ok(opts));
+    }
+}
+
+#[test]
+fn all_flags_bits() {
+    let all = Opts {
+        sync: true,
+        err: true,
+        err_code: true,
+        once: true,
+        cancel: true,
+        crc: true,
+    };
+    assert_eq!(all.bits(), 0x3f);
+    assert_eq!(Opts::try_from(0x3f), Ok(all));
+    assert_eq!(Opts::try_from(0x00), Ok(Opts::default()));
+}
+
+#[test]
+fn reserved_bits_rejected() {
+    for bits in [0x40, 0x80, 0xc0, 0x41, 0xff] {
+        assert_eq!(Opts::try_from(bits), Err(InvalidOpts(bits)));
+        assert_eq!(Opts::from_bits_truncate(bits).bits(), bits & 0x3f);
+    }
+}
+
+#[test]
+fn request_reserved_bits_rejected() {
+    for bits in [0x40, 0x80] {
+        let mut rpc_server = server::RpcServer::new(32);
+        // Ping request in channel 1 without body
+        let header = [0, 1, bits, 0, 0, 0, 0];
+        match rpc_server.parse(&header) {
+            Err(server::Error::InvalidOpts(opts)) if opts == bits => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn reply_reserved_bits_rejected() {
+    for bits in [0x40, 0x80] {
+        let mut rpc_client = client::RpcClient::new(32);
+        // Reply in channel 1 without body
+        let header = [1, bits, 0, 0, 0, 0];
+        match rpc_client.parse(&header) {
+            Err(client::Error::ReplyOptsInvalid(opts)) if opts == bits => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
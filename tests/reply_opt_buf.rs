@@ -0,0 +1,143 @@
+use urpc::{
+    client::{self, MethodError},
+    consts,
+    server::{self, Request},
+    server_requests,
+};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, fill, Fill(u8, OptBufNo, u32, OptBufYes)),
+        (1, samples, Samples(u8, OptBufNo, u32, OptBufYes) stream)
+    }
+}
+
+server_requests! {
+    ServerRequests;
+    (0, fill, Fill(u8, OptBufNo, u32, OptBufYes)),
+    (1, samples, Samples(u8, OptBufNo, u32, OptBufYes) stream)
+}
+
+struct Server;
+
+impl Handler for Server {
+    /// Fill the whole reply optional buffer, and claim `extra` more bytes.
+    fn fill(&mut self, extra: u8, out: &mut [u8]) -> Result<(u32, usize), ()> {
+        for b in out.iter_mut() {
+            *b = 0xaa;
+        }
+        Ok((out.len() as u32, out.len() + extra as usize))
+    }
+
+    fn samples(
+        &mut self,
+        len: u8,
+        items: &mut server::Items<u32, urpc::OptBufYes>,
+    ) -> Result<(), ()> {
+        items.send(0, &[0xaa; 4]).unwrap();
+        items.send(1, &vec![0xbb; len as usize]).ok();
+        Ok(())
+    }
+}
+
+const BUF_LEN: usize = 32;
+
+/// Dispatch the request serialized in `request`, and parse its replies into `rpc_client`.
+fn exchange(rpc_client: &mut client::RpcClient, request: &[u8]) {
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let mut reply_buf = [0; BUF_LEN];
+    let (header, body) = request.split_at(consts::REQ_HEADER_LEN);
+    server::dispatch::<ServerRequests, _>(&mut rpc_server, &mut Server, header, &mut reply_buf)
+        .unwrap();
+    let reply_len = match server::dispatch::<ServerRequests, _>(
+        &mut rpc_server,
+        &mut Server,
+        body,
+        &mut reply_buf,
+    ) {
+        Ok(server::ParseResult::Request(n)) => n,
+        r => panic!("unexpected result: {:?}", r),
+    };
+
+    let mut client_buf = [0; BUF_LEN];
+    let mut client_receiver = client::Receiver::new(&mut client_buf);
+    let mut pos = 0;
+    while pos < reply_len {
+        let (n, result) = client_receiver.push_slice(rpc_client, &reply_buf[pos..reply_len]);
+        pos += n;
+        result.transpose().unwrap();
+    }
+}
+
+#[test]
+fn handler_fills_opt_buf() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut fill = cli::Fill::new(0);
+    let n = fill.request(&mut rpc_client, &mut send_buf).unwrap();
+    exchange(&mut rpc_client, &send_buf[..n]);
+
+    // The optional buffer leaves room for the u32 body
+    let room = BUF_LEN - consts::REP_HEADER_LEN - 4;
+    let (len, buf) = fill.take_reply(&mut rpc_client).unwrap().unwrap();
+    assert_eq!(len as usize, room);
+    assert_eq!(buf, vec![0xaa; room]);
+}
+
+#[test]
+fn handler_opt_buf_too_long() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut fill = cli::Fill::new(1);
+    let n = fill.request(&mut rpc_client, &mut send_buf).unwrap();
+    exchange(&mut rpc_client, &send_buf[..n]);
+
+    match fill.take_reply(&mut rpc_client).unwrap() {
+        Err(MethodError::Client(client::Error::RequestTooLong)) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn item_opt_buf_too_long() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut samples = cli::Samples::new(BUF_LEN as u8);
+    let n = samples.request(&mut rpc_client, &mut send_buf).unwrap();
+    exchange(&mut rpc_client, &send_buf[..n]);
+
+    let (item, buf) = samples.take_item(&mut rpc_client).unwrap().unwrap();
+    assert_eq!((item, buf), (0, &[0xaa; 4][..]));
+    // The item that doesn't fit ends the stream
+    match samples.take_item(&mut rpc_client).unwrap() {
+        Err(MethodError::Client(client::Error::RequestTooLong)) => {}
+        r => panic!("unexpected item: {:?}", r),
+    }
+    assert!(samples.is_done());
+}
+
+#[test]
+fn opt_buf_writer_short_reply_buf() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let n = cli::Fill::new(0)
+        .request(&mut rpc_client, &mut send_buf)
+        .unwrap();
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let (header, body) = send_buf[..n].split_at(consts::REQ_HEADER_LEN);
+    ServerRequests::from_rpc(&mut rpc_server, header).unwrap();
+    let req = match ServerRequests::from_rpc(&mut rpc_server, body).unwrap() {
+        server::ParseResult::Request(ServerRequests::Fill(req)) => req,
+        r => panic!("unexpected result: {:?}", r),
+    };
+
+    // The reply buffer can't hold the header and the body
+    let mut reply_buf = [0; consts::REP_HEADER_LEN + 3];
+    assert!(matches!(
+        req.opt_buf_writer(4, &mut reply_buf),
+        Err(server::Error::Serialize(_))
+    ));
+}
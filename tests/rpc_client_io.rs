@@ -0,0 +1,310 @@
+use urpc::{
+    client::RpcClientIOError,
+    consts, rpc_client_io,
+    server::{self, Request},
+    server_requests,
+};
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RegError {
+    InvalidRegister(u8),
+}
+
+rpc_client_io! {
+    Client;
+    client_requests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
+    (2, recv_bytes, RecvBytes(u8, OptBufNo, u32, OptBufYes)),
+    (3, reverse, Reverse(u32, OptBufYes, u32, OptBufYes)),
+    (4, read_reg, ReadReg(u8, OptBufNo, u32, OptBufNo, RegError)),
+    (5, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream),
+    (6, reset, Reset((), OptBufNo, (), OptBufNo))
+}
+
+// The server doesn't implement the reset method
+server_requests! {
+    ServerRequests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
+    (2, recv_bytes, RecvBytes(u8, OptBufNo, u32, OptBufYes)),
+    (3, reverse, Reverse(u32, OptBufYes, u32, OptBufYes)),
+    (4, read_reg, ReadReg(u8, OptBufNo, u32, OptBufNo, RegError)),
+    (5, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
+}
+
+const BUF_LEN: usize = 256;
+
+/// In-memory stream that serves the requests written to it, and reads back the replies.
+struct MemServer {
+    rpc_server: server::RpcServer,
+    read_len: usize,
+    requests: Vec<u8>,
+    replies: VecDeque<u8>,
+}
+
+impl MemServer {
+    fn new() -> Self {
+        Self {
+            rpc_server: server::RpcServer::new(BUF_LEN as u16),
+            read_len: consts::REQ_HEADER_LEN,
+            requests: Vec::new(),
+            replies: VecDeque::new(),
+        }
+    }
+
+    fn serve(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut reply_buf = vec![0; BUF_LEN];
+        let req = match ServerRequests::from_rpc(&mut self.rpc_server, buf) {
+            Ok(server::ParseResult::NeedBytes(n)) => {
+                self.read_len = n;
+                return Vec::new();
+            }
+            Ok(server::ParseResult::Cancel(_)) => {
+                self.read_len = consts::REQ_HEADER_LEN;
+                return Vec::new();
+            }
+            Ok(server::ParseResult::Request(req)) => req,
+            Err(err) => {
+                self.read_len = consts::REQ_HEADER_LEN;
+                let n = self
+                    .rpc_server
+                    .reply_error_code(err.code().unwrap(), &mut reply_buf)
+                    .unwrap()
+                    .unwrap();
+                return reply_buf[..n].to_vec();
+            }
+        };
+        self.read_len = consts::REQ_HEADER_LEN;
+        let mut reply = Vec::new();
+        let n = match req {
+            ServerRequests::Ping(ping) => {
+                let body = ping.body;
+                ping.reply(body, &mut reply_buf).unwrap()
+            }
+            ServerRequests::SendBytes((send_bytes, buf)) => {
+                let sum = buf.iter().map(|b| *b as u32).sum::<u32>() + send_bytes.body;
+                send_bytes.reply(sum, &mut reply_buf).unwrap()
+            }
+            ServerRequests::RecvBytes(recv_bytes) => {
+                let len = recv_bytes.body as usize;
+                let mut opt_buf = recv_bytes.opt_buf_writer(4, &mut reply_buf).unwrap();
+                for (i, b) in opt_buf.spare()[..len].iter_mut().enumerate() {
+                    *b = i as u8;
+                }
+                opt_buf.advance(len).unwrap();
+                opt_buf.reply(len as u32).unwrap()
+            }
+            ServerRequests::Reverse((reverse, buf)) => {
+                let buf = buf.to_vec();
+                let body = reverse.body;
+                let mut opt_buf = reverse.opt_buf_writer(4, &mut reply_buf).unwrap();
+                for b in buf.iter().rev() {
+                    opt_buf.write(&[*b]).unwrap();
+                }
+                opt_buf.reply(body + 1).unwrap()
+            }
+            ServerRequests::ReadReg(read_reg) => {
+                let reg = read_reg.body;
+                if reg < 4 {
+                    read_reg.reply(0x1000 + reg as u32, &mut reply_buf).unwrap()
+                } else {
+                    read_reg
+                        .reply_err(RegError::InvalidRegister(reg), &mut reply_buf)
+                        .unwrap()
+                }
+            }
+            ServerRequests::Samples(samples) => {
+                for i in 0..samples.body as u16 {
+                    let n = samples.reply_item(i * 10, &mut reply_buf).unwrap();
+                    reply.extend_from_slice(&reply_buf[..n]);
+                }
+                samples.end(&mut reply_buf).unwrap()
+            }
+        };
+        reply.extend_from_slice(&reply_buf[..n]);
+        reply
+    }
+}
+
+impl Write for MemServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.requests.extend_from_slice(buf);
+        while self.requests.len() >= self.read_len {
+            let rest = self.requests.split_off(self.read_len);
+            let buf = std::mem::replace(&mut self.requests, rest);
+            let reply = self.serve(&buf);
+            self.replies.extend(reply);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemServer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.replies.len());
+        for (dst, src) in buf.iter_mut().zip(self.replies.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+fn client() -> Client<MemServer> {
+    Client::new(MemServer::new(), BUF_LEN)
+}
+
+#[test]
+fn request_no_opt_buf() {
+    let mut client = client();
+    assert_eq!(client.ping([0, 1, 2, 3]).unwrap(), [0, 1, 2, 3]);
+    assert_eq!(client.ping([4, 5, 6, 7]).unwrap(), [4, 5, 6, 7]);
+}
+
+#[test]
+fn request_opt_buf() {
+    let mut client = client();
+    assert_eq!(client.send_bytes(100, &[1, 2, 3, 4]).unwrap(), 110);
+}
+
+#[test]
+fn reply_opt_buf() {
+    let mut client = client();
+    assert_eq!(
+        client.recv_bytes(5).unwrap(),
+        (5, vec![0x00, 0x01, 0x02, 0x03, 0x04])
+    );
+}
+
+#[test]
+fn request_and_reply_opt_buf() {
+    let mut client = client();
+    assert_eq!(
+        client.reverse(41, &[1, 2, 3]).unwrap(),
+        (42, vec![0x03, 0x02, 0x01])
+    );
+}
+
+#[test]
+fn method_error() {
+    let mut client = client();
+    assert_eq!(client.read_reg(2).unwrap(), 0x1002);
+    match client.read_reg(7) {
+        Err(RpcClientIOError::Method(RegError::InvalidRegister(7))) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn stream() {
+    let mut client = client();
+    let items = client
+        .samples(4)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(items, vec![0, 10, 20, 30]);
+    // The client is usable again after the stream ends
+    assert_eq!(client.ping([0, 1, 2, 3]).unwrap(), [0, 1, 2, 3]);
+}
+
+#[test]
+fn unknown_method() {
+    let mut client = client();
+    match client.reset(()) {
+        Err(RpcClientIOError::Urpc(urpc::client::Error::UnknownMethod)) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn opt_buf_writer_bounds() {
+    let mut rpc_client = urpc::client::RpcClient::new(BUF_LEN as u16);
+    let mut buf = vec![0; BUF_LEN];
+    let n = RecvBytes::new(8)
+        .request(&mut rpc_client, &mut buf)
+        .unwrap();
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let (header, body) = buf[..n].split_at(consts::REQ_HEADER_LEN);
+    match ServerRequests::from_rpc(&mut rpc_server, header).unwrap() {
+        server::ParseResult::NeedBytes(len) => assert_eq!(len, body.len()),
+        _ => panic!("unexpected parse result"),
+    }
+    let req = match ServerRequests::from_rpc(&mut rpc_server, body).unwrap() {
+        server::ParseResult::Request(ServerRequests::RecvBytes(req)) => req,
+        _ => panic!("unexpected parse result"),
+    };
+
+    // The optional buffer gets the room left after the header and the reserved body
+    let mut reply_buf = [0; consts::REP_HEADER_LEN + 8 + 4];
+    let mut opt_buf = req.opt_buf_writer(4, &mut reply_buf).unwrap();
+    assert_eq!(opt_buf.capacity(), 8);
+    opt_buf.write(&[0, 1, 2]).unwrap();
+    opt_buf.spare()[..4].copy_from_slice(&[3, 4, 5, 6]);
+    opt_buf.advance(4).unwrap();
+    assert_eq!(
+        opt_buf.write(&[7, 8]),
+        Err(server::Error::ReplyOptBufTooLong { len: 9, max: 8 })
+    );
+    assert_eq!(
+        opt_buf.advance(2),
+        Err(server::Error::ReplyOptBufTooLong { len: 9, max: 8 })
+    );
+    assert_eq!(opt_buf.written(), [0, 1, 2, 3, 4, 5, 6]);
+    let n = opt_buf.reply(0x01020304).unwrap();
+    assert_eq!(
+        reply_buf[..n],
+        [1, 4, 4, 0, 7, 0, 0, 1, 2, 3, 4, 5, 6, 4, 3, 2, 1]
+    );
+}
+
+#[test]
+fn in_place_replies() {
+    let mut rpc = urpc::client::RpcClientIO::new(MemServer::new(), BUF_LEN);
+    rpc.client.set_in_place(true);
+    assert_eq!(
+        Ping::new([0, 1, 2, 3]).call_io(&mut rpc).unwrap(),
+        [0, 1, 2, 3]
+    );
+    assert_eq!(
+        RecvBytes::new(3).call_io(&mut rpc).unwrap(),
+        (3, vec![0x00, 0x01, 0x02])
+    );
+    assert_eq!(
+        Reverse::new(41).call_io(&[1, 2, 3], &mut rpc).unwrap(),
+        (42, vec![0x03, 0x02, 0x01])
+    );
+    match ReadReg::new(7).call_io(&mut rpc) {
+        Err(RpcClientIOError::Method(RegError::InvalidRegister(7))) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+    let items = Samples::new(3)
+        .call_io(&mut rpc)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(items, vec![0, 10, 20]);
+}
+
+#[test]
+fn drop_stream() {
+    let mut rpc = urpc::client::RpcClientIO::new(MemServer::new(), BUF_LEN);
+    let mut items = Samples::new(4).call_io(&mut rpc).unwrap();
+    assert_eq!(items.next().unwrap().unwrap(), 0);
+    drop(items);
+    // The stream is cancelled, and its late items are dropped
+    assert_eq!(rpc.client.in_flight(), 0);
+    assert_eq!(
+        Ping::new([0, 1, 2, 3]).call_io(&mut rpc).unwrap(),
+        [0, 1, 2, 3]
+    );
+    assert_eq!(rpc.client.in_flight(), 0);
+}
@@ -0,0 +1,425 @@
+#![cfg(any(feature = "embedded-io", feature = "embedded-hal-nb"))]
+
+use urpc::{
+    client::{self, RpcClient},
+    consts,
+    serial::{SerialClient, SerialServer},
+    server::Items,
+    service_requests,
+};
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+
+service_requests! {
+    Requests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
+    (2, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream)
+}
+
+struct Server;
+
+impl Handler for Server {
+    fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+        Ok(body)
+    }
+
+    fn send_bytes(&mut self, base: u32, buf: &[u8]) -> Result<u32, ()> {
+        Ok(buf.iter().map(|b| *b as u32).sum::<u32>() + base)
+    }
+
+    fn samples(&mut self, n: u8, items: &mut Items<u16>) -> Result<(), ()> {
+        for i in 0..n as u16 {
+            items.send(i * 10).unwrap();
+        }
+        Ok(())
+    }
+}
+
+const BUF_LEN: usize = 32;
+
+/// Mock serial port, which receives the bytes queued in `rx` and keeps the sent bytes in `tx`.
+/// Reading an empty `rx` would block.
+#[derive(Default)]
+struct MockSerial {
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+}
+
+impl MockSerial {
+    /// Move the bytes sent by `self` to the receive queue of `other`.
+    fn send_to(&mut self, other: &mut MockSerial) {
+        other.rx.extend(self.tx.drain(..));
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+mod io {
+    use super::*;
+    use embedded_io::{ErrorType, Read, ReadExactError, Write};
+    use urpc::serial::{IoSerial, SerialClientError, SerialServerError};
+
+    impl ErrorType for MockSerial {
+        type Error = Infallible;
+    }
+
+    impl Read for MockSerial {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let n = buf.len().min(self.rx.len());
+            for (dst, src) in buf.iter_mut().zip(self.rx.drain(..n)) {
+                *dst = src;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for MockSerial {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serve_and_call() {
+        let (mut rcv_buf, mut reply_buf) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut server = SerialServer::new(
+            IoSerial(MockSerial::default()),
+            &mut rcv_buf,
+            &mut reply_buf,
+        );
+        let mut buf = [0; BUF_LEN];
+        let mut storage = [0; 64];
+        let mut rpc = SerialClient::new(
+            IoSerial(MockSerial::default()),
+            RpcClient::with_storage(BUF_LEN as u16, &mut storage[..]),
+            &mut buf,
+        );
+
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        let n = ping.request(&mut rpc.client, rpc.buf).unwrap();
+        rpc.send(n).unwrap();
+        let mut send_bytes = SendBytes::new(100);
+        let n = send_bytes
+            .request(&[1, 2, 3, 4], &mut rpc.client, rpc.buf)
+            .unwrap();
+        rpc.send(n).unwrap();
+        let mut samples = Samples::new(3);
+        let n = samples.request(&mut rpc.client, rpc.buf).unwrap();
+        rpc.send(n).unwrap();
+
+        rpc.serial.0.send_to(&mut server.serial.0);
+        for _ in 0..3 {
+            server.serve::<Requests, _>(&mut Server).unwrap();
+        }
+        // No more requests
+        match server.serve::<Requests, _>(&mut Server) {
+            Err(SerialServerError::Serial(ReadExactError::UnexpectedEof)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        server.serial.0.send_to(&mut rpc.serial.0);
+
+        rpc.wait_reply(send_bytes.chan_id()).unwrap();
+        assert_eq!(
+            send_bytes.take_reply(&mut rpc.client).unwrap().unwrap(),
+            110
+        );
+        assert_eq!(
+            ping.take_reply(&mut rpc.client).unwrap().unwrap(),
+            [0, 1, 2, 3]
+        );
+        let mut items = Vec::new();
+        while !samples.is_done() {
+            rpc.wait_reply(samples.chan_id()).unwrap();
+            if let Some(item) = samples.take_item(&mut rpc.client) {
+                items.push(item.unwrap());
+            }
+        }
+        assert_eq!(items, vec![0, 10, 20]);
+        assert_eq!(rpc.client.in_flight(), 0);
+    }
+
+    #[test]
+    fn request_too_long() {
+        let (mut rcv_buf, mut reply_buf) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut server = SerialServer::new(
+            IoSerial(MockSerial::default()),
+            &mut rcv_buf,
+            &mut reply_buf,
+        );
+        let mut rpc_client = RpcClient::new(64);
+        let mut buf = [0; 64];
+
+        let mut send_bytes = SendBytes::new(0);
+        let n = send_bytes
+            .request(&[0xaa; 40], &mut rpc_client, &mut buf)
+            .unwrap();
+        server.serial.0.rx.extend(&buf[..n]);
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        let n = ping.request(&mut rpc_client, &mut buf).unwrap();
+        server.serial.0.rx.extend(&buf[..n]);
+
+        // The long request is skipped, and the next one is served
+        server.serve::<Requests, _>(&mut Server).unwrap();
+        server.serve::<Requests, _>(&mut Server).unwrap();
+        assert!(server.serial.0.rx.is_empty());
+
+        let replies = std::mem::take(&mut server.serial.0.tx);
+        let mut pos = 0;
+        while pos < replies.len() {
+            let n = rpc_client
+                .parse(&replies[pos..pos + consts::REP_HEADER_LEN])
+                .unwrap()
+                .0;
+            pos += consts::REP_HEADER_LEN;
+            rpc_client.parse(&replies[pos..pos + n]).unwrap();
+            pos += n;
+        }
+        match send_bytes.take_reply(&mut rpc_client).unwrap() {
+            Err(client::MethodError::Client(client::Error::RequestTooLong)) => {}
+            r => panic!("unexpected reply: {:?}", r),
+        }
+        assert_eq!(
+            ping.take_reply(&mut rpc_client).unwrap().unwrap(),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn server_read_error() {
+        let (mut rcv_buf, mut reply_buf) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut server = SerialServer::new(
+            IoSerial(MockSerial::default()),
+            &mut rcv_buf,
+            &mut reply_buf,
+        );
+        let mut rpc_client = RpcClient::new(BUF_LEN as u16);
+        let mut buf = [0; BUF_LEN];
+
+        // The stream ends in the middle of a request
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        let n = ping.request(&mut rpc_client, &mut buf).unwrap();
+        server.serial.0.rx.extend(&buf[..n - 2]);
+        match server.serve::<Requests, _>(&mut Server) {
+            Err(SerialServerError::Serial(ReadExactError::UnexpectedEof)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The next request is parsed from its header
+        let mut ping = Ping::new([4, 5, 6, 7]);
+        let n = ping.request(&mut rpc_client, &mut buf).unwrap();
+        server.serial.0.rx.extend(&buf[..n]);
+        server.serve::<Requests, _>(&mut Server).unwrap();
+        let reply = std::mem::take(&mut server.serial.0.tx);
+        let n = rpc_client
+            .parse(&reply[..consts::REP_HEADER_LEN])
+            .unwrap()
+            .0;
+        rpc_client
+            .parse(&reply[consts::REP_HEADER_LEN..consts::REP_HEADER_LEN + n])
+            .unwrap();
+        assert_eq!(
+            ping.take_reply(&mut rpc_client).unwrap().unwrap(),
+            [4, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn client_read_error() {
+        let mut buf = [0; BUF_LEN];
+        let mut rpc = SerialClient::new(
+            IoSerial(MockSerial::default()),
+            RpcClient::new(BUF_LEN as u16),
+            &mut buf,
+        );
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        ping.request(&mut rpc.client, rpc.buf).unwrap();
+        let reply = [0x01, 0x04, 0x04, 0x00, 0x00, 0x00, 0, 1, 2, 3];
+
+        // The stream ends in the middle of the reply
+        rpc.serial.0.rx.extend(&reply[..8]);
+        match rpc.wait_reply(ping.chan_id()) {
+            Err(SerialClientError::Serial(ReadExactError::UnexpectedEof)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The reply sent again is parsed from its header
+        rpc.serial.0.rx.extend(&reply);
+        rpc.wait_reply(ping.chan_id()).unwrap();
+        assert_eq!(
+            ping.take_reply(&mut rpc.client).unwrap().unwrap(),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn client_reply_too_long() {
+        let mut buf = [0; 16];
+        let mut rpc = SerialClient::new(
+            IoSerial(MockSerial::default()),
+            RpcClient::new(64),
+            &mut buf,
+        );
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        ping.request(&mut rpc.client, rpc.buf).unwrap();
+
+        // A reply whose body doesn't fit in the buffer, followed by the right one
+        rpc.serial.0.rx.extend(&[0x01, 0x04, 20, 0x00, 0x00, 0x00]);
+        rpc.serial.0.rx.extend(&[0xaa; 20]);
+        rpc.serial
+            .0
+            .rx
+            .extend(&[0x01, 0x04, 0x04, 0x00, 0x00, 0x00, 0, 1, 2, 3]);
+        match rpc.wait_reply(ping.chan_id()) {
+            Err(SerialClientError::Urpc(client::Error::ReplyBodyTooLong)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        rpc.wait_reply(ping.chan_id()).unwrap();
+        assert_eq!(
+            ping.take_reply(&mut rpc.client).unwrap().unwrap(),
+            [0, 1, 2, 3]
+        );
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+mod nb {
+    use super::*;
+    use embedded_hal_nb::nb;
+    use embedded_hal_nb::serial::{ErrorType, Read, Write};
+    use urpc::serial::{NbSerial, SerialClientError};
+
+    impl ErrorType for MockSerial {
+        type Error = Infallible;
+    }
+
+    impl Read for MockSerial {
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write for MockSerial {
+        fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+            self.tx.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poll() {
+        let (mut rcv_buf, mut reply_buf) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut server = SerialServer::new(
+            NbSerial(MockSerial::default()),
+            &mut rcv_buf,
+            &mut reply_buf,
+        );
+        let mut buf = [0; BUF_LEN];
+        let mut rpc = SerialClient::new(
+            NbSerial(MockSerial::default()),
+            RpcClient::new(BUF_LEN as u16),
+            &mut buf,
+        );
+
+        let mut send_bytes = SendBytes::new(100);
+        let n = send_bytes
+            .request(&[1, 2, 3, 4], &mut rpc.client, rpc.buf)
+            .unwrap();
+        rpc.send(n).unwrap();
+
+        // The request arrives byte by byte
+        let request = std::mem::take(&mut rpc.serial.0.tx);
+        for (i, b) in request.iter().enumerate() {
+            assert!(matches!(
+                server.poll::<Requests, _>(&mut Server),
+                Err(nb::Error::WouldBlock)
+            ));
+            server.serial.0.rx.push_back(*b);
+            if i == request.len() - 1 {
+                server.poll::<Requests, _>(&mut Server).unwrap();
+            }
+        }
+
+        // The reply arrives byte by byte
+        let reply = std::mem::take(&mut server.serial.0.tx);
+        for b in &reply {
+            assert!(matches!(rpc.poll(), Err(nb::Error::WouldBlock)));
+            rpc.serial.0.rx.push_back(*b);
+        }
+        assert_eq!(rpc.poll().unwrap(), send_bytes.chan_id());
+        assert_eq!(
+            send_bytes.take_reply(&mut rpc.client).unwrap().unwrap(),
+            110
+        );
+    }
+
+    #[test]
+    fn blocking() {
+        let (mut rcv_buf, mut reply_buf) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut server = SerialServer::new(
+            NbSerial(MockSerial::default()),
+            &mut rcv_buf,
+            &mut reply_buf,
+        );
+        let mut buf = [0; BUF_LEN];
+        let mut rpc = SerialClient::new(
+            NbSerial(MockSerial::default()),
+            RpcClient::new(BUF_LEN as u16),
+            &mut buf,
+        );
+
+        let mut samples = Samples::new(2);
+        let n = samples.request(&mut rpc.client, rpc.buf).unwrap();
+        rpc.send(n).unwrap();
+        rpc.serial.0.send_to(&mut server.serial.0);
+        server.serve::<Requests, _>(&mut Server).unwrap();
+        server.serial.0.send_to(&mut rpc.serial.0);
+
+        let mut items = Vec::new();
+        while !samples.is_done() {
+            rpc.wait_reply(samples.chan_id()).unwrap();
+            if let Some(item) = samples.take_item(&mut rpc.client) {
+                items.push(item.unwrap());
+            }
+        }
+        assert_eq!(items, vec![0, 10]);
+    }
+
+    #[test]
+    fn poll_reply_too_long() {
+        let mut buf = [0; 16];
+        let mut rpc = SerialClient::new(
+            NbSerial(MockSerial::default()),
+            RpcClient::new(64),
+            &mut buf,
+        );
+        let mut ping = Ping::new([0, 1, 2, 3]);
+        ping.request(&mut rpc.client, rpc.buf).unwrap();
+
+        // A reply whose body doesn't fit in the buffer, followed by the right one
+        rpc.serial.0.rx.extend(&[0x01, 0x04, 20, 0x00, 0x00, 0x00]);
+        rpc.serial.0.rx.extend(&[0xaa; 20]);
+        rpc.serial
+            .0
+            .rx
+            .extend(&[0x01, 0x04, 0x04, 0x00, 0x00, 0x00, 0, 1, 2, 3]);
+        match rpc.poll() {
+            Err(nb::Error::Other(SerialClientError::Urpc(client::Error::ReplyBodyTooLong))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(rpc.poll().unwrap(), ping.chan_id());
+        assert_eq!(
+            ping.take_reply(&mut rpc.client).unwrap().unwrap(),
+            [0, 1, 2, 3]
+        );
+    }
+}
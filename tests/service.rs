@@ -0,0 +1,549 @@
+#![cfg(feature = "macros")]
+
+use urpc::{
+    client::{self, RpcClientIO, RpcClientIOError},
+    consts,
+    server::{self, Items},
+    ServerInfo,
+};
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum RegError {
+    InvalidRegister(u8),
+}
+
+#[urpc::service]
+trait Device {
+    /// Echo the request body.
+    #[id(0)]
+    fn ping(&mut self, body: [u8; 4]) -> [u8; 4];
+
+    #[id(1)]
+    fn send_bytes(&mut self, base: u32, buf: &[u8]) -> u32;
+
+    #[id(2)]
+    fn recv_bytes(&mut self, len: u8, out: &mut [u8]) -> (u32, usize);
+
+    #[id(3)]
+    fn reverse(&self, buf: &[u8], out: &mut [u8], base: u32) -> (u32, usize);
+
+    #[id(4)]
+    fn read_reg(&mut self, reg: u8) -> Result<u32, RegError>;
+
+    #[id(5)]
+    fn samples(&mut self, n: u8, items: &mut Items<u16>);
+
+    #[id(6)]
+    fn reset(&mut self) -> Result<u32, ()>;
+
+    #[id(7)]
+    fn log(&mut self, msg: &str) -> u32;
+}
+
+mod cli {
+    use super::RegError;
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, ping, Ping([u8;4], OptBufNo, [u8;4], OptBufNo)),
+        (1, send_bytes, SendBytes(u32, OptBufYes, u32, OptBufNo)),
+        (2, recv_bytes, RecvBytes(u8, OptBufNo, u32, OptBufYes)),
+        (3, reverse, Reverse(u32, OptBufYes, u32, OptBufYes)),
+        (4, read_reg, ReadReg(u8, OptBufNo, u32, OptBufNo, RegError)),
+        (5, samples, Samples(u8, OptBufNo, u16, OptBufNo) stream),
+        (6, reset, Reset((), OptBufNo, u32, OptBufNo)),
+        (7, log, Log(&'static str, OptBufNo, u32, OptBufNo))
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    resets: u32,
+}
+
+impl Device for Server {
+    fn ping(&mut self, body: [u8; 4]) -> [u8; 4] {
+        body
+    }
+
+    fn send_bytes(&mut self, base: u32, buf: &[u8]) -> u32 {
+        buf.iter().map(|b| *b as u32).sum::<u32>() + base
+    }
+
+    fn recv_bytes(&mut self, len: u8, out: &mut [u8]) -> (u32, usize) {
+        let len = len as usize;
+        for (i, b) in out[..len].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        (len as u32, len)
+    }
+
+    fn reverse(&self, buf: &[u8], out: &mut [u8], base: u32) -> (u32, usize) {
+        for (dst, src) in out.iter_mut().zip(buf.iter().rev()) {
+            *dst = *src;
+        }
+        (base + 1, buf.len())
+    }
+
+    fn read_reg(&mut self, reg: u8) -> Result<u32, RegError> {
+        if reg < 4 {
+            Ok(0x1000 + reg as u32)
+        } else {
+            Err(RegError::InvalidRegister(reg))
+        }
+    }
+
+    fn samples(&mut self, n: u8, items: &mut Items<u16>) {
+        for i in 0..n as u16 {
+            items.send(i * 10).unwrap();
+        }
+    }
+
+    fn reset(&mut self) -> Result<u32, ()> {
+        self.resets += 1;
+        Ok(self.resets)
+    }
+
+    fn log(&mut self, msg: &str) -> u32 {
+        msg.len() as u32
+    }
+}
+
+const BUF_LEN: usize = 256;
+
+/// In-memory stream that dispatches the requests written to it, and reads back the replies.
+struct MemServer {
+    rpc_server: server::RpcServer,
+    server: Server,
+    read_len: usize,
+    requests: Vec<u8>,
+    replies: VecDeque<u8>,
+}
+
+impl MemServer {
+    fn new() -> Self {
+        Self {
+            rpc_server: server::RpcServer::new(BUF_LEN as u16),
+            server: Server::default(),
+            read_len: consts::REQ_HEADER_LEN,
+            requests: Vec::new(),
+            replies: VecDeque::new(),
+        }
+    }
+}
+
+impl Write for MemServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.requests.extend_from_slice(buf);
+        let mut reply_buf = vec![0; BUF_LEN];
+        while self.requests.len() >= self.read_len {
+            let rest = self.requests.split_off(self.read_len);
+            let buf = std::mem::replace(&mut self.requests, rest);
+            match server::dispatch::<DeviceRequests, _>(
+                &mut self.rpc_server,
+                &mut self.server,
+                &buf,
+                &mut reply_buf,
+            )
+            .unwrap()
+            {
+                server::ParseResult::NeedBytes(n) => self.read_len = n,
+                server::ParseResult::Cancel(_) => self.read_len = consts::REQ_HEADER_LEN,
+                server::ParseResult::Request(n) => {
+                    self.read_len = consts::REQ_HEADER_LEN;
+                    self.replies.extend(&reply_buf[..n]);
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MemServer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.replies.len());
+        for (dst, src) in buf.iter_mut().zip(self.replies.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+fn client() -> RpcClientIO<MemServer> {
+    RpcClientIO::new(MemServer::new(), BUF_LEN)
+}
+
+#[test]
+fn request_no_opt_buf() {
+    let mut rpc = client();
+    assert_eq!(
+        Ping::new([0, 1, 2, 3]).call_io(&mut rpc).unwrap(),
+        [0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn request_opt_buf() {
+    let mut rpc = client();
+    assert_eq!(
+        SendBytes::new(100)
+            .call_io(&[1, 2, 3, 4], &mut rpc)
+            .unwrap(),
+        110
+    );
+}
+
+#[test]
+fn reply_opt_buf() {
+    let mut rpc = client();
+    assert_eq!(
+        RecvBytes::new(5).call_io(&mut rpc).unwrap(),
+        (5, vec![0x00, 0x01, 0x02, 0x03, 0x04])
+    );
+}
+
+#[test]
+fn request_and_reply_opt_buf() {
+    let mut rpc = client();
+    assert_eq!(
+        Reverse::new(41).call_io(&[1, 2, 3], &mut rpc).unwrap(),
+        (42, vec![0x03, 0x02, 0x01])
+    );
+}
+
+#[test]
+fn method_error() {
+    let mut rpc = client();
+    assert_eq!(ReadReg::new(2).call_io(&mut rpc).unwrap(), 0x1002);
+    match ReadReg::new(7).call_io(&mut rpc) {
+        Err(RpcClientIOError::Method(RegError::InvalidRegister(7))) => {}
+        r => panic!("unexpected reply: {:?}", r),
+    }
+}
+
+#[test]
+fn stream() {
+    let mut rpc = client();
+    let items = Samples::new(4)
+        .call_io(&mut rpc)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(items, vec![0, 10, 20, 30]);
+}
+
+#[test]
+fn no_request_body() {
+    let mut rpc = client();
+    assert_eq!(Reset::new(()).call_io(&mut rpc).unwrap(), 1);
+    assert_eq!(Reset::new(()).call_io(&mut rpc).unwrap(), 2);
+}
+
+#[test]
+fn borrowed_request_body() {
+    let mut rpc = client();
+    assert_eq!(Log::new("hello").call_io(&mut rpc).unwrap(), 5);
+    assert_eq!(cli::Log::new("hello").call_io(&mut rpc).unwrap(), 5);
+}
+
+#[test]
+fn in_place_replies() {
+    let mut server = MemServer::new();
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    rpc_client.set_crc(true);
+    rpc_client.set_in_place(true);
+    let mut send_buf = vec![0; BUF_LEN];
+    let mut recv_buf = vec![0; BUF_LEN];
+
+    let mut req = RecvBytes::new(4);
+    let n = req.request(&mut rpc_client, &mut send_buf).unwrap();
+    server.write_all(&send_buf[..n]).unwrap();
+    server
+        .read_exact(&mut recv_buf[..consts::REP_HEADER_LEN])
+        .unwrap();
+    let read_len = rpc_client
+        .parse(&recv_buf[..consts::REP_HEADER_LEN])
+        .unwrap()
+        .0;
+    server.read_exact(&mut recv_buf[..read_len]).unwrap();
+    rpc_client.parse(&recv_buf[..read_len]).unwrap();
+    // The reply can't be taken as a copied reply
+    assert!(rpc_client.has_reply(req.chan_id()));
+    assert!(req.take_reply(&mut rpc_client).is_none());
+    let (r, buf) = req
+        .take_reply_in_place(&mut rpc_client, &recv_buf)
+        .unwrap()
+        .unwrap();
+    assert_eq!((r, buf), (4, &[0, 1, 2, 3][..]));
+    assert_eq!(rpc_client.in_flight(), 0);
+
+    let mut stream = Samples::new(3);
+    let n = stream.request(&mut rpc_client, &mut send_buf).unwrap();
+    server.write_all(&send_buf[..n]).unwrap();
+    let mut items = Vec::new();
+    while !stream.is_done() {
+        server
+            .read_exact(&mut recv_buf[..consts::REP_HEADER_LEN])
+            .unwrap();
+        let read_len = rpc_client
+            .parse(&recv_buf[..consts::REP_HEADER_LEN])
+            .unwrap()
+            .0;
+        server.read_exact(&mut recv_buf[..read_len]).unwrap();
+        rpc_client.parse(&recv_buf[..read_len]).unwrap();
+        if let Some(item) = stream.take_item_in_place(&mut rpc_client, &recv_buf) {
+            items.push(item.unwrap());
+        }
+    }
+    assert_eq!(items, vec![0, 10, 20]);
+}
+
+/// Send the requests in `send_buf` to `server`, and parse all its replies with `rpc_client`.
+fn exchange<S: client::ReplyStorage>(
+    server: &mut MemServer,
+    rpc_client: &mut client::RpcClient<S>,
+    send_buf: &[u8],
+) -> client::Result<()> {
+    server.write_all(send_buf).unwrap();
+    let mut recv_buf = vec![0; BUF_LEN];
+    let mut read_len = consts::REP_HEADER_LEN;
+    while !server.replies.is_empty() {
+        server.read_exact(&mut recv_buf[..read_len]).unwrap();
+        read_len = rpc_client.parse(&recv_buf[..read_len])?.0;
+    }
+    Ok(())
+}
+
+#[test]
+fn heapless_client() {
+    let mut server = MemServer::new();
+    let mut storage = [0; 64];
+    let mut rpc_client = client::RpcClient::with_storage(BUF_LEN as u16, &mut storage[..]);
+    let mut send_buf = [0; BUF_LEN];
+
+    let mut reg = ReadReg::new(1);
+    let n = reg.request(&mut rpc_client, &mut send_buf).unwrap();
+    let mut stream = Samples::new(3);
+    let m = stream.request(&mut rpc_client, &mut send_buf[n..]).unwrap();
+    exchange(&mut server, &mut rpc_client, &send_buf[..n + m]).unwrap();
+    // The replies are taken in any order
+    let mut items = Vec::new();
+    while let Some(item) = stream.take_item(&mut rpc_client) {
+        items.push(item.unwrap());
+    }
+    assert!(stream.is_done());
+    assert_eq!(items, vec![0, 10, 20]);
+    assert_eq!(reg.take_reply(&mut rpc_client).unwrap().unwrap(), 0x1001);
+    assert_eq!(rpc_client.in_flight(), 0);
+
+    let mut recv = RecvBytes::new(8);
+    let n = recv.request(&mut rpc_client, &mut send_buf).unwrap();
+    exchange(&mut server, &mut rpc_client, &send_buf[..n]).unwrap();
+    let (r, buf) = recv.take_reply(&mut rpc_client).unwrap().unwrap();
+    assert_eq!((r, buf), (8, &[0, 1, 2, 3, 4, 5, 6, 7][..]));
+
+    // The reply doesn't fit in the storage
+    let mut recv = RecvBytes::new(60);
+    let n = recv.request(&mut rpc_client, &mut send_buf).unwrap();
+    match exchange(&mut server, &mut rpc_client, &send_buf[..n]) {
+        Err(client::Error::ReplyStorageFull) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn push_receivers() {
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let mut server = Server::default();
+    let mut server_buf = [0; 32];
+    let mut server_receiver = server::Receiver::new(&mut server_buf);
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    rpc_client.set_crc(true);
+    rpc_client.set_in_place(true);
+    let mut send_buf = vec![0; BUF_LEN];
+    let mut requests = Vec::new();
+
+    let mut send = SendBytes::new(100);
+    let n = send
+        .request(&[1, 2, 3, 4], &mut rpc_client, &mut send_buf)
+        .unwrap();
+    requests.extend_from_slice(&send_buf[..n]);
+    // Too long for the server receiver buffer
+    let mut long = SendBytes::new(0);
+    let n = long
+        .request(&[0xaa; 40], &mut rpc_client, &mut send_buf)
+        .unwrap();
+    requests.extend_from_slice(&send_buf[..n]);
+    let mut stream = Samples::new(2);
+    let n = stream.request(&mut rpc_client, &mut send_buf).unwrap();
+    requests.extend_from_slice(&send_buf[..n]);
+    let mut recv = RecvBytes::new(4);
+    let n = recv.request(&mut rpc_client, &mut send_buf).unwrap();
+    requests.extend_from_slice(&send_buf[..n]);
+
+    // The requests arrive byte by byte
+    let mut reply_buf = vec![0; BUF_LEN];
+    let mut replies = Vec::new();
+    for b in &requests {
+        let (n, result) = server_receiver.dispatch::<DeviceRequests, _>(
+            &mut rpc_server,
+            &mut server,
+            &[*b],
+            &mut reply_buf,
+        );
+        assert_eq!(n, 1);
+        match result {
+            Some(Ok(server::ParseResult::Request(n))) => replies.extend_from_slice(&reply_buf[..n]),
+            None => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    // The replies arrive in a single chunk
+    let mut client_buf = [0; 32];
+    let mut client_receiver = client::Receiver::new(&mut client_buf);
+    let mut pos = 0;
+    let mut items = Vec::new();
+    while pos < replies.len() {
+        let (n, result) = client_receiver.push_slice(&mut rpc_client, &replies[pos..]);
+        pos += n;
+        match result.transpose().unwrap().flatten() {
+            Some(chan_id) if chan_id == send.chan_id() => {
+                let r = send.take_reply_in_place(&mut rpc_client, client_receiver.packet());
+                assert_eq!(r.unwrap().unwrap(), 110);
+            }
+            Some(chan_id) if chan_id == long.chan_id() => {
+                match long.take_reply_in_place(&mut rpc_client, client_receiver.packet()) {
+                    Some(Err(client::MethodError::Client(client::Error::RequestTooLong))) => {}
+                    r => panic!("unexpected reply: {:?}", r),
+                }
+            }
+            Some(chan_id) if chan_id == stream.chan_id() => {
+                if let Some(item) =
+                    stream.take_item_in_place(&mut rpc_client, client_receiver.packet())
+                {
+                    items.push(item.unwrap());
+                }
+            }
+            Some(chan_id) => {
+                assert_eq!(chan_id, recv.chan_id());
+                let (r, buf) = recv
+                    .take_reply_in_place(&mut rpc_client, client_receiver.packet())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!((r, buf), (4, &[0, 1, 2, 3][..]));
+            }
+            None => {}
+        }
+    }
+    assert!(stream.is_done());
+    assert_eq!(items, vec![0, 10]);
+    assert_eq!(rpc_client.in_flight(), 0);
+
+    // A reply too long for the client receiver buffer is dropped, and the next one is parsed
+    let mut recv = RecvBytes::new(30);
+    let n = recv.request(&mut rpc_client, &mut send_buf).unwrap();
+    let mut ping = Ping::new([0, 1, 2, 3]);
+    let m = ping.request(&mut rpc_client, &mut send_buf[n..]).unwrap();
+    let mut replies = Vec::new();
+    let mut pos = 0;
+    while pos < n + m {
+        let (k, result) = server_receiver.dispatch::<DeviceRequests, _>(
+            &mut rpc_server,
+            &mut server,
+            &send_buf[pos..n + m],
+            &mut reply_buf,
+        );
+        pos += k;
+        if let Some(n) = result {
+            match n.unwrap() {
+                server::ParseResult::Request(n) => replies.extend_from_slice(&reply_buf[..n]),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+    rpc_client.set_in_place(false);
+    let mut results = Vec::new();
+    let mut pos = 0;
+    while pos < replies.len() {
+        let (n, result) = client_receiver.push_slice(&mut rpc_client, &replies[pos..]);
+        pos += n;
+        results.extend(result);
+    }
+    match results.as_slice() {
+        [Err(client::Error::ReplyBodyTooLong), Ok(Some(chan_id))] => {
+            assert_eq!(*chan_id, ping.chan_id())
+        }
+        r => panic!("unexpected results: {:?}", r),
+    }
+    assert_eq!(
+        ping.take_reply(&mut rpc_client).unwrap().unwrap(),
+        [0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn schema() {
+    let schema = DeviceRequests::SCHEMA;
+    assert_eq!(schema.methods.len(), 8);
+    assert_eq!(schema.methods[1].name, "SendBytes");
+    assert!(schema.methods[1].req_opt_buf);
+    assert!(!schema.methods[1].rep_opt_buf);
+    assert!(schema.methods[5].stream);
+    // The client table defined with client_requests speaks the same service
+    assert_eq!(cli::SCHEMA.hash, schema.hash);
+}
+
+#[test]
+fn connect() {
+    let mut rpc = RpcClientIO::connect(MemServer::new(), BUF_LEN, &cli::SCHEMA).unwrap();
+    assert_eq!(rpc.schema_hash().unwrap(), DeviceRequests::SCHEMA.hash);
+    assert_eq!(
+        Ping::new([0, 1, 2, 3]).call_io(&mut rpc).unwrap(),
+        [0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn connect_schema_mismatch() {
+    mod old {
+        use urpc::client_requests;
+
+        client_requests! {
+            client_requests;
+            (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+            (1, send_bytes, SendBytes(u16, OptBufYes, u32, OptBufNo))
+        }
+    }
+    match RpcClientIO::connect(MemServer::new(), BUF_LEN, &old::SCHEMA) {
+        Err(RpcClientIOError::Urpc(client::Error::SchemaMismatch(hash))) => {
+            assert_eq!(hash, DeviceRequests::SCHEMA.hash)
+        }
+        r => panic!("unexpected result: {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn builtin_methods() {
+    let mut rpc = client();
+    rpc.ping().unwrap();
+    assert_eq!(
+        rpc.get_info().unwrap(),
+        ServerInfo {
+            version: consts::PROTOCOL_VERSION,
+            methods: 8,
+        }
+    );
+    assert_eq!(rpc.max_buf_len().unwrap(), BUF_LEN as u16);
+    // The built-in methods don't reach the handler
+    assert_eq!(Reset::new(()).call_io(&mut rpc).unwrap(), 1);
+}
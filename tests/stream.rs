@@ -0,0 +1,105 @@
+use urpc::{client, server, server_requests};
+
+mod cli {
+    use urpc::client_requests;
+
+    client_requests! {
+        client_requests;
+        (0, chunks, Chunks(u8, OptBufNo, u8, OptBufYes) stream),
+        (1, split, Split(u8, OptBufYes, u8, OptBufYes) stream)
+    }
+}
+
+// Stream methods whose items carry an optional buffer
+server_requests! {
+    ServerRequests;
+    (0, chunks, Chunks(u8, OptBufNo, u8, OptBufYes) stream),
+    (1, split, Split(u8, OptBufYes, u8, OptBufYes) stream)
+}
+
+struct Server;
+
+impl Handler for Server {
+    fn chunks(&mut self, n: u8, items: &mut server::Items<u8, urpc::OptBufYes>) -> Result<(), ()> {
+        for i in 0..n {
+            items.send(i, &[i; 3]).unwrap();
+        }
+        Ok(())
+    }
+
+    fn split(
+        &mut self,
+        size: u8,
+        buf: &[u8],
+        items: &mut server::Items<u8, urpc::OptBufYes>,
+    ) -> Result<(), ()> {
+        for (i, chunk) in buf.chunks(size as usize).enumerate() {
+            items.send(i as u8, chunk).unwrap();
+        }
+        Ok(())
+    }
+}
+
+const BUF_LEN: usize = 32;
+
+/// Serve the request serialized in `request`, and parse its replies into `rpc_client`.
+fn exchange(rpc_client: &mut client::RpcClient, request: &[u8], server: &mut Server) {
+    let mut rpc_server = server::RpcServer::new(BUF_LEN as u16);
+    let mut server_buf = [0; BUF_LEN];
+    let mut server_receiver = server::Receiver::new(&mut server_buf);
+    let mut reply_buf = [0; 4 * BUF_LEN];
+    let (n, result) = server_receiver.dispatch::<ServerRequests, _>(
+        &mut rpc_server,
+        server,
+        request,
+        &mut reply_buf,
+    );
+    assert_eq!(n, request.len());
+    let reply_len = match result {
+        Some(Ok(server::ParseResult::Request(n))) => n,
+        r => panic!("unexpected result: {:?}", r),
+    };
+
+    let mut client_buf = [0; BUF_LEN];
+    let mut client_receiver = client::Receiver::new(&mut client_buf);
+    let mut pos = 0;
+    while pos < reply_len {
+        let (n, result) = client_receiver.push_slice(rpc_client, &reply_buf[pos..reply_len]);
+        pos += n;
+        result.transpose().unwrap();
+    }
+}
+
+#[test]
+fn items_with_opt_buf() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut chunks = cli::Chunks::new(3);
+    let n = chunks.request(&mut rpc_client, &mut send_buf).unwrap();
+    exchange(&mut rpc_client, &send_buf[..n], &mut Server);
+
+    for i in 0..3 {
+        let (item, buf) = chunks.take_item(&mut rpc_client).unwrap().unwrap();
+        assert_eq!((item, buf), (i, &[i; 3][..]));
+    }
+    assert!(chunks.take_item(&mut rpc_client).is_none());
+    assert!(chunks.is_done());
+}
+
+#[test]
+fn items_with_request_and_reply_opt_buf() {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut send_buf = [0; BUF_LEN];
+    let mut split = cli::Split::new(2);
+    let n = split
+        .request(&[1, 2, 3, 4, 5], &mut rpc_client, &mut send_buf)
+        .unwrap();
+    exchange(&mut rpc_client, &send_buf[..n], &mut Server);
+
+    let mut chunks = Vec::new();
+    while let Some(item) = split.take_item(&mut rpc_client) {
+        let (i, buf) = item.unwrap();
+        chunks.push((i, buf.to_vec()));
+    }
+    assert_eq!(chunks, [(0, vec![1, 2]), (1, vec![3, 4]), (2, vec![5])]);
+}
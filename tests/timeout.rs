@@ -0,0 +1,213 @@
+use urpc::{
+    client::{self, RpcClientIO, RpcClientIOError},
+    server::{RpcServerIO, RpcServerIOError},
+    service_requests,
+    timeout::{Timeout, Timeouts},
+};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+service_requests! {
+    Requests;
+    (0, ping, Ping([u8; 4], OptBufNo, [u8; 4], OptBufNo)),
+    (1, send_bytes, SendBytes((), OptBufYes, usize, OptBufNo))
+}
+
+struct Server;
+
+impl Handler for Server {
+    fn ping(&mut self, body: [u8; 4]) -> Result<[u8; 4], ()> {
+        Ok(body)
+    }
+
+    fn send_bytes(&mut self, _body: (), buf: &[u8]) -> Result<usize, ()> {
+        Ok(buf.len())
+    }
+}
+
+const BUF_LEN: usize = 32;
+
+enum Event {
+    Byte(u8),
+    /// The read timeout of the stream expires after the duration.
+    Stall(Duration),
+}
+
+/// Stream that receives the scripted events, and keeps the sent bytes.  It ends after the last
+/// event.
+#[derive(Default)]
+struct Line {
+    rx: VecDeque<Event>,
+    tx: Vec<u8>,
+}
+
+impl Line {
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes.iter().map(|b| Event::Byte(*b)));
+    }
+
+    fn push_stall(&mut self, ms: u64) {
+        self.rx.push_back(Event::Stall(Duration::from_millis(ms)));
+    }
+}
+
+impl Read for Line {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.rx.pop_front() {
+            None => Ok(0),
+            Some(Event::Byte(b)) => {
+                buf[0] = b;
+                Ok(1)
+            }
+            Some(Event::Stall(duration)) => {
+                sleep(duration);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serialize a ping request of a new client.
+fn ping_request(body: [u8; 4]) -> Vec<u8> {
+    let mut rpc_client = client::RpcClient::new(BUF_LEN as u16);
+    let mut buf = vec![0; BUF_LEN];
+    let n = Ping::new(body).request(&mut rpc_client, &mut buf).unwrap();
+    buf[..n].to_vec()
+}
+
+const TIMEOUTS: Timeouts = Timeouts {
+    inter_byte: Some(Duration::from_millis(20)),
+    request: Some(Duration::from_secs(10)),
+};
+
+#[test]
+fn server_inter_byte_timeout() {
+    let mut line = Line::default();
+    // A client dies after sending a request header, and another one sends a request
+    line.push_bytes(&ping_request([0, 1, 2, 3])[..7]);
+    line.push_stall(50);
+    line.push_bytes(&ping_request([4, 5, 6, 7]));
+
+    let mut rpc_server = RpcServerIO::new(line, BUF_LEN);
+    rpc_server.set_timeouts(TIMEOUTS);
+    match rpc_server.serve::<Requests, _>(&mut Server) {
+        Err(RpcServerIOError::Timeout(Timeout::InterByte)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(rpc_server.serve::<Requests, _>(&mut Server).unwrap());
+    assert!(!rpc_server.serve::<Requests, _>(&mut Server).unwrap());
+
+    let line = rpc_server.into_inner();
+    assert_eq!(line.tx, [1, 4, 4, 0, 0, 0, 4, 5, 6, 7]);
+}
+
+#[test]
+fn server_request_timeout() {
+    let mut line = Line::default();
+    // The request bytes trickle
+    for b in ping_request([0, 1, 2, 3]) {
+        line.push_bytes(&[b]);
+        line.push_stall(10);
+    }
+    line.push_bytes(&ping_request([4, 5, 6, 7]));
+
+    let mut rpc_server = RpcServerIO::new(line, BUF_LEN);
+    rpc_server.set_timeouts(Timeouts {
+        inter_byte: Some(Duration::from_secs(10)),
+        request: Some(Duration::from_millis(30)),
+    });
+    match rpc_server.serve::<Requests, _>(&mut Server) {
+        Err(RpcServerIOError::Timeout(Timeout::Request)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn server_without_timeouts() {
+    let mut line = Line::default();
+    line.push_bytes(&ping_request([0, 1, 2, 3])[..7]);
+    line.push_stall(0);
+
+    let mut rpc_server = RpcServerIO::new(line, BUF_LEN);
+    match rpc_server.serve::<Requests, _>(&mut Server) {
+        Err(RpcServerIOError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn client_request_timeout() {
+    let mut line = Line::default();
+    // The server never replies
+    line.push_stall(50);
+
+    let mut rpc = RpcClientIO::new(line, BUF_LEN);
+    rpc.set_timeouts(Timeouts {
+        request: Some(Duration::from_millis(20)),
+        ..TIMEOUTS
+    });
+    match Ping::new([0, 1, 2, 3]).call_io(&mut rpc) {
+        Err(RpcClientIOError::Timeout(Timeout::Request)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    // The request is cancelled
+    assert_eq!(rpc.client.in_flight(), 0);
+    let line = rpc.into_inner();
+    assert_eq!(line.tx.len(), 2 * 7 + 4);
+}
+
+#[test]
+fn client_io_error() {
+    let mut line = Line::default();
+    // The read fails without timeouts set
+    line.push_bytes(&[1, 4]);
+    line.push_stall(0);
+
+    let mut rpc = RpcClientIO::new(line, BUF_LEN);
+    match Ping::new([0, 1, 2, 3]).call_io(&mut rpc) {
+        Err(RpcClientIOError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    // The request is cancelled
+    assert_eq!(rpc.client.in_flight(), 0);
+    let line = rpc.into_inner();
+    assert_eq!(line.tx.len(), 2 * 7 + 4);
+}
+
+#[test]
+fn client_inter_byte_timeout() {
+    let mut line = Line::default();
+    // The server stops sending in the middle of a reply, and then sends the whole reply
+    line.push_bytes(&[1, 4, 4]);
+    line.push_stall(50);
+    line.push_bytes(&[1, 4, 4, 0, 0, 0, 0, 1, 2, 3]);
+
+    let mut rpc = RpcClientIO::new(line, BUF_LEN);
+    rpc.set_timeouts(TIMEOUTS);
+    let mut ping = Ping::new([0, 1, 2, 3]);
+    let n = ping.request(&mut rpc.client, &mut rpc.stream_buf).unwrap();
+    rpc.send::<()>(n).unwrap();
+    match rpc.wait_reply::<()>(ping.chan_id()) {
+        Err(RpcClientIOError::Timeout(Timeout::InterByte)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+    rpc.wait_reply::<()>(ping.chan_id()).unwrap();
+    assert_eq!(
+        ping.take_reply(&mut rpc.client).unwrap().unwrap(),
+        [0, 1, 2, 3]
+    );
+}
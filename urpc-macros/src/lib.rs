@@ -0,0 +1,652 @@
+//! Procedural macros for [uRPC](https://docs.rs/urpc).  They are re-exported by `urpc` with the
+//! `macros` feature, so they should be used through it.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote,
+    spanned::Spanned,
+    visit_mut::{self, VisitMut},
+    Attribute, Error, FnArg, GenericArgument, Ident, ItemTrait, Lifetime, LitInt, PathArguments,
+    Result, ReturnType, TraitItem, TraitItemFn, Type, TypeReference,
+};
+
+/// Attribute that defines a service from a trait, with one RPC call per trait method.
+///
+/// Each method needs an `#[id(n)]` attribute with its method id, which must fit in a `u8`, be
+/// unique and differ from the built-in method ids.  Methods take `&self` or `&mut self`,
+/// followed by these arguments in any order:
+///
+/// - The request body, of any serializable type.  If it's missing the request body is `()`.
+///   The body can borrow from the receive buffer, as in `&str` or `&[u8]` inside a struct: its
+///   elided lifetimes are named `'a`, and the client type of the method then takes `'a` too.
+/// - `&[u8]`: the request optional buffer.
+/// - `&mut [u8]`: the reply optional buffer.  The method then returns the reply body and the
+///   length of the buffer as a `(T, usize)`.
+/// - `&mut Items<T>`: makes the method a stream method whose items are of type `T`, sent with
+///   `server::Items`.  Stream methods can't have a reply optional buffer.
+///
+/// Methods return the reply body, or a `Result` whose error type is serialized into the reply
+/// when it's not `()`.
+///
+/// The trait is kept as is (without the `id` attributes), and is the handler of the requests on
+/// the server.  The attribute builds the same types as `urpc::service_requests`, using the
+/// method names in camel case as type names:
+///
+/// - One `client::RequestType` or `client::StreamType` type per method.
+/// - A requests enum named after the trait with a `Requests` suffix, which implements
+///   `server::Request` and implements `server::Dispatch` for the trait implementors.  Its
+///   `SCHEMA` associated constant holds the `schema::Schema` of the requests, which clients can
+///   check with `client::RpcClientIO::check_schema`.
+///
+/// # Examples
+///
+/// ```
+/// use urpc::{client, consts, server::{self, Items}};
+///
+/// #[urpc::service]
+/// trait Sensor {
+///     /// Echo the request.
+///     #[id(0)]
+///     fn ping(&mut self, body: [u8; 4]) -> [u8; 4];
+///
+///     /// Read `n` samples.
+///     #[id(1)]
+///     fn samples(&mut self, n: u8, items: &mut Items<u16>);
+/// }
+///
+/// struct Server;
+///
+/// impl Sensor for Server {
+///     fn ping(&mut self, body: [u8; 4]) -> [u8; 4] {
+///         body
+///     }
+///
+///     fn samples(&mut self, n: u8, items: &mut Items<u16>) {
+///         for i in 0..n as u16 {
+///             items.send(i).ok();
+///         }
+///     }
+/// }
+///
+/// let mut rpc_client = client::RpcClient::new(32);
+/// let mut rpc_server = server::RpcServer::new(32);
+/// let mut req_buf = vec![0; 32];
+/// let mut rep_buf = vec![0; 32];
+///
+/// let mut req = Ping::new([0, 1, 2, 3]);
+/// let n = req.request(&mut rpc_client, &mut req_buf).unwrap();
+///
+/// // The server parses the request header first, and then the body
+/// let (header, body) = req_buf[..n].split_at(consts::REQ_HEADER_LEN);
+/// for buf in &[header, body] {
+///     server::dispatch::<SensorRequests, _>(&mut rpc_server, &mut Server, buf, &mut rep_buf)
+///         .unwrap();
+/// }
+///
+/// // The client parses the reply header first, and then the body
+/// let n = rpc_client.parse(&rep_buf[..consts::REP_HEADER_LEN]).unwrap().0;
+/// rpc_client
+///     .parse(&rep_buf[consts::REP_HEADER_LEN..consts::REP_HEADER_LEN + n])
+///     .unwrap();
+/// assert_eq!(req.take_reply(&mut rpc_client).unwrap().unwrap(), [0, 1, 2, 3]);
+/// ```
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item = parse_macro_input!(item as ItemTrait);
+    let attr = TokenStream2::from(attr);
+    let result = if attr.is_empty() {
+        expand(&mut item)
+    } else {
+        Err(Error::new(attr.span(), "unexpected attribute arguments"))
+    };
+    match result {
+        Ok(tokens) => tokens.into(),
+        Err(err) => {
+            let err = err.to_compile_error();
+            quote!(#item #err).into()
+        }
+    }
+}
+
+/// First method id reserved for the built-in methods, `urpc::consts::BUILTIN_METHOD_ID_START`.
+const BUILTIN_METHOD_ID_START: u8 = 0xf8;
+
+/// Argument of a method, after the receiver.
+enum Arg {
+    Body,
+    ReqBuf,
+    RepBuf,
+    Items,
+}
+
+struct Method {
+    id: u8,
+    id_span: Span,
+    fn_ident: Ident,
+    name: Ident,
+    docs: Vec<Attribute>,
+    args: Vec<Arg>,
+    /// Request body type, with the elided lifetimes named `'a`.
+    req_type: Type,
+    /// Whether the request body borrows from the receive buffer.
+    req_borrows: bool,
+    rep_type: Type,
+    err_type: Type,
+    returns_result: bool,
+}
+
+impl Method {
+    fn has(&self, arg: fn(&Arg) -> bool) -> bool {
+        self.args.iter().any(arg)
+    }
+
+    fn req_buf(&self) -> bool {
+        self.has(|arg| matches!(arg, Arg::ReqBuf))
+    }
+
+    fn rep_buf(&self) -> bool {
+        self.has(|arg| matches!(arg, Arg::RepBuf))
+    }
+
+    fn stream(&self) -> bool {
+        self.has(|arg| matches!(arg, Arg::Items))
+    }
+}
+
+fn expand(item: &mut ItemTrait) -> Result<TokenStream2> {
+    let mut methods: Vec<Method> = Vec::new();
+    let mut errors: Option<Error> = None;
+    for trait_item in item.items.iter_mut() {
+        let result = match trait_item {
+            TraitItem::Fn(item_fn) => parse_method(item_fn),
+            item => Err(Error::new(
+                item.span(),
+                "only methods are supported in a service",
+            )),
+        };
+        let result = result.and_then(|method| match methods.iter().find(|m| m.id == method.id) {
+            Some(other) => Err(Error::new(
+                method.id_span,
+                format!(
+                    "methods `{}` and `{}` have the same id {}",
+                    other.fn_ident, method.fn_ident, method.id
+                ),
+            )),
+            None => Ok(method),
+        });
+        match result {
+            Ok(method) => methods.push(method),
+            Err(err) => match errors.as_mut() {
+                Some(errors) => errors.combine(err),
+                None => errors = Some(err),
+            },
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+
+    let vis = &item.vis;
+    let trait_ident = &item.ident;
+    let enum_ident = format_ident!("{}Requests", trait_ident);
+    let method_id_mod = format_ident!("{}_method_id", snake_case(&trait_ident.to_string()));
+    let lifetime = if methods.iter().any(|m| m.req_buf() || m.req_borrows) {
+        quote!(<'a>)
+    } else {
+        quote!()
+    };
+
+    let opt_buf = |yes: bool| {
+        if yes {
+            quote!(::urpc::OptBufYes)
+        } else {
+            quote!(::urpc::OptBufNo)
+        }
+    };
+
+    let mut method_ids = Vec::new();
+    let mut aliases = Vec::new();
+    let mut variants = Vec::new();
+    let mut from_bytes = Vec::new();
+    let mut dispatch = Vec::new();
+    let mut schema = Vec::new();
+    for method in &methods {
+        let Method {
+            id,
+            fn_ident,
+            name,
+            docs,
+            req_type,
+            rep_type,
+            err_type,
+            ..
+        } = method;
+        let (req_buf, rep_buf, stream) = (method.req_buf(), method.rep_buf(), method.stream());
+        let method_name = name.to_string();
+        let type_name = |ty: &Type| quote!(#ty).to_string();
+        let (req_type_name, rep_type_name, err_type_name) = (
+            type_name(req_type),
+            type_name(rep_type),
+            type_name(err_type),
+        );
+        schema.push(quote! {
+            ::urpc::schema::MethodSchema {
+                id: #id,
+                name: #method_name,
+                req_type: #req_type_name,
+                req_opt_buf: #req_buf,
+                rep_type: #rep_type_name,
+                rep_opt_buf: #rep_buf,
+                err_type: #err_type_name,
+                stream: #stream,
+            },
+        });
+
+        let req_opt_buf = opt_buf(req_buf);
+        let rep_opt_buf = opt_buf(rep_buf);
+        let kind = if method.stream() {
+            quote!(StreamType)
+        } else {
+            quote!(RequestType)
+        };
+
+        method_ids.push(quote! {
+            pub struct #name;
+            impl ::urpc::client::MethodId for #name {
+                const METHOD_ID: u8 = #id;
+            }
+        });
+        let alias_lifetime = if method.req_borrows {
+            quote!(<'a>)
+        } else {
+            quote!()
+        };
+        aliases.push(quote! {
+            #(#docs)*
+            #vis type #name #alias_lifetime = ::urpc::client::#kind<
+                #method_id_mod::#name,
+                #req_type,
+                #req_opt_buf,
+                #rep_type,
+                #rep_opt_buf,
+                #err_type,
+            >;
+        });
+
+        let request = quote! {
+            ::urpc::server::#kind<#req_type, #req_opt_buf, #rep_type, #rep_opt_buf, #err_type>
+        };
+        let (variant, req) = if method.req_buf() {
+            (quote!((#request, &'a [u8])), quote!((req, req_buf)))
+        } else {
+            (request, quote!(req))
+        };
+        variants.push(quote! {
+            #(#docs)*
+            #name(#variant),
+        });
+        from_bytes.push(quote! {
+            #id => #enum_ident::#name(
+                ::urpc::server::#kind::<_, #req_opt_buf, _, _, _>::from_bytes(header, buf)?
+            ),
+        });
+
+        let call_args = method.args.iter().map(|arg| match arg {
+            Arg::Body => quote!(body),
+            Arg::ReqBuf => quote!(req_buf),
+            Arg::RepBuf => quote!(out),
+            Arg::Items => quote!(items),
+        });
+        let mut call = quote!(handler.#fn_ident(#(#call_args),*));
+        if !method.returns_result {
+            call = quote!(Ok(#call));
+        }
+        let body = if method.has(|arg| matches!(arg, Arg::Body)) {
+            quote!(body)
+        } else {
+            quote!(_)
+        };
+        let params = if method.stream() {
+            quote!(#body, items)
+        } else if method.rep_buf() {
+            quote!(#body, out)
+        } else {
+            quote!(#body)
+        };
+        dispatch.push(quote! {
+            #enum_ident::#name(#req) => req.handle(|#params| #call, writer),
+        });
+    }
+
+    let enum_doc = format!(
+        "Requests of the [`{}`] service, parsed by the server.",
+        trait_ident
+    );
+    Ok(quote! {
+        #item
+
+        #[doc(hidden)]
+        mod #method_id_mod {
+            #(#method_ids)*
+        }
+
+        #(#aliases)*
+
+        #[doc = #enum_doc]
+        #[derive(Debug)]
+        #vis enum #enum_ident #lifetime {
+            #(#variants)*
+        }
+
+        impl #lifetime #enum_ident #lifetime {
+            /// Schema of the requests.
+            #[allow(dead_code)]
+            #vis const SCHEMA: ::urpc::schema::Schema = ::urpc::schema::Schema::new(&[
+                #(#schema)*
+            ]);
+        }
+
+        impl<'a> ::urpc::server::Request<'a> for #enum_ident #lifetime {
+            fn from_bytes(
+                header: ::urpc::RequestHeader,
+                buf: &'a [u8],
+            ) -> ::urpc::server::Result<Self> {
+                Ok(match header.method_idx {
+                    #(#from_bytes)*
+                    _ => {
+                        return Err(::urpc::server::Error::UnknownMethod(header.method_idx));
+                    }
+                })
+            }
+        }
+
+        impl<'a, H: #trait_ident + ?Sized> ::urpc::server::Dispatch<H> for #enum_ident #lifetime {
+            const SCHEMA: ::urpc::schema::Schema = Self::SCHEMA;
+
+            fn dispatch(
+                handler: &mut H,
+                header: ::urpc::RequestHeader,
+                buf: &[u8],
+                writer: &mut dyn ::urpc::server::ReplyWrite,
+            ) -> ::urpc::server::Result<()> {
+                match <#enum_ident as ::urpc::server::Request>::from_bytes(header, buf)? {
+                    #(#dispatch)*
+                }
+            }
+        }
+    })
+}
+
+fn parse_method(item_fn: &mut TraitItemFn) -> Result<Method> {
+    let (id, id_span) = take_id(item_fn)?;
+    let sig = &item_fn.sig;
+    let fn_ident = sig.ident.clone();
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "service methods can't be generic",
+        ));
+    }
+    if sig.asyncness.is_some() {
+        return Err(Error::new(sig.span(), "service methods can't be async"));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() => {}
+        _ => {
+            return Err(Error::new(
+                sig.span(),
+                "service methods must take `&self` or `&mut self`",
+            ))
+        }
+    }
+
+    let mut args = Vec::new();
+    let mut req_type = None;
+    let mut item_type = None;
+    for input in inputs {
+        let ty = match input {
+            FnArg::Typed(pat_type) => &*pat_type.ty,
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new(receiver.span(), "unexpected receiver"));
+            }
+        };
+        let arg = if is_byte_slice(ty, false) {
+            Arg::ReqBuf
+        } else if is_byte_slice(ty, true) {
+            Arg::RepBuf
+        } else if let Some(item) = items_type(ty) {
+            item_type = Some(item.clone());
+            Arg::Items
+        } else {
+            req_type = Some(ty.clone());
+            Arg::Body
+        };
+        if args
+            .iter()
+            .any(|a| std::mem::discriminant(a) == std::mem::discriminant(&arg))
+        {
+            let msg = match arg {
+                Arg::Body => "service methods take a single request body",
+                Arg::ReqBuf => "service methods take a single request buffer `&[u8]`",
+                Arg::RepBuf => "service methods take a single reply buffer `&mut [u8]`",
+                Arg::Items => "service methods take a single `&mut Items<T>`",
+            };
+            return Err(Error::new(ty.span(), msg));
+        }
+        args.push(arg);
+    }
+
+    let mut req_type = req_type.unwrap_or_else(|| parse_quote!(()));
+    let req_borrows = name_elided_lifetimes(&mut req_type);
+
+    let ret: Type = match &sig.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    let (ok_type, err_type, returns_result) = match result_types(&ret) {
+        Some((ok_type, err_type)) => (ok_type.clone(), err_type.clone(), true),
+        None => (ret.clone(), parse_quote!(()), false),
+    };
+
+    let is_rep_buf = args.iter().any(|arg| matches!(arg, Arg::RepBuf));
+    let rep_type = match item_type {
+        Some(_) if is_rep_buf => {
+            return Err(Error::new(
+                sig.span(),
+                "stream methods can't have a reply buffer `&mut [u8]`",
+            ));
+        }
+        Some(item_type) => {
+            if !matches!(&ok_type, Type::Tuple(tuple) if tuple.elems.is_empty()) {
+                return Err(Error::new(
+                    ret.span(),
+                    "stream methods must return `()` or `Result<(), E>`",
+                ));
+            }
+            item_type
+        }
+        None if is_rep_buf => match &ok_type {
+            Type::Tuple(tuple) if tuple.elems.len() == 2 => tuple.elems[0].clone(),
+            _ => {
+                return Err(Error::new(
+                    ret.span(),
+                    "methods with a reply buffer must return the reply and the buffer length \
+                     as `(T, usize)`",
+                ));
+            }
+        },
+        None => ok_type,
+    };
+
+    Ok(Method {
+        id,
+        id_span,
+        name: Ident::new(&camel_case(&fn_ident.to_string()), fn_ident.span()),
+        fn_ident,
+        docs: item_fn
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .cloned()
+            .collect(),
+        args,
+        req_type,
+        req_borrows,
+        rep_type,
+        err_type,
+        returns_result,
+    })
+}
+
+/// Remove the `#[id(n)]` attribute of a method, and return its id.
+fn take_id(item_fn: &mut TraitItemFn) -> Result<(u8, Span)> {
+    let pos = item_fn
+        .attrs
+        .iter()
+        .position(|attr| attr.path().is_ident("id"))
+        .ok_or_else(|| {
+            Error::new(
+                item_fn.sig.ident.span(),
+                format!("method `{}` has no `#[id(n)]` attribute", item_fn.sig.ident),
+            )
+        })?;
+    let attr = item_fn.attrs.remove(pos);
+    let lit: LitInt = attr.parse_args()?;
+    let id = lit.base10_parse::<u8>().map_err(|_| {
+        Error::new(
+            lit.span(),
+            format!(
+                "method `{}` has id {}, which doesn't fit in a u8",
+                item_fn.sig.ident, lit
+            ),
+        )
+    })?;
+    if id >= BUILTIN_METHOD_ID_START {
+        return Err(Error::new(
+            lit.span(),
+            format!(
+                "method `{}` has id {}, which is reserved for the built-in methods",
+                item_fn.sig.ident, lit
+            ),
+        ));
+    }
+    Ok((id, lit.span()))
+}
+
+/// Name the elided lifetimes of `ty` `'a`, the lifetime of the receive buffer.  Returns whether
+/// `ty` borrows from it.
+fn name_elided_lifetimes(ty: &mut Type) -> bool {
+    struct NameLifetimes(bool);
+
+    impl VisitMut for NameLifetimes {
+        fn visit_type_reference_mut(&mut self, reference: &mut TypeReference) {
+            if reference.lifetime.is_none() {
+                reference.lifetime = Some(parse_quote!('a));
+            }
+            visit_mut::visit_type_reference_mut(self, reference);
+        }
+
+        fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+            if lifetime.ident == "_" {
+                *lifetime = parse_quote!('a);
+            }
+            if lifetime.ident == "a" {
+                self.0 = true;
+            }
+        }
+    }
+
+    let mut visitor = NameLifetimes(false);
+    visitor.visit_type_mut(ty);
+    visitor.0
+}
+
+/// Whether `ty` is `&[u8]`, or `&mut [u8]` if `mutability` is set.
+fn is_byte_slice(ty: &Type, mutability: bool) -> bool {
+    match ty {
+        Type::Reference(reference) if reference.mutability.is_some() == mutability => {
+            match &*reference.elem {
+                Type::Slice(slice) => {
+                    matches!(&*slice.elem, Type::Path(path) if path.path.is_ident("u8"))
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Item type of `&mut Items<T>`.
+fn items_type(ty: &Type) -> Option<&Type> {
+    let reference = match ty {
+        Type::Reference(reference) if reference.mutability.is_some() => reference,
+        _ => return None,
+    };
+    match generic_args(&reference.elem, "Items")?.as_slice() {
+        [item] => Some(item),
+        _ => None,
+    }
+}
+
+/// Ok and error types of `Result<T, E>`.
+fn result_types(ty: &Type) -> Option<(&Type, &Type)> {
+    match generic_args(ty, "Result")?.as_slice() {
+        [ok, err] => Some((ok, err)),
+        _ => None,
+    }
+}
+
+/// Generic type arguments of a path type whose last segment is `name`.
+fn generic_args<'a>(ty: &'a Type, name: &str) -> Option<Vec<&'a Type>> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != name {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn camel_case(s: &str) -> String {
+    s.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn snake_case(s: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}